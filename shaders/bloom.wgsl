@@ -0,0 +1,90 @@
+// Passes of bloom.rs. By default the source is downsampled to half its
+// resolution, with PREFILTER defined only its bright parts are kept and with
+// UPSAMPLE defined a mip is blurred into the next larger one instead.
+
+#include "fullscreen.wgsl"
+
+struct Params {
+    threshold: f32;
+    // Width of the soft transition below the threshold
+    knee: f32;
+    // Of the tent filter, in texture coordinates
+    radius: f32;
+    // Multiplies the output, the intensity when compositing and 1 otherwise
+    scale: f32;
+};
+
+[[group(0), binding(0)]]
+var t_source: texture_2d<f32>;
+[[group(0), binding(1)]]
+var s_source: sampler;
+[[group(0), binding(2)]]
+var<uniform> params: Params;
+
+fn sample_source(uv: vec2<f32>) -> vec3<f32> {
+    return textureSampleLevel(t_source, s_source, uv, 0.0).rgb;
+}
+
+#ifdef UPSAMPLE
+// 3x3 tent filter
+fn sample_filtered(uv: vec2<f32>) -> vec3<f32> {
+    let r = params.radius;
+    let corners = sample_source(uv + vec2<f32>(-r, -r))
+        + sample_source(uv + vec2<f32>(r, -r))
+        + sample_source(uv + vec2<f32>(-r, r))
+        + sample_source(uv + vec2<f32>(r, r));
+    let edges = sample_source(uv + vec2<f32>(-r, 0.0))
+        + sample_source(uv + vec2<f32>(r, 0.0))
+        + sample_source(uv + vec2<f32>(0.0, -r))
+        + sample_source(uv + vec2<f32>(0.0, r));
+    return (sample_source(uv) * 4.0 + edges * 2.0 + corners) / 16.0;
+}
+#else
+// 13 tap filter from Jimenez's "Next Generation Post Processing in Call of
+// Duty: Advanced Warfare", which avoids the flickering of a box filter
+fn sample_filtered(uv: vec2<f32>) -> vec3<f32> {
+    let texel = vec2<f32>(1.0) / vec2<f32>(textureDimensions(t_source));
+    let a = sample_source(uv + vec2<f32>(-2.0, -2.0) * texel);
+    let b = sample_source(uv + vec2<f32>(0.0, -2.0) * texel);
+    let c = sample_source(uv + vec2<f32>(2.0, -2.0) * texel);
+    let d = sample_source(uv + vec2<f32>(-1.0, -1.0) * texel);
+    let e = sample_source(uv + vec2<f32>(1.0, -1.0) * texel);
+    let f = sample_source(uv + vec2<f32>(-2.0, 0.0) * texel);
+    let g = sample_source(uv);
+    let h = sample_source(uv + vec2<f32>(2.0, 0.0) * texel);
+    let i = sample_source(uv + vec2<f32>(-1.0, 1.0) * texel);
+    let j = sample_source(uv + vec2<f32>(1.0, 1.0) * texel);
+    let k = sample_source(uv + vec2<f32>(-2.0, 2.0) * texel);
+    let l = sample_source(uv + vec2<f32>(0.0, 2.0) * texel);
+    let m = sample_source(uv + vec2<f32>(2.0, 2.0) * texel);
+
+    // The inner box gets half of the weight, the four overlapping outer boxes
+    // an eighth each
+    return (d + e + i + j) * 0.125
+        + (b + f + h + l) * 0.0625
+        + (a + c + k + m) * 0.03125
+        + g * 0.125;
+}
+#endif
+
+#ifdef PREFILTER
+// Scales colors down to nothing below the threshold, with a quadratic curve
+// around it instead of a hard cut
+fn threshold(color: vec3<f32>) -> vec3<f32> {
+    let brightness = max(color.r, max(color.g, color.b));
+    let knee = clamp(brightness - params.threshold + params.knee, 0.0, 2.0 * params.knee);
+    let soft = knee * knee / (4.0 * params.knee + 0.00001);
+    let contribution = max(soft, brightness - params.threshold) / max(brightness, 0.00001);
+    return color * contribution;
+}
+#endif
+
+[[stage(fragment)]]
+fn fs_main(in: FullscreenOutput) -> [[location(0)]] vec4<f32> {
+    var color = sample_filtered(in.tex_coords);
+#ifdef PREFILTER
+    // Clamped so single very bright pixels don't turn into flickering squares
+    color = threshold(min(color, vec3<f32>(65000.0)));
+#endif
+    return vec4<f32>(color * params.scale, 1.0);
+}
@@ -0,0 +1,19 @@
+#include "postprocess.wgsl"
+
+struct Params {
+    // Offset of the red and blue channels at the edges of the screen, in
+    // texture coordinates
+    strength: f32;
+};
+[[group(0), binding(2)]]
+var<uniform> params: Params;
+
+[[stage(fragment)]]
+fn fs_main(in: FullscreenOutput) -> [[location(0)]] vec4<f32> {
+    // Grows towards the edges like the aberration of a lens
+    let offset = (in.tex_coords - vec2<f32>(0.5)) * params.strength;
+    let center = sample_input(in.tex_coords);
+    let r = sample_input(in.tex_coords + offset).r;
+    let b = sample_input(in.tex_coords - offset).b;
+    return vec4<f32>(r, center.g, b, center.a);
+}
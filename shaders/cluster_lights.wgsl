@@ -0,0 +1,59 @@
+// Light culling of clustering.rs, listing the point lights whose range touches
+// every cluster. One invocation per cluster.
+
+#define LIGHTS_GROUP 0
+#define CLUSTER_ACCESS read_write
+#include "lights.wgsl"
+#include "clusters.wgsl"
+
+// View space point at `depth` on the ray through a point of the screen
+fn view_point(ndc: vec2<f32>, depth: f32) -> vec3<f32> {
+    let near = cluster_params.inv_proj * vec4<f32>(ndc, 0.0, 1.0);
+    let point = near.xyz / near.w;
+    return point * (depth / -point.z);
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn cs_main([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let grid = cluster_params.grid;
+    let index = id.x;
+    if (index >= grid.x * grid.y * grid.z) {
+        return;
+    }
+    let x = index % grid.x;
+    let y = index / grid.x % grid.y;
+    let z = index / (grid.x * grid.y);
+
+    // Bounds of the cluster, from the corners of its tile on both sides of its
+    // slice. Screen y goes down while NDC y goes up.
+    let uv_min = vec2<f32>(f32(x), f32(y)) / vec2<f32>(grid.xy);
+    let uv_max = vec2<f32>(f32(x + 1u), f32(y + 1u)) / vec2<f32>(grid.xy);
+    let ndc_min = vec2<f32>(uv_min.x * 2.0 - 1.0, 1.0 - uv_min.y * 2.0);
+    let ndc_max = vec2<f32>(uv_max.x * 2.0 - 1.0, 1.0 - uv_max.y * 2.0);
+    let near_depth = slice_depth(z);
+    let far_depth = slice_depth(z + 1u);
+    let a = view_point(ndc_min, near_depth);
+    let b = view_point(ndc_max, near_depth);
+    let c = view_point(ndc_min, far_depth);
+    let d = view_point(ndc_max, far_depth);
+    let bounds_min = min(min(a, b), min(c, d));
+    let bounds_max = max(max(a, b), max(c, d));
+
+    let offset = index * grid.w;
+    var count = 0u;
+    for (var i = 0u; i < lights.point_light_count; i = i + 1u) {
+        if (count == grid.w) {
+            break;
+        }
+        let light = lights.point_lights[i];
+        let center = (cluster_params.view * vec4<f32>(light.position, 1.0)).xyz;
+        // Distance from the light to the closest point of the cluster
+        let to_closest = clamp(center, bounds_min, bounds_max) - center;
+        if (dot(to_closest, to_closest) <= light.range * light.range) {
+            light_indices.data[offset + count] = i;
+            count = count + 1u;
+        }
+    }
+    clusters.data[index].offset = offset;
+    clusters.data[index].count = count;
+}
@@ -2,7 +2,7 @@
 
 struct VertexInput {
     [[location(0)]] position: vec3<f32>;
-    [[location(1)]] color: vec3<f32>;
+    [[location(1)]] tex_coords: vec2<f32>;
 };
 
 struct VertexOutput {
@@ -17,11 +17,11 @@ fn vs_main(model: VertexInput) -> VertexOutput {
     out.color = (model.position + 0.5);
 
     return out;
-};
+}
 
 // Fragment shader
 
 [[stage(fragment)]]
 fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
     return vec4<f32>(in.color, 1.0);
-};
\ No newline at end of file
+}
@@ -0,0 +1,96 @@
+// Frustum culling of instances, writing the visible ones of every batch to a
+// compacted instance buffer and counting them in its indirect draw arguments.
+// The instance counts have to be reset to 0 before every dispatch.
+
+struct Frustum {
+    // Inwards facing planes as (normal, d)
+    planes: array<vec4<f32>, 6>;
+};
+
+struct Batch {
+    // Bounds of the batch's mesh
+    min: vec3<f32>;
+    // Where the batch's instances start in the input and output buffers
+    first_instance: u32;
+    max: vec3<f32>;
+    instance_count: u32;
+};
+
+// Matches wgpu's DrawIndexedIndirect
+struct DrawArgs {
+    index_count: u32;
+    instance_count: atomic<u32>;
+    first_index: u32;
+    base_vertex: i32;
+    first_instance: u32;
+};
+
+struct Batches {
+    data: array<Batch>;
+};
+// Matches InstanceRaw
+struct Instance {
+    model: mat4x4<f32>;
+    layer: u32;
+};
+struct Instances {
+    data: array<Instance>;
+};
+struct InstanceBatches {
+    data: array<u32>;
+};
+struct DrawArgsArray {
+    data: array<DrawArgs>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> frustum: Frustum;
+[[group(0), binding(1)]]
+var<storage, read> batches: Batches;
+[[group(0), binding(2)]]
+var<storage, read> instances: Instances;
+// Index of the batch of every instance
+[[group(0), binding(3)]]
+var<storage, read> instance_batches: InstanceBatches;
+[[group(0), binding(4)]]
+var<storage, read_write> visible: Instances;
+[[group(0), binding(5)]]
+var<storage, read_write> draw_args: DrawArgsArray;
+
+fn is_visible(center: vec3<f32>, extents: vec3<f32>) -> bool {
+    for (var i = 0; i < 6; i = i + 1) {
+        let plane = frustum.planes[i];
+        // Distance from the center to the corner furthest along the normal
+        let radius = dot(extents, abs(plane.xyz));
+        if (dot(plane.xyz, center) + plane.w < -radius) {
+            return false;
+        }
+    }
+    return true;
+}
+
+[[stage(compute), workgroup_size(64)]]
+fn cs_main([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let index = id.x;
+    if (index >= arrayLength(&instances.data)) {
+        return;
+    }
+
+    let instance = instances.data[index];
+    let model = instance.model;
+    let batch_index = instance_batches.data[index];
+    let batch = batches.data[batch_index];
+
+    // Bounds of the mesh transformed by the model matrix
+    let local_center = (batch.min + batch.max) * 0.5;
+    let local_extents = (batch.max - batch.min) * 0.5;
+    let center = (model * vec4<f32>(local_center, 1.0)).xyz;
+    let extents = abs(model[0].xyz) * local_extents.x
+        + abs(model[1].xyz) * local_extents.y
+        + abs(model[2].xyz) * local_extents.z;
+
+    if (is_visible(center, extents)) {
+        let slot = atomicAdd(&draw_args.data[batch_index].instance_count, 1u);
+        visible.data[batch.first_instance + slot] = instance;
+    }
+}
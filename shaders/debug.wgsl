@@ -0,0 +1,120 @@
+// Debug views, compiled once per view with one of WIREFRAME, NORMALS,
+// TEX_COORDS, DEPTH or OVERDRAW defined.
+//
+// With PULL_VERTICES the mesh is read from storage buffers and drawn without an
+// index buffer, so every triangle corner gets its own barycentric coordinates.
+// The wireframe uses them when PolygonMode::Line isn't supported.
+
+#define CAMERA_GROUP 1
+#include "camera.wgsl"
+#include "instance.wgsl"
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] tex_coords: vec2<f32>;
+    [[location(1)]] normal: vec3<f32>;
+    [[location(2)]] barycentric: vec3<f32>;
+};
+
+#ifdef PULL_VERTICES
+struct VertexInput {
+    position: vec3<f32>;
+    tex_coords: vec2<f32>;
+    normal: vec3<f32>;
+};
+
+struct Vertices {
+    data: array<f32>;
+};
+struct Indices {
+    data: array<u32>;
+};
+[[group(0), binding(0)]]
+var<storage, read> vertices: Vertices;
+[[group(0), binding(1)]]
+var<storage, read> indices: Indices;
+
+// Number of floats in a Vertex
+let VERTEX_STRIDE: u32 = 14u;
+
+fn pull_vertex(vertex_index: u32) -> VertexInput {
+    let i = indices.data[vertex_index] * VERTEX_STRIDE;
+    var model: VertexInput;
+    model.position = vec3<f32>(vertices.data[i], vertices.data[i + 1u], vertices.data[i + 2u]);
+    model.tex_coords = vec2<f32>(vertices.data[i + 3u], vertices.data[i + 4u]);
+    model.normal = vec3<f32>(vertices.data[i + 5u], vertices.data[i + 6u], vertices.data[i + 7u]);
+    return model;
+}
+#else
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] tex_coords: vec2<f32>;
+    [[location(2)]] normal: vec3<f32>;
+};
+#endif
+
+fn transform(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model_matrix = instance_model_matrix(instance);
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+    out.tex_coords = model.tex_coords;
+    // Instances are only rotated and translated, so this is enough for normals
+    out.normal = (model_matrix * vec4<f32>(model.normal, 0.0)).xyz;
+    out.barycentric = vec3<f32>(0.0);
+    return out;
+}
+
+#ifdef PULL_VERTICES
+[[stage(vertex)]]
+fn vs_main(
+    [[builtin(vertex_index)]] vertex_index: u32,
+    instance: InstanceInput,
+) -> VertexOutput {
+    var out = transform(pull_vertex(vertex_index), instance);
+    let corner = vertex_index % 3u;
+    out.barycentric = vec3<f32>(
+        select(0.0, 1.0, corner == 0u),
+        select(0.0, 1.0, corner == 1u),
+        select(0.0, 1.0, corner == 2u),
+    );
+    return out;
+}
+#else
+[[stage(vertex)]]
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    return transform(model, instance);
+}
+#endif
+
+[[stage(fragment)]]
+fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+#ifdef WIREFRAME
+#ifdef PULL_VERTICES
+    // Only keep fragments within about a pixel of an edge
+    let edge = step(fwidth(in.barycentric), in.barycentric);
+    if (edge.x * edge.y * edge.z > 0.5) {
+        discard;
+    }
+#endif
+    return vec4<f32>(1.0);
+#endif
+#ifdef NORMALS
+    return vec4<f32>(normalize(in.normal) * 0.5 + 0.5, 1.0);
+#endif
+#ifdef TEX_COORDS
+    return vec4<f32>(in.tex_coords, 0.0, 1.0);
+#endif
+#ifdef DEPTH
+    // The depth buffer isn't linear, this gives the distance from the camera
+    let depth = in.clip_position.z;
+    let distance = ZNEAR * ZFAR / (ZFAR - depth * (ZFAR - ZNEAR));
+    return vec4<f32>(vec3<f32>(distance / ZFAR), 1.0);
+#endif
+#ifdef OVERDRAW
+    // Added up by the blend state into the count of the pixel
+    return vec4<f32>(1.0, 0.0, 0.0, 0.0);
+#endif
+}
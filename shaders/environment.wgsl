@@ -0,0 +1,190 @@
+// Image based lighting of environment.rs, generated once at startup.
+//
+// Without defines this draws one face of the procedural sky. IRRADIANCE
+// convolves the sky into the diffuse light reaching a normal and PREFILTER blurs
+// it into the specular light reflected by a roughness, one face and mip level at
+// a time. BRDF_LUT integrates the scale and bias applied to the specular
+// reflectance, by view angle and roughness.
+
+#include "fullscreen.wgsl"
+
+let PI: f32 = 3.14159265359;
+
+#ifdef IRRADIANCE
+#define CONVOLVE
+#endif
+#ifdef PREFILTER
+#define CONVOLVE
+#endif
+
+#ifndef BRDF_LUT
+struct Params {
+    // Cube face being drawn, in the order of the array layers
+    face: u32;
+    // Only used with PREFILTER defined
+    roughness: f32;
+};
+[[group(0), binding(2)]]
+var<uniform> params: Params;
+
+// Direction through a point of a cube face, with tex_coords going from 0 at
+// the top left to 1 at the bottom right of the face
+fn face_direction(face: u32, tex_coords: vec2<f32>) -> vec3<f32> {
+    let st = tex_coords * 2.0 - 1.0;
+    var direction: vec3<f32>;
+    if (face == 0u) {
+        direction = vec3<f32>(1.0, -st.y, -st.x);
+    } else if (face == 1u) {
+        direction = vec3<f32>(-1.0, -st.y, st.x);
+    } else if (face == 2u) {
+        direction = vec3<f32>(st.x, 1.0, st.y);
+    } else if (face == 3u) {
+        direction = vec3<f32>(st.x, -1.0, -st.y);
+    } else if (face == 4u) {
+        direction = vec3<f32>(st.x, -st.y, 1.0);
+    } else {
+        direction = vec3<f32>(-st.x, -st.y, -1.0);
+    }
+    return normalize(direction);
+}
+#endif
+
+#ifdef CONVOLVE
+// The sky
+[[group(0), binding(0)]]
+var t_source: texture_cube<f32>;
+[[group(0), binding(1)]]
+var s_source: sampler;
+#endif
+
+// Rotates `v` from a space where z is up to one where `normal` is
+fn to_world(v: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    let up = select(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 0.0, 1.0), abs(normal.z) < 0.999);
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+    return normalize(tangent * v.x + bitangent * v.y + normal * v.z);
+}
+
+// Point i of the Hammersley sequence, evenly spreading `count` points over the
+// unit square
+fn hammersley(i: u32, count: u32) -> vec2<f32> {
+    var radical_inverse = 0.0;
+    var scale = 0.5;
+    for (var bits = i; bits > 0u; bits = bits >> 1u) {
+        if ((bits & 1u) == 1u) {
+            radical_inverse = radical_inverse + scale;
+        }
+        scale = scale * 0.5;
+    }
+    return vec2<f32>(f32(i) / f32(count), radical_inverse);
+}
+
+// Half vector around `normal` sampled with the GGX distribution, so samples
+// concentrate where the specular lobe is
+fn importance_sample_ggx(xi: vec2<f32>, normal: vec3<f32>, roughness: f32) -> vec3<f32> {
+    let a = roughness * roughness;
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    return to_world(vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta), normal);
+}
+
+#ifdef IRRADIANCE
+// Angle between the samples of the hemisphere
+let SAMPLE_DELTA: f32 = 0.05;
+
+fn environment(direction: vec3<f32>) -> vec3<f32> {
+    var sum = vec3<f32>(0.0);
+    var count = 0.0;
+    for (var phi = 0.0; phi < 2.0 * PI; phi = phi + SAMPLE_DELTA) {
+        for (var theta = 0.0; theta < 0.5 * PI; theta = theta + SAMPLE_DELTA) {
+            let v = vec3<f32>(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            let color = textureSampleLevel(t_source, s_source, to_world(v, direction), 0.0).rgb;
+            // Weighted by cos for the angle of incidence and sin for the
+            // smaller rings near the pole
+            sum = sum + color * cos(theta) * sin(theta);
+            count = count + 1.0;
+        }
+    }
+    return PI * sum / count;
+}
+#else
+#ifdef PREFILTER
+let SAMPLE_COUNT: u32 = 256u;
+
+// Assumes the view and reflection directions are the normal, which loses the
+// stretched reflections at grazing angles
+fn environment(direction: vec3<f32>) -> vec3<f32> {
+    var sum = vec3<f32>(0.0);
+    var weight = 0.0;
+    for (var i = 0u; i < SAMPLE_COUNT; i = i + 1u) {
+        let half_dir = importance_sample_ggx(hammersley(i, SAMPLE_COUNT), direction, params.roughness);
+        let light_dir = normalize(2.0 * dot(direction, half_dir) * half_dir - direction);
+        let n_dot_l = dot(direction, light_dir);
+        if (n_dot_l > 0.0) {
+            sum = sum + textureSampleLevel(t_source, s_source, light_dir, 0.0).rgb * n_dot_l;
+            weight = weight + n_dot_l;
+        }
+    }
+    return sum / weight;
+}
+#else
+#ifndef BRDF_LUT
+let SUN_DIRECTION: vec3<f32> = vec3<f32>(0.48, 0.6, 0.64);
+
+// Blue sky fading to a bright horizon, over brown ground, with a soft sun
+fn environment(direction: vec3<f32>) -> vec3<f32> {
+    let zenith = vec3<f32>(0.15, 0.35, 0.8);
+    let horizon = vec3<f32>(0.7, 0.8, 0.9);
+    let ground = vec3<f32>(0.25, 0.2, 0.15);
+    var color: vec3<f32>;
+    if (direction.y >= 0.0) {
+        color = mix(horizon, zenith, sqrt(direction.y));
+    } else {
+        color = mix(horizon, ground, pow(-direction.y, 0.3));
+    }
+    let sun = pow(max(dot(direction, SUN_DIRECTION), 0.0), 64.0);
+    return color + vec3<f32>(4.0, 3.6, 3.0) * sun;
+}
+#endif
+#endif
+#endif
+
+#ifdef BRDF_LUT
+let SAMPLE_COUNT: u32 = 512u;
+
+// Split sum scale and bias of the specular reflectance, with the view angle
+// along x and the roughness along y
+[[stage(fragment)]]
+fn fs_main(in: FullscreenOutput) -> [[location(0)]] vec4<f32> {
+    let n_dot_v = in.tex_coords.x;
+    let roughness = in.tex_coords.y;
+    let view_dir = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+    let normal = vec3<f32>(0.0, 0.0, 1.0);
+    // k of Schlick-GGX for image based lighting
+    let k = roughness * roughness / 2.0;
+
+    var scale = 0.0;
+    var bias = 0.0;
+    for (var i = 0u; i < SAMPLE_COUNT; i = i + 1u) {
+        let half_dir = importance_sample_ggx(hammersley(i, SAMPLE_COUNT), normal, roughness);
+        let light_dir = normalize(2.0 * dot(view_dir, half_dir) * half_dir - view_dir);
+        let n_dot_l = max(light_dir.z, 0.0);
+        if (n_dot_l > 0.0) {
+            let n_dot_h = max(half_dir.z, 0.0);
+            let v_dot_h = max(dot(view_dir, half_dir), 0.0);
+            let geometry = n_dot_v / (n_dot_v * (1.0 - k) + k) * n_dot_l / (n_dot_l * (1.0 - k) + k);
+            let visibility = geometry * v_dot_h / (n_dot_h * n_dot_v);
+            let fresnel = pow(1.0 - v_dot_h, 5.0);
+            scale = scale + (1.0 - fresnel) * visibility;
+            bias = bias + fresnel * visibility;
+        }
+    }
+    return vec4<f32>(vec2<f32>(scale, bias) / f32(SAMPLE_COUNT), 0.0, 1.0);
+}
+#else
+[[stage(fragment)]]
+fn fs_main(in: FullscreenOutput) -> [[location(0)]] vec4<f32> {
+    return vec4<f32>(environment(face_direction(params.face, in.tex_coords)), 1.0);
+}
+#endif
@@ -0,0 +1,62 @@
+#include "postprocess.wgsl"
+
+struct Params {
+    // Minimum contrast, relative to the brightest neighbour, of edges that get
+    // anti-aliased
+    edge_threshold: f32;
+    // Contrast below which dark areas are skipped
+    edge_threshold_min: f32;
+    // Longest blur along an edge in pixels
+    span_max: f32;
+};
+[[group(0), binding(2)]]
+var<uniform> params: Params;
+
+// Blurs along edges found from the luminance of the diagonal neighbours, based
+// on the simplified version of Timothy Lottes' FXAA
+[[stage(fragment)]]
+fn fs_main(in: FullscreenOutput) -> [[location(0)]] vec4<f32> {
+    let texel = input_texel_size();
+    let uv = in.tex_coords;
+    let center = sample_input(uv);
+
+    let luma_nw = luminance(sample_input(uv + vec2<f32>(-1.0, -1.0) * texel).rgb);
+    let luma_ne = luminance(sample_input(uv + vec2<f32>(1.0, -1.0) * texel).rgb);
+    let luma_sw = luminance(sample_input(uv + vec2<f32>(-1.0, 1.0) * texel).rgb);
+    let luma_se = luminance(sample_input(uv + vec2<f32>(1.0, 1.0) * texel).rgb);
+    let luma_m = luminance(center.rgb);
+
+    let luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    let luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+    if (luma_max - luma_min < max(params.edge_threshold_min, luma_max * params.edge_threshold)) {
+        return center;
+    }
+
+    // Perpendicular to the gradient, so along the edge
+    var dir = vec2<f32>(
+        (luma_sw + luma_se) - (luma_nw + luma_ne),
+        (luma_nw + luma_sw) - (luma_ne + luma_se),
+    );
+    let dir_reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.03125, 0.0078125);
+    let inverse_dir_min = 1.0 / (min(abs(dir.x), abs(dir.y)) + dir_reduce);
+    dir = clamp(
+        dir * inverse_dir_min,
+        vec2<f32>(-params.span_max),
+        vec2<f32>(params.span_max),
+    ) * texel;
+
+    let inner = 0.5 * (
+        sample_input(uv + dir * (1.0 / 3.0 - 0.5)).rgb
+        + sample_input(uv + dir * (2.0 / 3.0 - 0.5)).rgb
+    );
+    let outer = inner * 0.5 + 0.25 * (
+        sample_input(uv - dir * 0.5).rgb
+        + sample_input(uv + dir * 0.5).rgb
+    );
+    // The wider blur crossed another edge if it's outside the local range
+    let luma_outer = luminance(outer);
+    if (luma_outer < luma_min || luma_outer > luma_max) {
+        return vec4<f32>(inner, center.a);
+    }
+    return vec4<f32>(outer, center.a);
+}
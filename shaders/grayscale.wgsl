@@ -0,0 +1,15 @@
+#include "postprocess.wgsl"
+
+struct Params {
+    // 0 keeps the colors, 1 is fully gray
+    amount: f32;
+};
+[[group(0), binding(2)]]
+var<uniform> params: Params;
+
+[[stage(fragment)]]
+fn fs_main(in: FullscreenOutput) -> [[location(0)]] vec4<f32> {
+    let color = sample_input(in.tex_coords);
+    let gray = vec3<f32>(luminance(color.rgb));
+    return vec4<f32>(mix(color.rgb, gray, vec3<f32>(params.amount)), color.a);
+}
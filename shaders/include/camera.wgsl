@@ -0,0 +1,10 @@
+// Define CAMERA_GROUP as the bind group the camera uniform is bound to before
+// including this file
+
+struct Camera {
+    view_proj: mat4x4<f32>;
+    // w is unused
+    position: vec4<f32>;
+};
+[[group(CAMERA_GROUP), binding(0)]]
+var<uniform> camera: Camera;
@@ -0,0 +1,71 @@
+// Light clusters of clustering.rs, the view frustum split into a grid of
+// froxels that each list the point lights touching them. Include lights.wgsl
+// before this file. Define CLUSTER_ACCESS as read_write to write the clusters.
+
+#ifndef CLUSTER_ACCESS
+#define CLUSTER_ACCESS read
+#endif
+
+struct ClusterParams {
+    view: mat4x4<f32>;
+    inv_proj: mat4x4<f32>;
+    // Clusters along x, y and z, and the most lights a cluster can list in w
+    grid: vec4<u32>;
+    screen_size: vec2<f32>;
+    znear: f32;
+    zfar: f32;
+};
+
+// The lights of a cluster are light_indices[offset..offset + count]
+struct Cluster {
+    offset: u32;
+    count: u32;
+};
+
+struct Clusters {
+    data: array<Cluster>;
+};
+struct LightIndices {
+    data: array<u32>;
+};
+
+[[group(LIGHTS_GROUP), binding(1)]]
+var<uniform> cluster_params: ClusterParams;
+[[group(LIGHTS_GROUP), binding(2)]]
+var<storage, CLUSTER_ACCESS> clusters: Clusters;
+[[group(LIGHTS_GROUP), binding(3)]]
+var<storage, CLUSTER_ACCESS> light_indices: LightIndices;
+
+// Depth of the near side of a slice, slices are spaced exponentially so
+// clusters are about as deep as they are wide
+fn slice_depth(slice: u32) -> f32 {
+    let ratio = cluster_params.zfar / cluster_params.znear;
+    return cluster_params.znear * pow(ratio, f32(slice) / f32(cluster_params.grid.z));
+}
+
+// Index of the cluster containing a fragment, from its builtin position and
+// world position
+fn cluster_index(frag_coord: vec2<f32>, world_position: vec3<f32>) -> u32 {
+    let grid = cluster_params.grid;
+    let view_depth = -(cluster_params.view * vec4<f32>(world_position, 1.0)).z;
+    let ratio = log(view_depth / cluster_params.znear) / log(cluster_params.zfar / cluster_params.znear);
+    let slice = u32(clamp(ratio * f32(grid.z), 0.0, f32(grid.z - 1u)));
+    let tile = vec2<u32>(frag_coord / cluster_params.screen_size * vec2<f32>(grid.xy));
+    let clamped = min(tile, grid.xy - vec2<u32>(1u));
+    return clamped.x + grid.x * (clamped.y + grid.y * slice);
+}
+
+// Like direct_lighting in lights.wgsl, but only with the lights of the
+// fragment's cluster
+fn clustered_lighting(surface: Surface, camera_position: vec3<f32>, frag_coord: vec2<f32>) -> vec3<f32> {
+    let cluster = clusters.data[cluster_index(frag_coord, surface.position)];
+    let view_dir = normalize(camera_position - surface.position);
+    var color = vec3<f32>(0.0);
+    for (var i = 0u; i < cluster.count; i = i + 1u) {
+        // Not indexed in one expression, as `]]` would be read as the end of an
+        // attribute
+        let light_index = light_indices.data[cluster.offset + i];
+        color = color + point_light(lights.point_lights[light_index], surface, view_dir);
+    }
+    return color;
+}
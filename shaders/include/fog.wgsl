@@ -0,0 +1,55 @@
+// Fog of scene.rs. Include lights.wgsl first, which defines the bind group.
+
+let FOG_LINEAR: u32 = 1u;
+let FOG_EXPONENTIAL: u32 = 2u;
+let FOG_EXPONENTIAL_SQUARED: u32 = 3u;
+
+struct Scene {
+    fog_color: vec3<f32>;
+    // 0 without distance fog, or one of the FOG_ constants
+    fog_mode: u32;
+    fog_start: f32;
+    fog_end: f32;
+    fog_density: f32;
+    // 0 without height fog
+    height_density: f32;
+    height_base: f32;
+    height_falloff: f32;
+};
+
+[[group(LIGHTS_GROUP), binding(8)]]
+var<uniform> scene: Scene;
+
+// Fraction of the light leaving `position` that reaches the camera through the
+// fog
+fn fog_transmittance(position: vec3<f32>, camera_position: vec3<f32>) -> f32 {
+    let distance = length(position - camera_position);
+    var transmittance = 1.0;
+    if (scene.fog_mode == FOG_LINEAR) {
+        transmittance = clamp((scene.fog_end - distance) / (scene.fog_end - scene.fog_start), 0.0, 1.0);
+    } else if (scene.fog_mode == FOG_EXPONENTIAL) {
+        transmittance = exp(-scene.fog_density * distance);
+    } else if (scene.fog_mode == FOG_EXPONENTIAL_SQUARED) {
+        let optical_depth = scene.fog_density * distance;
+        transmittance = exp(-optical_depth * optical_depth);
+    }
+
+    if (scene.height_density > 0.0) {
+        // The density falls off exponentially with height, so its integral
+        // along the ray has a closed form
+        let falloff = scene.height_falloff;
+        let camera_density = scene.height_density * exp(-falloff * (camera_position.y - scene.height_base));
+        let rise = falloff * (position.y - camera_position.y);
+        var optical_depth = camera_density * distance;
+        // The closed form divides by 0 for horizontal rays
+        if (abs(rise) > 0.0001) {
+            optical_depth = optical_depth * (1.0 - exp(-rise)) / rise;
+        }
+        transmittance = transmittance * exp(-optical_depth);
+    }
+    return transmittance;
+}
+
+fn apply_fog(color: vec3<f32>, position: vec3<f32>, camera_position: vec3<f32>) -> vec3<f32> {
+    return mix(scene.fog_color, color, fog_transmittance(position, camera_position));
+}
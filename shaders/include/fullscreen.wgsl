@@ -0,0 +1,17 @@
+// Vertex shader of fullscreen passes, drawn with 3 vertices and no buffers
+
+struct FullscreenOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] tex_coords: vec2<f32>;
+};
+
+// One triangle that covers the whole screen, tex_coords go from 0 at the top
+// left corner to 1 at the bottom right
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] index: u32) -> FullscreenOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: FullscreenOutput;
+    out.clip_position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.tex_coords = uv;
+    return out;
+}
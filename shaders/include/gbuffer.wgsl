@@ -0,0 +1,21 @@
+// Output of materials drawn to the G-buffer, matching the targets in deferred.rs.
+// Include lights.wgsl first for Surface.
+
+struct GBufferOutput {
+    [[location(0)]] albedo: vec4<f32>;
+    // World space, xyz in -1..1
+    [[location(1)]] normal: vec4<f32>;
+    // Metallic, roughness and occlusion
+    [[location(2)]] material: vec4<f32>;
+    // Added to the lit color
+    [[location(3)]] emissive: vec4<f32>;
+};
+
+fn gbuffer_output(surface: Surface, emissive: vec3<f32>) -> GBufferOutput {
+    var out: GBufferOutput;
+    out.albedo = vec4<f32>(surface.albedo, 1.0);
+    out.normal = vec4<f32>(surface.normal, 0.0);
+    out.material = vec4<f32>(surface.metallic, surface.roughness, surface.occlusion, 0.0);
+    out.emissive = vec4<f32>(emissive, 0.0);
+    return out;
+}
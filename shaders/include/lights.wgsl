@@ -0,0 +1,144 @@
+// Lights of light.rs and the physically based lighting of a surface. Define
+// LIGHTS_GROUP as the bind group the lights are bound to before including this
+// file.
+
+let PI: f32 = 3.14159265359;
+
+struct PointLight {
+    position: vec3<f32>;
+    // Distance at which the light has faded out completely
+    range: f32;
+    color: vec3<f32>;
+    intensity: f32;
+};
+
+struct Lights {
+    // Scales the light of the environment
+    ambient: vec3<f32>;
+    point_light_count: u32;
+    point_lights: array<PointLight>;
+};
+
+[[group(LIGHTS_GROUP), binding(0)]]
+var<storage, read> lights: Lights;
+
+// Image based lighting of environment.rs
+[[group(LIGHTS_GROUP), binding(4)]]
+var t_irradiance: texture_cube<f32>;
+// Mip levels are prefiltered for increasing roughness
+[[group(LIGHTS_GROUP), binding(5)]]
+var t_prefiltered: texture_cube<f32>;
+[[group(LIGHTS_GROUP), binding(6)]]
+var t_brdf_lut: texture_2d<f32>;
+[[group(LIGHTS_GROUP), binding(7)]]
+var s_environment: sampler;
+
+// Screen space ambient occlusion of ssao.rs, 1 where nothing is occluded
+[[group(LIGHTS_GROUP), binding(9)]]
+var t_screen_occlusion: texture_2d<f32>;
+
+// Occlusion of the opaque scene at a fragment's position
+fn screen_occlusion(clip_position: vec4<f32>) -> f32 {
+    return textureLoad(t_screen_occlusion, vec2<i32>(clip_position.xy), 0).r;
+}
+
+// A surface of the glTF metallic-roughness model
+struct Surface {
+    position: vec3<f32>;
+    normal: vec3<f32>;
+    // Base color
+    albedo: vec3<f32>;
+    metallic: f32;
+    // Perceptual roughness, squared before it's used
+    roughness: f32;
+    // Only applies to the light of the environment
+    occlusion: f32;
+};
+
+// Trowbridge-Reitz GGX normal distribution
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+// Smith's shadowing-masking with Schlick-GGX for both directions
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let r = roughness + 1.0;
+    let k = r * r / 8.0;
+    let g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return g_v * g_l;
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (vec3<f32>(1.0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Reflectance at normal incidence, dielectrics reflect 4%
+fn surface_f0(surface: Surface) -> vec3<f32> {
+    return mix(vec3<f32>(0.04), surface.albedo, surface.metallic);
+}
+
+// Lambert diffuse and Cook-Torrance specular light from one point light
+fn point_light(light: PointLight, surface: Surface, view_dir: vec3<f32>) -> vec3<f32> {
+    let to_light = light.position - surface.position;
+    let distance = length(to_light);
+    if (distance >= light.range) {
+        return vec3<f32>(0.0);
+    }
+    let light_dir = to_light / distance;
+    let n_dot_l = dot(surface.normal, light_dir);
+    if (n_dot_l <= 0.0) {
+        return vec3<f32>(0.0);
+    }
+    // Inverse square falloff, windowed so it reaches 0 at the range
+    let ratio = distance / light.range;
+    let window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+    let attenuation = window * window / (distance * distance + 1.0);
+    let radiance = light.color * light.intensity * attenuation;
+
+    let half_dir = normalize(light_dir + view_dir);
+    let n_dot_v = max(dot(surface.normal, view_dir), 0.0001);
+    let n_dot_h = max(dot(surface.normal, half_dir), 0.0);
+    let fresnel = fresnel_schlick(max(dot(half_dir, view_dir), 0.0), surface_f0(surface));
+    let specular = distribution_ggx(n_dot_h, surface.roughness)
+        * geometry_smith(n_dot_v, n_dot_l, surface.roughness)
+        * fresnel / (4.0 * n_dot_v * n_dot_l + 0.0001);
+    // Metals have no diffuse, and light reflected by the specular isn't diffused
+    let diffuse = (vec3<f32>(1.0) - fresnel) * (1.0 - surface.metallic) * surface.albedo / PI;
+    return (diffuse + specular) * radiance * n_dot_l;
+}
+
+// Light from every point light, without the environment
+fn direct_lighting(surface: Surface, camera_position: vec3<f32>) -> vec3<f32> {
+    let view_dir = normalize(camera_position - surface.position);
+    var color = vec3<f32>(0.0);
+    for (var i = 0u; i < lights.point_light_count; i = i + 1u) {
+        color = color + point_light(lights.point_lights[i], surface, view_dir);
+    }
+    return color;
+}
+
+// Light from the environment, with the split sum approximation for the
+// specular
+fn ambient_lighting(surface: Surface, camera_position: vec3<f32>) -> vec3<f32> {
+    let view_dir = normalize(camera_position - surface.position);
+    let n_dot_v = max(dot(surface.normal, view_dir), 0.0001);
+    let f0 = surface_f0(surface);
+    // Fresnel with the roughness folded in, as there's no single half vector
+    let f90 = max(vec3<f32>(1.0 - surface.roughness), f0);
+    let fresnel = f0 + (f90 - f0) * pow(1.0 - n_dot_v, 5.0);
+
+    let irradiance = textureSampleLevel(t_irradiance, s_environment, surface.normal, 0.0).rgb;
+    let diffuse = (vec3<f32>(1.0) - fresnel) * (1.0 - surface.metallic) * surface.albedo * irradiance;
+
+    let reflected = reflect(-view_dir, surface.normal);
+    let max_lod = f32(textureNumLevels(t_prefiltered) - 1);
+    let prefiltered = textureSampleLevel(t_prefiltered, s_environment, reflected, surface.roughness * max_lod).rgb;
+    let brdf = textureSampleLevel(t_brdf_lut, s_environment, vec2<f32>(n_dot_v, surface.roughness), 0.0).rg;
+    let specular = prefiltered * (fresnel * brdf.x + brdf.y);
+
+    return (diffuse + specular) * surface.occlusion * lights.ambient;
+}
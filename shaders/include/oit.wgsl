@@ -0,0 +1,21 @@
+// Output of materials drawn with weighted blended order-independent
+// transparency, matching the targets in oit.rs
+
+struct OitOutput {
+    [[location(0)]] accum: vec4<f32>;
+    [[location(1)]] revealage: f32;
+};
+
+// `depth` is the fragment's depth from 0 at the near plane to 1 at the far one
+fn oit_output(color: vec4<f32>, depth: f32) -> OitOutput {
+    // Equation 10 from McGuire and Bavoil's paper, close and opaque fragments
+    // get a higher weight
+    let a = min(1.0, color.a * 10.0) + 0.01;
+    let b = 1.0 - depth * 0.9;
+    let weight = clamp(a * a * a * 100000000.0 * b * b * b, 0.01, 3000.0);
+
+    var out: OitOutput;
+    out.accum = vec4<f32>(color.rgb * color.a, color.a) * weight;
+    out.revealage = color.a;
+    return out;
+}
@@ -0,0 +1,21 @@
+// Input of post processing effects, the output of the previous effect or of
+// tonemapping. Effects declare their params at binding 2, see postprocess.rs.
+
+#include "fullscreen.wgsl"
+
+[[group(0), binding(0)]]
+var t_input: texture_2d<f32>;
+[[group(0), binding(1)]]
+var s_input: sampler;
+
+fn sample_input(tex_coords: vec2<f32>) -> vec4<f32> {
+    return textureSampleLevel(t_input, s_input, tex_coords, 0.0);
+}
+
+fn input_texel_size() -> vec2<f32> {
+    return vec2<f32>(1.0) / vec2<f32>(textureDimensions(t_input));
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
@@ -0,0 +1,54 @@
+// Lighting pass of deferred.rs, shading every pixel of the G-buffer with every
+// light and the environment, then fogging it
+
+#include "fullscreen.wgsl"
+#define LIGHTS_GROUP 0
+#include "lights.wgsl"
+#include "fog.wgsl"
+
+struct Params {
+    inv_view_proj: mat4x4<f32>;
+    // w is unused
+    camera_position: vec4<f32>;
+    // Where nothing was drawn
+    background: vec4<f32>;
+};
+
+// Bindings 1 to 9 are used by lights.wgsl and fog.wgsl
+[[group(0), binding(10)]]
+var t_albedo: texture_2d<f32>;
+[[group(0), binding(11)]]
+var t_normal: texture_2d<f32>;
+[[group(0), binding(12)]]
+var t_material: texture_2d<f32>;
+[[group(0), binding(13)]]
+var t_emissive: texture_2d<f32>;
+[[group(0), binding(14)]]
+var t_depth: texture_depth_2d;
+[[group(0), binding(15)]]
+var<uniform> params: Params;
+
+[[stage(fragment)]]
+fn fs_main(in: FullscreenOutput) -> [[location(0)]] vec4<f32> {
+    let coords = vec2<i32>(in.clip_position.xy);
+    let depth = textureLoad(t_depth, coords, 0);
+    if (depth >= 1.0) {
+        return params.background;
+    }
+
+    let ndc = vec4<f32>(in.tex_coords.x * 2.0 - 1.0, 1.0 - in.tex_coords.y * 2.0, depth, 1.0);
+    let position = params.inv_view_proj * ndc;
+    let material = textureLoad(t_material, coords, 0);
+    var surface: Surface;
+    surface.position = position.xyz / position.w;
+    surface.normal = normalize(textureLoad(t_normal, coords, 0).xyz);
+    surface.albedo = textureLoad(t_albedo, coords, 0).rgb;
+    surface.metallic = material.r;
+    surface.roughness = material.g;
+    surface.occlusion = material.b * screen_occlusion(in.clip_position);
+
+    let camera_position = params.camera_position.xyz;
+    let emissive = textureLoad(t_emissive, coords, 0).rgb;
+    let color = ambient_lighting(surface, camera_position) + direct_lighting(surface, camera_position) + emissive;
+    return vec4<f32>(apply_fog(color, surface.position, camera_position), 1.0);
+}
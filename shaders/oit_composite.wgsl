@@ -0,0 +1,22 @@
+#include "fullscreen.wgsl"
+
+[[group(0), binding(0)]]
+var t_accum: texture_2d<f32>;
+[[group(0), binding(1)]]
+var t_revealage: texture_2d<f32>;
+
+// Resolves the accumulated transparent fragments, alpha blended over the
+// opaque scene
+[[stage(fragment)]]
+fn fs_main(in: FullscreenOutput) -> [[location(0)]] vec4<f32> {
+    let coords = vec2<i32>(in.clip_position.xy);
+    // Product of (1 - alpha) of every fragment, so 1 where nothing was drawn
+    let revealage = textureLoad(t_revealage, coords, 0).r;
+    if (revealage >= 1.0) {
+        discard;
+    }
+
+    let accum = textureLoad(t_accum, coords, 0);
+    let color = accum.rgb / clamp(accum.a, 0.0001, 50000.0);
+    return vec4<f32>(color, 1.0 - revealage);
+}
@@ -0,0 +1,30 @@
+#include "fullscreen.wgsl"
+
+[[group(0), binding(0)]]
+var t_overdraw: texture_2d<f32>;
+
+// Count at which the ramp reaches red
+let MAX_OVERDRAW: f32 = 8.0;
+
+// Black where nothing was drawn, then blue, green, yellow and red as the
+// count goes up to MAX_OVERDRAW
+fn ramp(count: f32) -> vec3<f32> {
+    let x = clamp(count / MAX_OVERDRAW, 0.0, 1.0) * 4.0;
+    if (x < 1.0) {
+        return mix(vec3<f32>(0.0), vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(x));
+    }
+    if (x < 2.0) {
+        return mix(vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(x - 1.0));
+    }
+    if (x < 3.0) {
+        return mix(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 1.0, 0.0), vec3<f32>(x - 2.0));
+    }
+    return mix(vec3<f32>(1.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(x - 3.0));
+}
+
+// Maps how many times every pixel was drawn to a color
+[[stage(fragment)]]
+fn fs_main(in: FullscreenOutput) -> [[location(0)]] vec4<f32> {
+    let count = textureLoad(t_overdraw, vec2<i32>(in.clip_position.xy), 0).r;
+    return vec4<f32>(ramp(count), 1.0);
+}
@@ -0,0 +1,161 @@
+// glTF metallic-roughness material, see pbr.rs
+
+// Vertex shader
+
+#define CAMERA_GROUP 1
+#include "camera.wgsl"
+#include "instance.wgsl"
+#define LIGHTS_GROUP 2
+#include "lights.wgsl"
+#ifdef GBUFFER
+#include "gbuffer.wgsl"
+#else
+#include "clusters.wgsl"
+#include "fog.wgsl"
+#endif
+#ifdef OIT
+#include "oit.wgsl"
+#endif
+
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] tex_coords: vec2<f32>;
+    [[location(2)]] normal: vec3<f32>;
+    [[location(3)]] tangent: vec3<f32>;
+    [[location(4)]] bitangent: vec3<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] tex_coords: vec2<f32>;
+    [[location(1)]] world_position: vec3<f32>;
+    [[location(2)]] world_normal: vec3<f32>;
+    [[location(3)]] world_tangent: vec3<f32>;
+    [[location(4)]] world_bitangent: vec3<f32>;
+};
+
+[[stage(vertex)]]
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = instance_model_matrix(instance);
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    var out: VertexOutput;
+    out.tex_coords = model.tex_coords;
+    out.world_position = world_position.xyz;
+    // Instances are only rotated and translated, so this keeps the tangent
+    // frame perpendicular to the surface
+    out.world_normal = (model_matrix * vec4<f32>(model.normal, 0.0)).xyz;
+    out.world_tangent = (model_matrix * vec4<f32>(model.tangent, 0.0)).xyz;
+    out.world_bitangent = (model_matrix * vec4<f32>(model.bitangent, 0.0)).xyz;
+    out.clip_position = camera.view_proj * world_position;
+    return out;
+}
+
+// Fragment shader
+
+// Roughness is clamped to this, as the highlight of a perfectly smooth surface
+// is infinitely small
+let MIN_ROUGHNESS: f32 = 0.04;
+
+struct Material {
+    base_color: vec4<f32>;
+    emissive: vec3<f32>;
+    // Only used with ALPHA_MASK defined
+    alpha_cutoff: f32;
+    metallic: f32;
+    roughness: f32;
+    // Scales the xy of the normal map
+    normal_scale: f32;
+    // How much of the occlusion map is applied
+    occlusion_strength: f32;
+    // Texture coordinate transform, e.g. into an atlas
+    uv_offset: vec2<f32>;
+    uv_scale: vec2<f32>;
+};
+[[group(0), binding(0)]]
+var<uniform> material: Material;
+
+[[group(0), binding(1)]]
+var t_base_color: texture_2d<f32>;
+[[group(0), binding(2)]]
+var s_base_color: sampler;
+// Roughness in g and metallic in b
+[[group(0), binding(3)]]
+var t_metallic_roughness: texture_2d<f32>;
+[[group(0), binding(4)]]
+var s_metallic_roughness: sampler;
+// Tangent space, in a linear format
+[[group(0), binding(5)]]
+var t_normal: texture_2d<f32>;
+[[group(0), binding(6)]]
+var s_normal: sampler;
+// Occlusion in r
+[[group(0), binding(7)]]
+var t_occlusion: texture_2d<f32>;
+[[group(0), binding(8)]]
+var s_occlusion: sampler;
+[[group(0), binding(9)]]
+var t_emissive: texture_2d<f32>;
+[[group(0), binding(10)]]
+var s_emissive: sampler;
+
+[[stage(fragment)]]
+#ifdef GBUFFER
+fn fs_main(in: VertexOutput, [[builtin(front_facing)]] front_facing: bool) -> GBufferOutput {
+#else
+#ifdef OIT
+fn fs_main(in: VertexOutput, [[builtin(front_facing)]] front_facing: bool) -> OitOutput {
+#else
+fn fs_main(in: VertexOutput, [[builtin(front_facing)]] front_facing: bool) -> [[location(0)]] vec4<f32> {
+#endif
+#endif
+    let uv = material.uv_offset + in.tex_coords * material.uv_scale;
+    // Everything is sampled before discarding, as sampling needs derivatives
+    let color = material.base_color * textureSample(t_base_color, s_base_color, uv);
+    let metallic_roughness = textureSample(t_metallic_roughness, s_metallic_roughness, uv);
+    let normal_sample = textureSample(t_normal, s_normal, uv).xyz * 2.0 - 1.0;
+    let occlusion = textureSample(t_occlusion, s_occlusion, uv).r;
+    let emissive = material.emissive * textureSample(t_emissive, s_emissive, uv).rgb;
+
+    // The back of double sided materials faces the other way, along with its
+    // tangent frame
+    var tbn = mat3x3<f32>(normalize(in.world_tangent), normalize(in.world_bitangent), normalize(in.world_normal));
+    if (!front_facing) {
+        tbn = tbn * -1.0;
+    }
+    let tangent_normal = normalize(vec3<f32>(normal_sample.xy * material.normal_scale, normal_sample.z));
+    let normal = normalize(tbn * tangent_normal);
+
+#ifdef ALPHA_MASK
+    if (color.a < material.alpha_cutoff) {
+        discard;
+    }
+#endif
+
+    var surface: Surface;
+    surface.position = in.world_position;
+    surface.normal = normal;
+    surface.albedo = color.rgb;
+    surface.metallic = clamp(material.metallic * metallic_roughness.b, 0.0, 1.0);
+    surface.roughness = clamp(material.roughness * metallic_roughness.g, MIN_ROUGHNESS, 1.0);
+    surface.occlusion = 1.0 + material.occlusion_strength * (occlusion - 1.0);
+
+#ifdef GBUFFER
+    return gbuffer_output(surface, emissive);
+#else
+    let camera_position = camera.position.xyz;
+#ifndef ALPHA_BLEND
+    // Blended surfaces are in front of the opaque scene it was computed from
+    surface.occlusion = surface.occlusion * screen_occlusion(in.clip_position);
+#endif
+    let direct = clustered_lighting(surface, camera_position, in.clip_position.xy);
+    let lit = apply_fog(ambient_lighting(surface, camera_position) + direct + emissive, in.world_position, camera_position);
+#ifdef OIT
+    return oit_output(vec4<f32>(lit, color.a), in.clip_position.z);
+#else
+    return vec4<f32>(lit, color.a);
+#endif
+#endif
+}
@@ -0,0 +1,20 @@
+#include "postprocess.wgsl"
+
+struct Params {
+    strength: f32;
+};
+[[group(0), binding(2)]]
+var<uniform> params: Params;
+
+// Unsharp mask with the four direct neighbours
+[[stage(fragment)]]
+fn fs_main(in: FullscreenOutput) -> [[location(0)]] vec4<f32> {
+    let texel = input_texel_size();
+    let center = sample_input(in.tex_coords);
+    let neighbours = sample_input(in.tex_coords + vec2<f32>(texel.x, 0.0)).rgb
+        + sample_input(in.tex_coords - vec2<f32>(texel.x, 0.0)).rgb
+        + sample_input(in.tex_coords + vec2<f32>(0.0, texel.y)).rgb
+        + sample_input(in.tex_coords - vec2<f32>(0.0, texel.y)).rgb;
+    let sharpened = center.rgb * (1.0 + 4.0 * params.strength) - neighbours * params.strength;
+    return vec4<f32>(max(sharpened, vec3<f32>(0.0)), center.a);
+}
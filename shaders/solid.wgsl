@@ -1,25 +1,34 @@
 // Vertex shader
 
-struct Camera {
-    view_proj: mat4x4<f32>;
-};
-[[group(1), binding(0)]]
-var<uniform> camera: Camera;
+#define CAMERA_GROUP 1
+#include "camera.wgsl"
+#include "instance.wgsl"
+#define LIGHTS_GROUP 2
+#include "lights.wgsl"
+#ifdef GBUFFER
+#include "gbuffer.wgsl"
+#else
+#include "clusters.wgsl"
+#include "fog.wgsl"
+#endif
+#ifdef OIT
+#include "oit.wgsl"
+#endif
 
 struct VertexInput {
     [[location(0)]] position: vec3<f32>;
     [[location(1)]] tex_coords: vec2<f32>;
-};
-struct InstanceInput {
-    [[location(5)]] model_matrix_0: vec4<f32>;
-    [[location(6)]] model_matrix_1: vec4<f32>;
-    [[location(7)]] model_matrix_2: vec4<f32>;
-    [[location(8)]] model_matrix_3: vec4<f32>;
+    [[location(2)]] normal: vec3<f32>;
 };
 
 struct VertexOutput {
     [[builtin(position)]] clip_position: vec4<f32>;
     [[location(0)]] tex_coords: vec2<f32>;
+    [[location(1)]] world_position: vec3<f32>;
+    [[location(2)]] world_normal: vec3<f32>;
+#ifdef TEXTURE_ARRAY
+    [[location(3), interpolate(flat)]] layer: u32;
+#endif
 };
 
 [[stage(vertex)]]
@@ -27,26 +36,97 @@ fn vs_main(
     model: VertexInput,
     instance: InstanceInput,
 ) -> VertexOutput {
-    let model_matrix = mat4x4<f32>(
-        instance.model_matrix_0,
-        instance.model_matrix_1,
-        instance.model_matrix_2,
-        instance.model_matrix_3,
-    );
+    let model_matrix = instance_model_matrix(instance);
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
     var out: VertexOutput;
     out.tex_coords = model.tex_coords;
-    out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+#ifdef TEXTURE_ARRAY
+    out.layer = instance.layer;
+#endif
+    out.world_position = world_position.xyz;
+    // Instances are only rotated and translated, so this keeps normals
+    // perpendicular to the surface
+    out.world_normal = (model_matrix * vec4<f32>(model.normal, 0.0)).xyz;
+    out.clip_position = camera.view_proj * world_position;
     return out;
 }
 
 // Fragment shader
 
+struct Material {
+    color: vec4<f32>;
+    // Only used with ALPHA_MASK defined
+    alpha_cutoff: f32;
+    metallic: f32;
+    roughness: f32;
+};
 [[group(0), binding(0)]]
-var t_diffuse: texture_2d<f32>;
+var<uniform> material: Material;
+
+#ifdef TEXTURED
 [[group(0), binding(1)]]
+#ifdef TEXTURE_ARRAY
+// Every instance samples its own layer, see texture_array.rs
+var t_diffuse: texture_2d_array<f32>;
+#else
+var t_diffuse: texture_2d<f32>;
+#endif
+[[group(0), binding(2)]]
 var s_diffuse: sampler;
+#endif
 
 [[stage(fragment)]]
-fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
-    return textureSample(t_diffuse, s_diffuse, in.tex_coords);
-}
\ No newline at end of file
+#ifdef GBUFFER
+fn fs_main(in: VertexOutput, [[builtin(front_facing)]] front_facing: bool) -> GBufferOutput {
+#else
+#ifdef OIT
+fn fs_main(in: VertexOutput, [[builtin(front_facing)]] front_facing: bool) -> OitOutput {
+#else
+fn fs_main(in: VertexOutput, [[builtin(front_facing)]] front_facing: bool) -> [[location(0)]] vec4<f32> {
+#endif
+#endif
+#ifdef TEXTURED
+#ifdef TEXTURE_ARRAY
+    let color = material.color * textureSample(t_diffuse, s_diffuse, in.tex_coords, i32(in.layer));
+#else
+    let color = material.color * textureSample(t_diffuse, s_diffuse, in.tex_coords);
+#endif
+#else
+    let color = material.color;
+#endif
+#ifdef ALPHA_MASK
+    if (color.a < material.alpha_cutoff) {
+        discard;
+    }
+#endif
+    // The back of double sided materials faces the other way
+    var normal = normalize(in.world_normal);
+    if (!front_facing) {
+        normal = -normal;
+    }
+
+    var surface: Surface;
+    surface.position = in.world_position;
+    surface.normal = normal;
+    surface.albedo = color.rgb;
+    surface.metallic = material.metallic;
+    surface.roughness = material.roughness;
+    surface.occlusion = 1.0;
+
+#ifdef GBUFFER
+    return gbuffer_output(surface, vec3<f32>(0.0));
+#else
+    let camera_position = camera.position.xyz;
+#ifndef ALPHA_BLEND
+    // Blended surfaces are in front of the opaque scene it was computed from
+    surface.occlusion = surface.occlusion * screen_occlusion(in.clip_position);
+#endif
+    let direct = clustered_lighting(surface, camera_position, in.clip_position.xy);
+    let lit = apply_fog(ambient_lighting(surface, camera_position) + direct, in.world_position, camera_position);
+#ifdef OIT
+    return oit_output(vec4<f32>(lit, color.a), in.clip_position.z);
+#else
+    return vec4<f32>(lit, color.a);
+#endif
+#endif
+}
@@ -0,0 +1,119 @@
+// Passes of ssao.rs. By default the ambient occlusion of the depth buffer is
+// computed, with BLUR defined it's blurred without crossing depth edges.
+
+#include "fullscreen.wgsl"
+
+struct Params {
+    proj: mat4x4<f32>;
+    inv_proj: mat4x4<f32>;
+    // Offsets in a hemisphere around +z with a radius of 1, only the first
+    // sample_count are used
+    kernel: array<vec4<f32>, 32>;
+    // In view space
+    radius: f32;
+    bias: f32;
+    // Exponent of the occlusion
+    intensity: f32;
+    sample_count: u32;
+};
+
+[[group(0), binding(0)]]
+var t_depth: texture_depth_2d;
+// The rotation noise when computing the occlusion, the occlusion otherwise
+[[group(0), binding(1)]]
+var t_input: texture_2d<f32>;
+[[group(0), binding(2)]]
+var<uniform> params: Params;
+
+fn depth_size() -> vec2<i32> {
+    return textureDimensions(t_depth);
+}
+
+fn view_position(coords: vec2<i32>) -> vec3<f32> {
+    let size = depth_size();
+    let clamped = clamp(coords, vec2<i32>(0), size - vec2<i32>(1));
+    let depth = textureLoad(t_depth, clamped, 0);
+    let uv = (vec2<f32>(clamped) + vec2<f32>(0.5)) / vec2<f32>(size);
+    let position = params.inv_proj * vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    return position.xyz / position.w;
+}
+
+#ifdef BLUR
+// 4x4 box filter, which covers exactly one tile of the noise texture, with
+// neighbours at a different depth than the center weighted down
+[[stage(fragment)]]
+fn fs_main(in: FullscreenOutput) -> [[location(0)]] vec4<f32> {
+    let coords = vec2<i32>(in.clip_position.xy);
+    let center = view_position(coords).z;
+    var total = 0.0;
+    var weights = 0.0;
+    for (var y = -2; y < 2; y = y + 1) {
+        for (var x = -2; x < 2; x = x + 1) {
+            let neighbour = coords + vec2<i32>(x, y);
+            let difference = abs(view_position(neighbour).z - center);
+            let weight = exp(-difference * 8.0 / params.radius);
+            let clamped = clamp(neighbour, vec2<i32>(0), depth_size() - vec2<i32>(1));
+            total = total + textureLoad(t_input, clamped, 0).r * weight;
+            weights = weights + weight;
+        }
+    }
+    return vec4<f32>(total / max(weights, 0.0001), 0.0, 0.0, 1.0);
+}
+#else
+// Normal of the surface at coords, from the neighbours on the side with the
+// smaller depth difference so edges don't get a normal between both surfaces
+fn view_normal(coords: vec2<i32>, center: vec3<f32>) -> vec3<f32> {
+    let left = view_position(coords - vec2<i32>(1, 0));
+    let right = view_position(coords + vec2<i32>(1, 0));
+    let up = view_position(coords - vec2<i32>(0, 1));
+    let down = view_position(coords + vec2<i32>(0, 1));
+
+    var dx = right - center;
+    if (abs(center.z - left.z) < abs(right.z - center.z)) {
+        dx = center - left;
+    }
+    var dy = down - center;
+    if (abs(center.z - up.z) < abs(down.z - center.z)) {
+        dy = center - up;
+    }
+    // dy points down the screen, so this faces the camera
+    return normalize(cross(dy, dx));
+}
+
+[[stage(fragment)]]
+fn fs_main(in: FullscreenOutput) -> [[location(0)]] vec4<f32> {
+    let coords = vec2<i32>(in.clip_position.xy);
+    // Nothing was drawn here
+    if (textureLoad(t_depth, coords, 0) >= 1.0) {
+        return vec4<f32>(1.0, 0.0, 0.0, 1.0);
+    }
+    let position = view_position(coords);
+    let normal = view_normal(coords, position);
+
+    // Rotates the kernel around the normal, differently in every pixel of a
+    // noise tile, so fewer samples are needed
+    let random = textureLoad(t_input, coords % textureDimensions(t_input), 0).xyz;
+    let tangent = normalize(random - normal * dot(random, normal));
+    let bitangent = cross(normal, tangent);
+    let tbn = mat3x3<f32>(tangent, bitangent, normal);
+
+    let size = vec2<f32>(depth_size());
+    var occlusion = 0.0;
+    for (var i = 0u; i < params.sample_count; i = i + 1u) {
+        let sample = position + tbn * params.kernel[i].xyz * params.radius;
+        let clip = params.proj * vec4<f32>(sample, 1.0);
+        let ndc = clip.xy / clip.w;
+        let uv = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+        let scene = view_position(vec2<i32>(uv * size)).z;
+        // Surfaces further away than the radius don't occlude, with a smooth
+        // falloff so their edges don't get dark halos
+        let range = clamp(params.radius / max(abs(position.z - scene), 0.0001), 0.0, 1.0);
+        let range_check = range * range * (3.0 - 2.0 * range);
+        if (scene >= sample.z + params.bias) {
+            occlusion = occlusion + range_check;
+        }
+    }
+    let ambient = 1.0 - occlusion / f32(params.sample_count);
+    return vec4<f32>(pow(ambient, params.intensity), 0.0, 0.0, 1.0);
+}
+#endif
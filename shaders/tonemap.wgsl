@@ -0,0 +1,68 @@
+#include "fullscreen.wgsl"
+
+struct Params {
+    // In stops, the color is multiplied by 2^exposure
+    exposure: f32;
+    // Tonemapper in hdr.rs
+    tonemapper: u32;
+    // Set to copy the color unchanged
+    passthrough: u32;
+};
+
+[[group(0), binding(0)]]
+var t_hdr: texture_2d<f32>;
+[[group(0), binding(1)]]
+var<uniform> params: Params;
+
+fn reinhard(color: vec3<f32>) -> vec3<f32> {
+    return color / (color + vec3<f32>(1.0));
+}
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve
+fn aces(x: vec3<f32>) -> vec3<f32> {
+    let a = 2.51;
+    let b = vec3<f32>(0.03);
+    let c = 2.43;
+    let d = vec3<f32>(0.59);
+    let e = vec3<f32>(0.14);
+    return clamp((x * (a * x + b)) / (x * (c * x + d) + e), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+// John Hable's filmic curve from Uncharted 2
+fn uncharted2_curve(x: vec3<f32>) -> vec3<f32> {
+    let a = 0.15;
+    let b = 0.50;
+    let c = 0.10;
+    let d = 0.20;
+    let e = 0.02;
+    let f = 0.30;
+    let numerator = x * (a * x + vec3<f32>(c * b)) + vec3<f32>(d * e);
+    let denominator = x * (a * x + vec3<f32>(b)) + vec3<f32>(d * f);
+    return numerator / denominator - vec3<f32>(e / f);
+}
+
+fn uncharted2(color: vec3<f32>) -> vec3<f32> {
+    let exposure_bias = 2.0;
+    // Linear white point, mapped to 1
+    let white = vec3<f32>(11.2);
+    return uncharted2_curve(color * exposure_bias) / uncharted2_curve(white);
+}
+
+[[stage(fragment)]]
+fn fs_main(in: FullscreenOutput) -> [[location(0)]] vec4<f32> {
+    let hdr = textureLoad(t_hdr, vec2<i32>(in.clip_position.xy), 0).rgb;
+    if (params.passthrough != 0u) {
+        return vec4<f32>(hdr, 1.0);
+    }
+    let color = hdr * exp2(params.exposure);
+
+    var mapped: vec3<f32>;
+    if (params.tonemapper == 0u) {
+        mapped = reinhard(color);
+    } else if (params.tonemapper == 1u) {
+        mapped = aces(color);
+    } else {
+        mapped = uncharted2(color);
+    }
+    return vec4<f32>(mapped, 1.0);
+}
@@ -0,0 +1,23 @@
+#include "postprocess.wgsl"
+
+struct Params {
+    // How much the corners are darkened
+    intensity: f32;
+    // Distance from the center, in half screen heights, where darkening starts
+    radius: f32;
+    // Distance over which it fades in
+    softness: f32;
+};
+[[group(0), binding(2)]]
+var<uniform> params: Params;
+
+[[stage(fragment)]]
+fn fs_main(in: FullscreenOutput) -> [[location(0)]] vec4<f32> {
+    let color = sample_input(in.tex_coords);
+    let size = vec2<f32>(textureDimensions(t_input));
+    // Scaled by the aspect ratio so the vignette is round
+    let offset = (in.tex_coords - vec2<f32>(0.5)) * 2.0 * vec2<f32>(size.x / size.y, 1.0);
+    let t = clamp((length(offset) - params.radius) / params.softness, 0.0, 1.0);
+    let darkening = params.intensity * t * t * (3.0 - 2.0 * t);
+    return vec4<f32>(color.rgb * (1.0 - darkening), color.a);
+}
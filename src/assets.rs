@@ -0,0 +1,480 @@
+use std::{
+    any::{Any, TypeId},
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::*;
+
+use crate::{
+    mesh::{Mesh, MeshData},
+    texture::{ColorSpace, Texture},
+    watcher::FileWatcher,
+};
+
+/// Something that can be loaded from a file.
+///
+/// Loading is split in two so the slow part (reading and decoding the file) can
+/// run on a background task while the GPU upload stays on the thread that owns
+/// the device.
+pub trait Asset: Send + Sync + Sized + 'static {
+    type Data: Send + 'static;
+    /// How the data is uploaded. Loading the same file with different settings
+    /// gives different assets.
+    type Settings: Clone + Default + Hash + Send + Sync + 'static;
+
+    fn read(path: &Path) -> Result<Self::Data>;
+    fn upload(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: Self::Data,
+        settings: &Self::Settings,
+        label: &str,
+    ) -> Result<Self>;
+}
+
+impl Asset for Texture {
+    type Data = image::DynamicImage;
+    type Settings = ColorSpace;
+
+    fn read(path: &Path) -> Result<Self::Data> {
+        Ok(image::open(path)?)
+    }
+
+    fn upload(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: Self::Data,
+        settings: &Self::Settings,
+        label: &str,
+    ) -> Result<Self> {
+        Texture::from_image(device, queue, &data, *settings, Some(label))
+    }
+}
+
+/// Decoded image kept on the CPU, for textures generated from it
+impl Asset for image::DynamicImage {
+    type Data = image::DynamicImage;
+    type Settings = ();
+
+    fn read(path: &Path) -> Result<Self::Data> {
+        Ok(image::open(path)?)
+    }
+
+    fn upload(
+        _device: &wgpu::Device,
+        _queue: &wgpu::Queue,
+        data: Self::Data,
+        _settings: &Self::Settings,
+        _label: &str,
+    ) -> Result<Self> {
+        Ok(data)
+    }
+}
+
+impl Asset for Mesh {
+    type Data = MeshData;
+    type Settings = ();
+
+    fn read(path: &Path) -> Result<Self::Data> {
+        MeshData::load_obj(path)
+    }
+
+    fn upload(
+        device: &wgpu::Device,
+        _queue: &wgpu::Queue,
+        data: Self::Data,
+        _settings: &Self::Settings,
+        label: &str,
+    ) -> Result<Self> {
+        Ok(Mesh::new(device, &data, Some(label)))
+    }
+}
+
+/// WGSL source code
+pub struct ShaderSource {
+    pub code: String,
+}
+
+impl Asset for ShaderSource {
+    type Data = String;
+    type Settings = ();
+
+    fn read(path: &Path) -> Result<Self::Data> {
+        Ok(std::fs::read_to_string(path)?)
+    }
+
+    fn upload(
+        _device: &wgpu::Device,
+        _queue: &wgpu::Queue,
+        data: Self::Data,
+        _settings: &Self::Settings,
+        _label: &str,
+    ) -> Result<Self> {
+        Ok(Self { code: data })
+    }
+}
+
+struct SlotState<T> {
+    asset: Option<Arc<T>>,
+    error: Option<Arc<Error>>,
+    version: u64,
+}
+
+struct Slot<T: Asset> {
+    path: PathBuf,
+    settings: T::Settings,
+    state: RwLock<SlotState<T>>,
+}
+
+/// Cheap to clone reference to an asset owned by an [`AssetManager`]
+pub struct Handle<T: Asset> {
+    slot: Arc<Slot<T>>,
+}
+
+impl<T: Asset> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+impl<T: Asset> Handle<T> {
+    fn new(path: PathBuf, settings: T::Settings) -> Self {
+        Self {
+            slot: Arc::new(Slot {
+                path,
+                settings,
+                state: RwLock::new(SlotState {
+                    asset: None,
+                    error: None,
+                    version: 0,
+                }),
+            }),
+        }
+    }
+
+    /// Handle to an asset that wasn't loaded from a file, like a generated
+    /// texture. It's never reloaded, `name` is only used as its path.
+    pub fn from_asset(name: impl Into<PathBuf>, asset: T) -> Self {
+        let handle = Self::new(name.into(), T::Settings::default());
+        handle.set(Ok(asset));
+        handle
+    }
+
+    /// Swaps the asset of a handle made with [`Handle::from_asset`] for a
+    /// regenerated one, bumping its version
+    pub fn replace(&self, asset: T) {
+        self.set(Ok(asset));
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.slot.path
+    }
+
+    /// Returns the last successfully loaded version of the asset, `None` while
+    /// it's still loading or if it never loaded
+    pub fn get(&self) -> Option<Arc<T>> {
+        self.slot.state.read().unwrap().asset.clone()
+    }
+
+    /// Error of the last load attempt, cleared once the asset loads successfully
+    pub fn error(&self) -> Option<Arc<Error>> {
+        self.slot.state.read().unwrap().error.clone()
+    }
+
+    pub fn is_loading(&self) -> bool {
+        let state = self.slot.state.read().unwrap();
+        state.asset.is_none() && state.error.is_none()
+    }
+
+    /// Incremented every time the asset is (re)loaded, anything created from
+    /// the asset (like a bind group) should be recreated when this changes
+    pub fn version(&self) -> u64 {
+        self.slot.state.read().unwrap().version
+    }
+
+    fn set(&self, result: Result<T>) {
+        let mut state = self.slot.state.write().unwrap();
+        match result {
+            Result::Ok(asset) => {
+                state.asset = Some(Arc::new(asset));
+                state.error = None;
+                state.version += 1;
+            }
+            Err(error) => {
+                if state.asset.is_some() {
+                    log::error!(
+                        "Failed to reload {}, keeping the last good version: {:?}",
+                        self.path().display(),
+                        error
+                    );
+                } else {
+                    log::error!("Failed to load {}: {:?}", self.path().display(), error);
+                }
+                state.error = Some(Arc::new(error));
+            }
+        }
+    }
+}
+
+/// Type erased [`Handle`] so handles of every asset type can be stored together
+trait AnyHandle {
+    fn as_any(&self) -> &dyn Any;
+    fn path(&self) -> &Path;
+    fn reload(&self, device: &wgpu::Device, queue: &wgpu::Queue);
+}
+
+impl<T: Asset> AnyHandle for Handle<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn path(&self) -> &Path {
+        Handle::path(self)
+    }
+
+    fn reload(&self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        log::info!("Reloading {}", self.path().display());
+        let result = T::read(self.path()).and_then(|data| upload(device, queue, self, data));
+        self.set(result);
+    }
+}
+
+type Upload = Box<dyn FnOnce(&wgpu::Device, &wgpu::Queue) + Send>;
+
+/// Loads assets from disk, handing out shared handles so loading the same file
+/// twice only reads it once.
+///
+/// Loaded files are watched and reloaded in [`AssetManager::update`] when they
+/// change on disk. A failed reload keeps the previous version of the asset.
+pub struct AssetManager {
+    root: PathBuf,
+    /// Keyed by the asset type, path and hash of the settings
+    handles: HashMap<(TypeId, PathBuf, u64), Box<dyn AnyHandle>>,
+    watcher: FileWatcher,
+
+    upload_sender: mpsc::Sender<Upload>,
+    upload_receiver: mpsc::Receiver<Upload>,
+}
+
+impl AssetManager {
+    /// Relative paths are resolved against `root`
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let (upload_sender, upload_receiver) = mpsc::channel();
+        Self {
+            root: root.into(),
+            handles: HashMap::new(),
+            watcher: FileWatcher::new(Duration::from_millis(500)),
+            upload_sender,
+            upload_receiver,
+        }
+    }
+
+    /// Loads an asset immediately, blocking until it's read and uploaded
+    pub fn load<T: Asset>(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: impl AsRef<Path>,
+    ) -> Handle<T> {
+        self.load_with(device, queue, path, T::Settings::default())
+    }
+
+    /// Like [`AssetManager::load`], with settings other than the defaults
+    pub fn load_with<T: Asset>(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: impl AsRef<Path>,
+        settings: T::Settings,
+    ) -> Handle<T> {
+        let (handle, is_new) = self.handle::<T>(path.as_ref(), settings);
+        if is_new {
+            let result =
+                T::read(handle.path()).and_then(|data| upload(device, queue, &handle, data));
+            handle.set(result);
+        }
+        handle
+    }
+
+    /// Starts reading an asset on a background task, the returned handle stays
+    /// empty until [`AssetManager::update`] uploads it
+    pub fn load_async<T: Asset>(&mut self, path: impl AsRef<Path>) -> Handle<T> {
+        self.load_async_with(path, T::Settings::default())
+    }
+
+    /// Like [`AssetManager::load_async`], with settings other than the defaults
+    pub fn load_async_with<T: Asset>(
+        &mut self,
+        path: impl AsRef<Path>,
+        settings: T::Settings,
+    ) -> Handle<T> {
+        let (handle, is_new) = self.handle::<T>(path.as_ref(), settings);
+        if is_new {
+            let sender = self.upload_sender.clone();
+            let task_handle = handle.clone();
+            // Reading is blocking IO and decoding, so it runs on the blocking
+            // thread pool instead of an executor thread. This is what
+            // async_std::task::spawn_blocking does, which is unstable.
+            async_std::task::spawn(blocking::unblock(move || {
+                let handle = task_handle;
+                let data = T::read(handle.path());
+                let upload: Upload = Box::new(move |device, queue| {
+                    let result = data.and_then(|data| upload(device, queue, &handle, data));
+                    handle.set(result);
+                });
+                // Only fails if the manager was dropped, in which case nobody cares
+                let _ = sender.send(upload);
+            }));
+        }
+        handle
+    }
+
+    /// Finishes background loads and reloads changed files, should be called
+    /// once per frame
+    pub fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        for upload in self.upload_receiver.try_iter() {
+            upload(device, queue);
+        }
+
+        for path in self.watcher.poll() {
+            self.handles
+                .values()
+                .filter(|handle| handle.path() == path)
+                .for_each(|handle| handle.reload(device, queue));
+        }
+    }
+
+    /// Paths of every asset that failed to load, with the reason
+    pub fn errors<T: Asset>(&self) -> Vec<(PathBuf, Arc<Error>)> {
+        self.handles
+            .iter()
+            .filter(|((type_id, _, _), _)| *type_id == TypeId::of::<T>())
+            .filter_map(|(_, handle)| {
+                let handle = handle.as_any().downcast_ref::<Handle<T>>()?;
+                Some((handle.path().to_owned(), handle.error()?))
+            })
+            .collect()
+    }
+
+    /// Returns the existing handle for the path and settings, or a new one if
+    /// it's the first time they're requested
+    fn handle<T: Asset>(&mut self, path: &Path, settings: T::Settings) -> (Handle<T>, bool) {
+        let path = self.root.join(path);
+        // So different spellings of the same file share a handle
+        let path = std::fs::canonicalize(&path).unwrap_or(path);
+        let mut hasher = DefaultHasher::new();
+        settings.hash(&mut hasher);
+        let key = (TypeId::of::<T>(), path.clone(), hasher.finish());
+        if let Some(handle) = self.handles.get(&key) {
+            return (
+                handle.as_any().downcast_ref::<Handle<T>>().unwrap().clone(),
+                false,
+            );
+        }
+
+        self.watcher.watch(path.clone());
+        let handle = Handle::<T>::new(path, settings);
+        self.handles.insert(key, Box::new(handle.clone()));
+        (handle, true)
+    }
+}
+
+/// Uploads data read for a handle with its settings
+fn upload<T: Asset>(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    handle: &Handle<T>,
+    data: T::Data,
+) -> Result<T> {
+    let label = handle.path().display().to_string();
+    T::upload(device, queue, data, &handle.slot.settings, &label)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager() -> AssetManager {
+        AssetManager::new(env!("CARGO_MANIFEST_DIR"))
+    }
+
+    /// What loading does minus the upload, which needs a device
+    fn read(handle: &Handle<ShaderSource>) {
+        handle.set(ShaderSource::read(handle.path()).map(|code| ShaderSource { code }));
+    }
+
+    #[test]
+    fn repeated_loads_share_a_handle() {
+        let mut assets = manager();
+        let (first, is_new) = assets.handle::<ShaderSource>(Path::new("shaders/solid.wgsl"), ());
+        assert!(is_new);
+        for spelling in [
+            "shaders/solid.wgsl",
+            "./shaders/solid.wgsl",
+            "shaders/../shaders/solid.wgsl",
+        ] {
+            let (handle, is_new) = assets.handle::<ShaderSource>(Path::new(spelling), ());
+            assert!(!is_new, "{} was loaded again", spelling);
+            assert!(Arc::ptr_eq(&first.slot, &handle.slot));
+        }
+    }
+
+    #[test]
+    fn settings_and_types_get_their_own_handles() {
+        let mut assets = manager();
+        let (srgb, _) = assets.handle::<Texture>(Path::new("tree.png"), ColorSpace::Srgb);
+        let (linear, is_new) = assets.handle::<Texture>(Path::new("tree.png"), ColorSpace::Linear);
+        assert!(is_new);
+        assert!(!Arc::ptr_eq(&srgb.slot, &linear.slot));
+        let (_, is_new) = assets.handle::<ShaderSource>(Path::new("tree.png"), ());
+        assert!(is_new);
+    }
+
+    #[test]
+    fn cloned_handles_see_the_same_asset() {
+        let mut assets = manager();
+        let (handle, _) = assets.handle::<ShaderSource>(Path::new("shaders/solid.wgsl"), ());
+        let clone = handle.clone();
+        assert!(clone.is_loading());
+
+        handle.set(Ok(ShaderSource {
+            code: "// Reloaded".to_owned(),
+        }));
+        assert_eq!(clone.get().unwrap().code, "// Reloaded");
+        assert_eq!(clone.version(), 1);
+    }
+
+    #[test]
+    fn reports_errors_per_asset() {
+        let mut assets = manager();
+        let (missing, _) = assets.handle::<ShaderSource>(Path::new("shaders/missing.wgsl"), ());
+        read(&missing);
+        let (loaded, _) = assets.handle::<ShaderSource>(Path::new("shaders/solid.wgsl"), ());
+        read(&loaded);
+
+        let errors = assets.errors::<ShaderSource>();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, missing.path());
+        assert!(!missing.is_loading());
+        assert!(missing.get().is_none());
+        assert!(assets.errors::<Texture>().is_empty());
+    }
+
+    #[test]
+    fn failed_reloads_keep_the_last_good_version() {
+        let mut assets = manager();
+        let (handle, _) = assets.handle::<ShaderSource>(Path::new("shaders/solid.wgsl"), ());
+        read(&handle);
+        handle.set(Err(anyhow!("Syntax error")));
+
+        assert!(handle.get().is_some());
+        assert!(handle.error().is_some());
+        assert_eq!(handle.version(), 1);
+    }
+}
@@ -0,0 +1,381 @@
+use anyhow::*;
+use image::GenericImageView;
+
+use crate::texture::Texture;
+
+/// A rectangle inside the atlas, in pixels.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PackedRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A rectangle inside the atlas, in normalized texture coordinates.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct UvRect {
+    pub min: glam::Vec2,
+    pub max: glam::Vec2,
+}
+
+impl UvRect {
+    /// How much of the atlas the image covers, texture coordinates of the
+    /// original image map to `min + uv * size()`
+    pub fn size(&self) -> glam::Vec2 {
+        self.max - self.min
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct SkylineNode {
+    x: u32,
+    y: u32,
+    width: u32,
+}
+
+/// Skyline bottom-left rectangle packer.
+///
+/// Keeps track of the top edge ("skyline") of everything placed so far and puts
+/// each new rectangle at the position where its top ends up the lowest.
+pub struct AtlasPacker {
+    width: u32,
+    height: u32,
+    padding: u32,
+    skyline: Vec<SkylineNode>,
+}
+
+impl AtlasPacker {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            padding: 0,
+            skyline: vec![SkylineNode { x: 0, y: 0, width }],
+        }
+    }
+
+    /// Empty space left around every packed rectangle so linear filtering
+    /// doesn't bleed neighbouring images into each other
+    pub fn with_padding(mut self, padding: u32) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Returns `None` if the rectangle doesn't fit in the remaining space
+    pub fn pack(&mut self, width: u32, height: u32) -> Option<PackedRect> {
+        let padded_width = width + self.padding * 2;
+        let padded_height = height + self.padding * 2;
+
+        let mut best: Option<(usize, u32, u32)> = None;
+        for i in 0..self.skyline.len() {
+            if let Some(y) = self.fits(i, padded_width, padded_height) {
+                let node = self.skyline[i];
+                let is_better = match best {
+                    Some((_, best_y, best_width)) => {
+                        y < best_y || (y == best_y && node.width < best_width)
+                    }
+                    None => true,
+                };
+                if is_better {
+                    best = Some((i, y, node.width));
+                }
+            }
+        }
+
+        let (index, y, _) = best?;
+        let x = self.skyline[index].x;
+        self.add_level(index, x, y + padded_height, padded_width);
+
+        Some(PackedRect {
+            x: x + self.padding,
+            y: y + self.padding,
+            width,
+            height,
+        })
+    }
+
+    /// Height at which a rectangle would rest if its left edge was placed on
+    /// skyline node `index`
+    fn fits(&self, index: usize, width: u32, height: u32) -> Option<u32> {
+        let x = self.skyline[index].x;
+        if x + width > self.width {
+            return None;
+        }
+
+        let mut y = 0;
+        let mut width_left = width as i64;
+        let mut i = index;
+        while width_left > 0 {
+            let node = self.skyline.get(i)?;
+            y = y.max(node.y);
+            if y + height > self.height {
+                return None;
+            }
+            width_left -= node.width as i64;
+            i += 1;
+        }
+        Some(y)
+    }
+
+    fn add_level(&mut self, index: usize, x: u32, y: u32, width: u32) {
+        self.skyline.insert(index, SkylineNode { x, y, width });
+
+        // Shrink or remove the nodes now covered by the new one
+        let i = index + 1;
+        while i < self.skyline.len() {
+            let previous_end = self.skyline[i - 1].x + self.skyline[i - 1].width;
+            let node = &mut self.skyline[i];
+            if node.x >= previous_end {
+                break;
+            }
+            let shrink = previous_end - node.x;
+            if node.width <= shrink {
+                self.skyline.remove(i);
+            } else {
+                node.x += shrink;
+                node.width -= shrink;
+                break;
+            }
+        }
+
+        // Merge neighbours at the same height
+        let mut i = 0;
+        while i + 1 < self.skyline.len() {
+            if self.skyline[i].y == self.skyline[i + 1].y {
+                self.skyline[i].width += self.skyline[i + 1].width;
+                self.skyline.remove(i + 1);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Many differently sized images packed into a single texture.
+pub struct Atlas {
+    pub texture: Texture,
+    /// One entry per input image, in the same order
+    pub rects: Vec<UvRect>,
+}
+
+impl Atlas {
+    const PADDING: u32 = 1;
+
+    pub fn from_images(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        images: &[image::DynamicImage],
+        label: Option<&str>,
+    ) -> Result<Self> {
+        let sizes = images
+            .iter()
+            .map(|img| img.dimensions())
+            .collect::<Vec<_>>();
+        let max_size = device.limits().max_texture_dimension_2d;
+        let (packer, rects) = Self::pack(&sizes, max_size)
+            .ok_or_else(|| anyhow!("Images don't fit in a {0}x{0} atlas", max_size))?;
+        let (width, height) = packer.size();
+
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label,
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
+
+        for (img, rect) in images.iter().zip(&rects) {
+            let rgba = img.to_rgba8();
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: rect.x,
+                        y: rect.y,
+                        z: 0,
+                    },
+                },
+                &rgba,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(4 * rect.width),
+                    rows_per_image: std::num::NonZeroU32::new(rect.height),
+                },
+                wgpu::Extent3d {
+                    width: rect.width,
+                    height: rect.height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        let atlas_size = glam::vec2(width as f32, height as f32);
+        let rects = rects
+            .iter()
+            .map(|rect| UvRect {
+                min: glam::vec2(rect.x as f32, rect.y as f32) / atlas_size,
+                max: glam::vec2((rect.x + rect.width) as f32, (rect.y + rect.height) as f32)
+                    / atlas_size,
+            })
+            .collect();
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = Texture::create_sampler(device);
+
+        Ok(Self {
+            texture: Texture {
+                texture,
+                view,
+                sampler,
+            },
+            rects,
+        })
+    }
+
+    /// Packs the sizes into the smallest power of two square (or 2:1 rectangle)
+    /// that fits them, returning rects in input order
+    fn pack(sizes: &[(u32, u32)], max_size: u32) -> Option<(AtlasPacker, Vec<PackedRect>)> {
+        // Placing tall images first gives a much flatter skyline
+        let mut order = (0..sizes.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| std::cmp::Reverse((sizes[i].1, sizes[i].0)));
+
+        let area = sizes
+            .iter()
+            .map(|(w, h)| (w + Self::PADDING * 2) as u64 * (h + Self::PADDING * 2) as u64)
+            .sum::<u64>();
+        let mut width = (area as f64).sqrt().ceil().max(1.0) as u32;
+        width = width.next_power_of_two();
+        let mut height = width;
+
+        while width <= max_size && height <= max_size {
+            let mut packer = AtlasPacker::new(width, height).with_padding(Self::PADDING);
+            let mut rects = vec![
+                PackedRect {
+                    x: 0,
+                    y: 0,
+                    width: 0,
+                    height: 0
+                };
+                sizes.len()
+            ];
+            let packed_all = order
+                .iter()
+                .all(|&i| match packer.pack(sizes[i].0, sizes[i].1) {
+                    Some(rect) => {
+                        rects[i] = rect;
+                        true
+                    }
+                    None => false,
+                });
+            if packed_all {
+                return Some((packer, rects));
+            }
+
+            if width == height {
+                width *= 2;
+            } else {
+                height *= 2;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlaps(a: &PackedRect, b: &PackedRect) -> bool {
+        a.x < b.x + b.width && b.x < a.x + a.width && a.y < b.y + b.height && b.y < a.y + a.height
+    }
+
+    #[test]
+    fn packs_differently_sized_rects_without_overlap() {
+        let sizes = [(64, 32), (16, 16), (40, 60), (8, 100), (32, 32), (50, 10)];
+        let mut packer = AtlasPacker::new(128, 128).with_padding(1);
+        let rects = sizes
+            .iter()
+            .map(|&(width, height)| packer.pack(width, height).unwrap())
+            .collect::<Vec<_>>();
+
+        for (rect, &(width, height)) in rects.iter().zip(&sizes) {
+            assert_eq!((rect.width, rect.height), (width, height));
+            assert!(rect.x >= 1 && rect.x + rect.width < 128);
+            assert!(rect.y >= 1 && rect.y + rect.height < 128);
+        }
+        for (i, a) in rects.iter().enumerate() {
+            for b in &rects[i + 1..] {
+                // Including the padding on both sides
+                let padded = PackedRect {
+                    x: a.x - 1,
+                    y: a.y - 1,
+                    width: a.width + 2,
+                    height: a.height + 2,
+                };
+                assert!(!overlaps(&padded, b), "{:?} overlaps {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn fills_gaps_left_by_taller_rects() {
+        let mut packer = AtlasPacker::new(64, 64);
+        assert_eq!(
+            packer.pack(32, 64),
+            Some(PackedRect {
+                x: 0,
+                y: 0,
+                width: 32,
+                height: 64
+            })
+        );
+        for y in [0, 32] {
+            assert_eq!(
+                packer.pack(32, 32),
+                Some(PackedRect {
+                    x: 32,
+                    y,
+                    width: 32,
+                    height: 32
+                })
+            );
+        }
+        assert_eq!(packer.pack(1, 1), None);
+    }
+
+    #[test]
+    fn rejects_rects_larger_than_the_atlas() {
+        let mut packer = AtlasPacker::new(64, 64).with_padding(1);
+        assert_eq!(packer.pack(64, 8), None);
+        assert!(packer.pack(62, 8).is_some());
+    }
+
+    #[test]
+    fn grows_until_everything_fits() {
+        let sizes = [(100, 20), (20, 100), (60, 60), (1, 1)];
+        let (packer, rects) = Atlas::pack(&sizes, 4096).unwrap();
+        let (width, height) = packer.size();
+        assert!(width.is_power_of_two() && height.is_power_of_two());
+        assert!(width <= 256 && height <= 256);
+        // In input order, even though taller rects are placed first
+        for (rect, &(width, height)) in rects.iter().zip(&sizes) {
+            assert_eq!((rect.width, rect.height), (width, height));
+        }
+
+        assert!(Atlas::pack(&[(100, 100)], 64).is_none());
+    }
+}
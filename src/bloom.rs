@@ -0,0 +1,318 @@
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    assets::AssetManager,
+    fullscreen::FullscreenPass,
+    hdr::{Hdr, HDR_FORMAT},
+    pipeline::PipelineCache,
+    preprocessor::Preprocessor,
+    shader::{capture_validation_errors, Shader},
+};
+
+/// Mips of the bloom texture, the first one is half the size of the surface
+const MAX_MIPS: u32 = 6;
+
+/// Params in bloom.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct BloomParams {
+    threshold: f32,
+    knee: f32,
+    radius: f32,
+    scale: f32,
+}
+
+/// Makes bright parts of the HDR scene glow.
+///
+/// The parts brighter than the threshold are downsampled into a mip chain,
+/// then every mip is blurred into the next larger one with a tent filter and
+/// the result is added to the scene.
+pub struct Bloom {
+    pub enabled: bool,
+    /// Brightness, before exposure, above which colors bloom
+    pub threshold: f32,
+    pub intensity: f32,
+    /// Radius of the upsampling filter in texture coordinates, larger values
+    /// spread the glow further
+    pub radius: f32,
+
+    mip_views: Vec<wgpu::TextureView>,
+    sampler: wgpu::Sampler,
+    params_buffer: wgpu::Buffer,
+    composite_params_buffer: wgpu::Buffer,
+
+    passes: Passes,
+    bind_groups: BindGroups,
+}
+
+struct Passes {
+    prefilter: FullscreenPass,
+    downsample: FullscreenPass,
+    /// Also used to composite, as it draws to the same format with the same
+    /// blending
+    upsample: FullscreenPass,
+}
+
+struct BindGroups {
+    prefilter: wgpu::BindGroup,
+    /// Reading from every mip but the last one
+    downsample: Vec<wgpu::BindGroup>,
+    /// Reading from every mip but the first one
+    upsample: Vec<wgpu::BindGroup>,
+    composite: wgpu::BindGroup,
+}
+
+impl Bloom {
+    pub fn new(
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        config: &wgpu::SurfaceConfiguration,
+        hdr: &Hdr,
+    ) -> Result<Self> {
+        let mut pass = |preprocessor, blend| {
+            FullscreenPass::new(
+                assets,
+                device,
+                queue,
+                pipelines,
+                Shader::new(
+                    "shaders/bloom.wgsl",
+                    include_str!("../shaders/bloom.wgsl"),
+                    preprocessor,
+                ),
+                Some(HDR_FORMAT),
+                blend,
+            )
+        };
+        let additive = wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::Zero,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+        };
+        let passes = Passes {
+            prefilter: pass(Preprocessor::new().define("PREFILTER", ""), None)?,
+            downsample: pass(Preprocessor::new(), None)?,
+            upsample: pass(Preprocessor::new().define("UPSAMPLE", ""), Some(additive))?,
+        };
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Bloom sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let create_params_buffer = |label| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents: bytemuck::bytes_of::<BloomParams>(&bytemuck::Zeroable::zeroed()),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            })
+        };
+        let params_buffer = create_params_buffer("Bloom params buffer");
+        let composite_params_buffer = create_params_buffer("Bloom composite params buffer");
+
+        let mip_views = create_mip_views(device, config);
+        let bind_groups = BindGroups::new(
+            device,
+            &passes,
+            &sampler,
+            &mip_views,
+            [&params_buffer, &composite_params_buffer],
+            hdr,
+        )?;
+        Ok(Self {
+            enabled: true,
+            threshold: 1.0,
+            intensity: 0.3,
+            radius: 0.005,
+            mip_views,
+            sampler,
+            params_buffer,
+            composite_params_buffer,
+            passes,
+            bind_groups,
+        })
+    }
+
+    /// Has to be called after [`Hdr::resize`]
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        hdr: &Hdr,
+    ) {
+        self.mip_views = create_mip_views(device, config);
+        let bind_groups = BindGroups::new(
+            device,
+            &self.passes,
+            &self.sampler,
+            &self.mip_views,
+            [&self.params_buffer, &self.composite_params_buffer],
+            hdr,
+        );
+        match bind_groups {
+            Result::Ok(bind_groups) => self.bind_groups = bind_groups,
+            Err(e) => log::error!("{:?}", e),
+        }
+    }
+
+    /// Uploads the settings and rebuilds the pipelines of edited shaders
+    pub fn update(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+    ) {
+        let passes = &mut self.passes;
+        for pass in [
+            &mut passes.prefilter,
+            &mut passes.downsample,
+            &mut passes.upsample,
+        ] {
+            pass.update(assets, device, queue, pipelines);
+        }
+
+        let params = BloomParams {
+            threshold: self.threshold,
+            knee: self.threshold * 0.5,
+            radius: self.radius,
+            scale: 1.0,
+        };
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::bytes_of(&params));
+        let params = BloomParams {
+            scale: self.intensity,
+            ..params
+        };
+        queue.write_buffer(
+            &self.composite_params_buffer,
+            0,
+            bytemuck::bytes_of(&params),
+        );
+    }
+
+    /// Adds the bloom of the scene in `hdr` to it
+    pub fn render(&self, pipelines: &PipelineCache, encoder: &mut wgpu::CommandEncoder, hdr: &Hdr) {
+        if !self.enabled {
+            return;
+        }
+
+        let (passes, bind_groups) = (&self.passes, &self.bind_groups);
+        passes.prefilter.draw(
+            pipelines,
+            encoder,
+            &self.mip_views[0],
+            &bind_groups.prefilter,
+        );
+        for (i, bind_group) in bind_groups.downsample.iter().enumerate() {
+            passes
+                .downsample
+                .draw(pipelines, encoder, &self.mip_views[i + 1], bind_group);
+        }
+        for (i, bind_group) in bind_groups.upsample.iter().enumerate().rev() {
+            passes
+                .upsample
+                .draw(pipelines, encoder, &self.mip_views[i], bind_group);
+        }
+        passes
+            .upsample
+            .draw(pipelines, encoder, hdr.view(), &bind_groups.composite);
+    }
+}
+
+impl BindGroups {
+    /// `params_buffers` are the buffers of the passes and of compositing
+    fn new(
+        device: &wgpu::Device,
+        passes: &Passes,
+        sampler: &wgpu::Sampler,
+        mip_views: &[wgpu::TextureView],
+        params_buffers: [&wgpu::Buffer; 2],
+        hdr: &Hdr,
+    ) -> Result<Self> {
+        let [params_buffer, composite_params_buffer] = params_buffers;
+        let bind_group = |pass: &FullscreenPass, view, params_buffer: &wgpu::Buffer| {
+            capture_validation_errors(device, || {
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("Bloom bind group"),
+                    layout: pass.layout(),
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: wgpu::BindingResource::TextureView(view),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: wgpu::BindingResource::Sampler(sampler),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: params_buffer.as_entire_binding(),
+                        },
+                    ],
+                })
+            })
+        };
+
+        let mips = mip_views.len();
+        Ok(Self {
+            prefilter: bind_group(&passes.prefilter, hdr.view(), params_buffer)?,
+            downsample: mip_views[..mips - 1]
+                .iter()
+                .map(|view| bind_group(&passes.downsample, view, params_buffer))
+                .collect::<Result<_>>()?,
+            upsample: mip_views[1..]
+                .iter()
+                .map(|view| bind_group(&passes.upsample, view, params_buffer))
+                .collect::<Result<_>>()?,
+            composite: bind_group(&passes.upsample, &mip_views[0], composite_params_buffer)?,
+        })
+    }
+}
+
+/// Views of every mip of a new bloom texture
+fn create_mip_views(
+    device: &wgpu::Device,
+    config: &wgpu::SurfaceConfiguration,
+) -> Vec<wgpu::TextureView> {
+    let width = (config.width / 2).max(1);
+    let height = (config.height / 2).max(1);
+    // Down to a few pixels on the smaller side
+    let mips = (u32::BITS - width.min(height).leading_zeros())
+        .saturating_sub(2)
+        .clamp(1, MAX_MIPS);
+
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Bloom texture"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: mips,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: HDR_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+    });
+    (0..mips)
+        .map(|mip| {
+            texture.create_view(&wgpu::TextureViewDescriptor {
+                label: Some("Bloom mip view"),
+                base_mip_level: mip,
+                mip_level_count: std::num::NonZeroU32::new(1),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
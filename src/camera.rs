@@ -2,6 +2,15 @@ use winit::event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent};
 
 use crate::deg_to_rad;
 
+/// The camera uniform in camera.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct CameraUniform {
+    view_proj: [[f32; 4]; 4],
+    /// The eye, with w unused
+    position: [f32; 4],
+}
+
 pub struct Camera {
     pub eye: glam::Vec3,
     pub up: glam::Vec3,
@@ -14,11 +23,22 @@ pub struct Camera {
 
 impl Camera {
     pub fn build_vp_matrix(&self) -> glam::Mat4 {
-        let view = glam::Mat4::look_at_rh(self.eye, self.eye + self.front, self.up);
-        let proj =
-            glam::Mat4::perspective_rh(deg_to_rad(self.fovy), self.aspect, self.znear, self.zfar);
+        self.build_proj_matrix() * self.build_view_matrix()
+    }
+
+    pub fn build_view_matrix(&self) -> glam::Mat4 {
+        glam::Mat4::look_at_rh(self.eye, self.eye + self.front, self.up)
+    }
 
-        proj * view
+    pub fn build_proj_matrix(&self) -> glam::Mat4 {
+        glam::Mat4::perspective_rh(deg_to_rad(self.fovy), self.aspect, self.znear, self.zfar)
+    }
+
+    pub fn to_uniform(&self) -> CameraUniform {
+        CameraUniform {
+            view_proj: self.build_vp_matrix().to_cols_array_2d(),
+            position: self.eye.extend(1.0).to_array(),
+        }
     }
 }
 
@@ -104,12 +124,7 @@ impl CameraController {
         self.yaw += position_delta.x * self.sensitivity;
         self.pitch -= position_delta.y * self.sensitivity;
 
-        if self.pitch > 89.0 {
-            self.pitch = 89.0;
-        }
-        if self.pitch < -89.0 {
-            self.pitch = -89.0;
-        }
+        self.pitch = self.pitch.clamp(-89.0, 89.0);
     }
 
     pub fn update_camera(&self, camera: &mut Camera, delta_time: f32) {
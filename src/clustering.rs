@@ -0,0 +1,258 @@
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    assets::AssetManager,
+    camera::Camera,
+    deferred::LightingInputs,
+    preprocessor::Preprocessor,
+    shader::{capture_validation_errors, Shader},
+};
+
+/// Clusters along x, y and z
+const GRID: [u32; 3] = [16, 9, 24];
+const CLUSTER_COUNT: u32 = GRID[0] * GRID[1] * GRID[2];
+/// Lights past this touching a cluster are ignored
+const MAX_LIGHTS_PER_CLUSTER: u32 = 64;
+/// Must match the workgroup size in cluster_lights.wgsl
+const WORKGROUP_SIZE: u32 = 64;
+
+/// ClusterParams in clusters.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ClusterParams {
+    view: [[f32; 4]; 4],
+    inv_proj: [[f32; 4]; 4],
+    grid: [u32; 4],
+    screen_size: [f32; 2],
+    znear: f32,
+    zfar: f32,
+}
+
+/// Clustered light culling, so forward shading only loops over the lights
+/// that can reach a fragment.
+///
+/// The view frustum is split into a grid of froxels, with depth slices spaced
+/// exponentially. Every frame a compute shader lists the point lights touching
+/// every cluster, which the shaders read with clusters.wgsl.
+pub struct LightClusters {
+    shader: Shader,
+    pipeline_layout: wgpu::PipelineLayout,
+    pipeline: wgpu::ComputePipeline,
+    compute_bind_group: wgpu::BindGroup,
+    /// Bound at [`crate::material::LIGHTS_GROUP`]
+    bind_group: wgpu::BindGroup,
+    params_buffer: wgpu::Buffer,
+    cluster_buffer: wgpu::Buffer,
+    light_index_buffer: wgpu::Buffer,
+}
+
+impl LightClusters {
+    /// `layout` is the layout of the bind group returned by
+    /// [`LightClusters::bind_group`], which also binds the environment, scene
+    /// and occlusion of `inputs`. Its depth isn't used.
+    pub fn new(
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        inputs: &LightingInputs,
+    ) -> Result<Self> {
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cluster params buffer"),
+            contents: bytemuck::bytes_of::<ClusterParams>(&bytemuck::Zeroable::zeroed()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        // Offset and count of every cluster
+        let cluster_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cluster buffer"),
+            size: (CLUSTER_COUNT as usize * std::mem::size_of::<[u32; 2]>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let light_index_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cluster light index buffer"),
+            size: (CLUSTER_COUNT as usize
+                * MAX_LIGHTS_PER_CLUSTER as usize
+                * std::mem::size_of::<u32>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let entries = buffer_entries(
+            inputs,
+            [&params_buffer, &cluster_buffer, &light_index_buffer],
+        );
+
+        let mut shader = Shader::new(
+            "shaders/cluster_lights.wgsl",
+            include_str!("../shaders/cluster_lights.wgsl"),
+            Preprocessor::new(),
+        );
+        let reflection = shader.reflect(assets, device, queue)?;
+        let compute_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Light clustering bind group layout"),
+            entries: &reflection.bind_groups[0],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Light clustering pipeline layout"),
+            bind_group_layouts: &[&compute_layout],
+            push_constant_ranges: &[],
+        });
+        let module = shader.create_module(assets, device, queue)?;
+        let pipeline = create_compute_pipeline(device, &pipeline_layout, &module);
+
+        let compute_bind_group = capture_validation_errors(device, || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Light clustering bind group"),
+                layout: &compute_layout,
+                entries: &entries,
+            })
+        })?;
+        let bind_group = create_bind_group(
+            device,
+            layout,
+            inputs,
+            [&params_buffer, &cluster_buffer, &light_index_buffer],
+        )?;
+        Ok(Self {
+            shader,
+            pipeline_layout,
+            pipeline,
+            compute_bind_group,
+            bind_group,
+            params_buffer,
+            cluster_buffer,
+            light_index_buffer,
+        })
+    }
+
+    /// The lights, their clusters, the environment, the scene settings and the
+    /// ambient occlusion, for shaders including lights.wgsl, clusters.wgsl and
+    /// fog.wgsl
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    /// Has to be called after the occlusion is resized
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        inputs: &LightingInputs,
+    ) {
+        let buffers = [
+            &self.params_buffer,
+            &self.cluster_buffer,
+            &self.light_index_buffer,
+        ];
+        match create_bind_group(device, layout, inputs, buffers) {
+            Result::Ok(bind_group) => self.bind_group = bind_group,
+            Err(e) => log::error!("{:?}", e),
+        }
+    }
+
+    /// Uploads the camera for the next dispatch and rebuilds the pipeline if
+    /// the shader was edited
+    pub fn update(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera: &Camera,
+        config: &wgpu::SurfaceConfiguration,
+    ) {
+        if self.shader.changed() {
+            let result = self
+                .shader
+                .create_module(assets, device, queue)
+                .and_then(|module| {
+                    capture_validation_errors(device, || {
+                        create_compute_pipeline(device, &self.pipeline_layout, &module)
+                    })
+                });
+            match result {
+                Result::Ok(pipeline) => self.pipeline = pipeline,
+                Err(e) => log::error!("{:?}", e),
+            }
+        }
+
+        let [x, y, z] = GRID;
+        let params = ClusterParams {
+            view: camera.build_view_matrix().to_cols_array_2d(),
+            inv_proj: camera.build_proj_matrix().inverse().to_cols_array_2d(),
+            grid: [x, y, z, MAX_LIGHTS_PER_CLUSTER],
+            screen_size: [config.width as f32, config.height as f32],
+            znear: camera.znear,
+            zfar: camera.zfar,
+        };
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::bytes_of(&params));
+    }
+
+    /// Records the dispatch listing the lights of every cluster, which has to
+    /// happen after the lights are uploaded and before anything reads them
+    pub fn cluster(&self, encoder: &mut wgpu::CommandEncoder) {
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Light Clustering Pass"),
+        });
+        compute_pass.set_pipeline(&self.pipeline);
+        compute_pass.set_bind_group(0, &self.compute_bind_group, &[]);
+        compute_pass.dispatch(CLUSTER_COUNT.div_ceil(WORKGROUP_SIZE), 1, 1);
+    }
+}
+
+/// The lights followed by the cluster params, clusters and light indices
+fn buffer_entries<'a>(
+    inputs: &LightingInputs<'a>,
+    buffers: [&'a wgpu::Buffer; 3],
+) -> Vec<wgpu::BindGroupEntry<'a>> {
+    std::iter::once(inputs.lights.buffer())
+        .chain(buffers)
+        .enumerate()
+        .map(|(i, buffer)| wgpu::BindGroupEntry {
+            binding: i as u32,
+            resource: buffer.as_entire_binding(),
+        })
+        .collect()
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    inputs: &LightingInputs,
+    buffers: [&wgpu::Buffer; 3],
+) -> Result<wgpu::BindGroup> {
+    let entries = buffer_entries(inputs, buffers)
+        .into_iter()
+        .chain(inputs.environment.entries())
+        .chain([
+            wgpu::BindGroupEntry {
+                binding: 8,
+                resource: inputs.scene.buffer().as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 9,
+                resource: wgpu::BindingResource::TextureView(inputs.occlusion),
+            },
+        ])
+        .collect::<Vec<_>>();
+    capture_validation_errors(device, || {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Lights bind group"),
+            layout,
+            entries: &entries,
+        })
+    })
+}
+
+fn create_compute_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    module: &wgpu::ShaderModule,
+) -> wgpu::ComputePipeline {
+    device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("Light Clustering Pipeline"),
+        layout: Some(layout),
+        module,
+        entry_point: "cs_main",
+    })
+}
@@ -0,0 +1,281 @@
+use crate::{
+    camera::Camera,
+    deg_to_rad,
+    instance::{Instance, InstanceRaw},
+    lod::{Lod, LodSettings},
+    material::{AlphaMode, Draw, MaterialId, Materials},
+    oit::Transparency,
+};
+
+/// Axis aligned bounding box
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: glam::Vec3,
+    pub max: glam::Vec3,
+}
+
+impl Aabb {
+    /// Smallest box containing every point, or `None` if there are no points
+    pub fn from_points(points: impl IntoIterator<Item = glam::Vec3>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        Some(points.fold(Self::new(first, first), |aabb, point| {
+            Self::new(aabb.min.min(point), aabb.max.max(point))
+        }))
+    }
+
+    pub fn new(min: glam::Vec3, max: glam::Vec3) -> Self {
+        Self { min, max }
+    }
+
+    pub fn center(&self) -> glam::Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    /// Half of the size on every axis
+    pub fn extents(&self) -> glam::Vec3 {
+        (self.max - self.min) * 0.5
+    }
+
+    /// Box containing this box after it's transformed by `matrix`
+    pub fn transformed(&self, matrix: &glam::Mat4) -> Self {
+        let center = matrix.transform_point3(self.center());
+        let extents = self.extents();
+        // Every axis of the matrix contributes its absolute value to the extents
+        let extents = matrix.x_axis.truncate().abs() * extents.x
+            + matrix.y_axis.truncate().abs() * extents.y
+            + matrix.z_axis.truncate().abs() * extents.z;
+        Self::new(center - extents, center + extents)
+    }
+}
+
+/// Points `p` with `normal.dot(p) + d >= 0` are in front of the plane
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Plane {
+    pub normal: glam::Vec3,
+    pub d: f32,
+}
+
+impl Plane {
+    fn from_vec4(v: glam::Vec4) -> Self {
+        let length = v.truncate().length();
+        Self {
+            normal: v.truncate() / length,
+            d: v.w / length,
+        }
+    }
+
+    pub fn distance(&self, point: glam::Vec3) -> f32 {
+        self.normal.dot(point) + self.d
+    }
+}
+
+/// The volume a camera sees, as six planes facing inwards
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Frustum {
+    pub planes: [Plane; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes from a view projection matrix like the one built by
+    /// `Camera::build_vp_matrix`, which maps depth to 0..1
+    pub fn from_matrix(view_proj: glam::Mat4) -> Self {
+        let row = |i| view_proj.row(i);
+        Self {
+            planes: [
+                Plane::from_vec4(row(3) + row(0)), // Left
+                Plane::from_vec4(row(3) - row(0)), // Right
+                Plane::from_vec4(row(3) + row(1)), // Bottom
+                Plane::from_vec4(row(3) - row(1)), // Top
+                Plane::from_vec4(row(2)),          // Near
+                Plane::from_vec4(row(3) - row(2)), // Far
+            ],
+        }
+    }
+
+    /// Whether any part of the box might be visible. Boxes near the corners of
+    /// the frustum can be reported as visible even if they aren't.
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        let center = aabb.center();
+        let extents = aabb.extents();
+        self.planes.iter().all(|plane| {
+            // Distance from the center to the corner furthest along the normal
+            let radius = extents.dot(plane.normal.abs());
+            plane.distance(center) >= -radius
+        })
+    }
+}
+
+/// Instances of a model drawn with the same material
+pub struct Batch {
+    /// Ordered from the most to the least detailed. The bounds of the first
+    /// level are used for culling.
+    pub lods: Vec<Lod>,
+    pub material: MaterialId,
+    pub instances: Vec<Instance>,
+    /// Level every instance was drawn with last frame, resized to match
+    /// `instances` when they're culled
+    current_lods: Vec<usize>,
+}
+
+impl Batch {
+    pub fn new(lods: Vec<Lod>, material: MaterialId, instances: Vec<Instance>) -> Self {
+        assert!(
+            !lods.is_empty(),
+            "A batch needs at least one level of detail"
+        );
+        Self {
+            current_lods: vec![0; instances.len()],
+            lods,
+            material,
+            instances,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CullStats {
+    pub drawn: u32,
+    pub culled: u32,
+}
+
+/// Selects the level of detail of the instances that intersect the camera's
+/// frustum. Their instance data is appended to `instance_data`, grouped by
+/// level, and a draw for every level with visible instances to `draws`.
+///
+/// With [`Transparency::Sorted`] instances of blended materials get a draw
+/// each instead, so they can be sorted back to front.
+pub fn cull(
+    camera: &Camera,
+    lod_settings: &LodSettings,
+    transparency: Transparency,
+    materials: &Materials,
+    batches: &mut [Batch],
+    instance_data: &mut Vec<InstanceRaw>,
+    draws: &mut Vec<Draw>,
+) -> CullStats {
+    let frustum = Frustum::from_matrix(camera.build_vp_matrix());
+    let fovy = deg_to_rad(camera.fovy);
+    let forward = camera.front.normalize();
+
+    let mut stats = CullStats::default();
+    // Visible instances of every level along with the closest one's depth
+    let mut levels = Vec::<(Vec<_>, f32)>::new();
+    for batch in batches {
+        // Batches are skipped until every level is loaded
+        let meshes = match batch
+            .lods
+            .iter()
+            .map(|lod| lod.mesh.get())
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(meshes) => meshes,
+            None => continue,
+        };
+        let bounds = meshes[0].bounds;
+        let sorted = transparency == Transparency::Sorted
+            && materials.alpha_mode(batch.material) == AlphaMode::Blend;
+        levels.resize_with(batch.lods.len(), || (Vec::new(), f32::INFINITY));
+        // Instances can be added or removed between frames
+        batch.current_lods.resize(batch.instances.len(), 0);
+
+        for (instance, current) in batch.instances.iter().zip(&mut batch.current_lods) {
+            let raw = instance.to_raw();
+            let matrix = glam::Mat4::from_cols_array_2d(&raw.model);
+            let world_bounds = bounds.transformed(&matrix);
+            if !frustum.intersects_aabb(&world_bounds) {
+                stats.culled += 1;
+                continue;
+            }
+            stats.drawn += 1;
+
+            let center = world_bounds.center();
+            let distance = center.distance(camera.eye);
+            let radius = world_bounds.extents().length();
+            *current = lod_settings.select(&batch.lods, *current, distance, radius, fovy);
+            let depth = (center - camera.eye).dot(forward);
+
+            if sorted {
+                instance_data.push(raw);
+                draws.push(Draw {
+                    mesh: meshes[*current].clone(),
+                    material: batch.material,
+                    instances: instance_data.len() as u32 - 1..instance_data.len() as u32,
+                    depth,
+                });
+            } else {
+                let (level, nearest) = &mut levels[*current];
+                level.push(raw);
+                *nearest = nearest.min(depth);
+            }
+        }
+
+        for (mesh, (level, nearest)) in meshes.into_iter().zip(&mut levels) {
+            if level.is_empty() {
+                continue;
+            }
+            let start = instance_data.len() as u32;
+            instance_data.append(level);
+            draws.push(Draw {
+                mesh,
+                material: batch.material,
+                instances: start..instance_data.len() as u32,
+                depth: *nearest,
+            });
+            *nearest = f32::INFINITY;
+        }
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frustum() -> Frustum {
+        let view = glam::Mat4::look_at_rh(glam::Vec3::ZERO, -glam::Vec3::Z, glam::Vec3::Y);
+        let proj = glam::Mat4::perspective_rh(std::f32::consts::FRAC_PI_2, 1.0, 0.1, 100.0);
+        Frustum::from_matrix(proj * view)
+    }
+
+    fn cube(center: glam::Vec3) -> Aabb {
+        Aabb::new(
+            center - glam::Vec3::splat(0.5),
+            center + glam::Vec3::splat(0.5),
+        )
+    }
+
+    #[test]
+    fn extracts_normalized_planes() {
+        let frustum = frustum();
+        let near = frustum.planes[4];
+        assert!((near.normal - -glam::Vec3::Z).length() < 1e-5);
+        assert!((near.d + 0.1).abs() < 1e-5);
+        let far = frustum.planes[5];
+        assert!((far.normal - glam::Vec3::Z).length() < 1e-5);
+        assert!((far.d - 100.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn culls_boxes_outside_the_frustum() {
+        let frustum = frustum();
+        assert!(frustum.intersects_aabb(&cube(glam::vec3(0.0, 0.0, -5.0))));
+        // Partially inside the left plane
+        assert!(frustum.intersects_aabb(&cube(glam::vec3(-5.4, 0.0, -5.0))));
+
+        assert!(!frustum.intersects_aabb(&cube(glam::vec3(0.0, 0.0, 5.0))));
+        assert!(!frustum.intersects_aabb(&cube(glam::vec3(-6.5, 0.0, -5.0))));
+        assert!(!frustum.intersects_aabb(&cube(glam::vec3(0.0, 7.0, -5.0))));
+        assert!(!frustum.intersects_aabb(&cube(glam::vec3(0.0, 0.0, -101.0))));
+    }
+
+    #[test]
+    fn transforms_boxes() {
+        let aabb = Aabb::new(glam::vec3(-1.0, -2.0, -3.0), glam::vec3(1.0, 2.0, 3.0));
+        let rotation = glam::Mat4::from_rotation_y(std::f32::consts::FRAC_PI_2);
+        let translation = glam::Mat4::from_translation(glam::vec3(10.0, 0.0, 0.0));
+        let transformed = aabb.transformed(&(translation * rotation));
+        assert!((transformed.min - glam::vec3(7.0, -2.0, -1.0)).length() < 1e-5);
+        assert!((transformed.max - glam::vec3(13.0, 2.0, 1.0)).length() < 1e-5);
+    }
+}
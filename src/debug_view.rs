@@ -0,0 +1,407 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::*;
+
+use crate::{
+    assets::AssetManager,
+    camera::Camera,
+    fullscreen::FullscreenPass,
+    hdr::HDR_FORMAT,
+    instance::Instance,
+    material::Draw,
+    mesh::Mesh,
+    pipeline::{DepthState, ModuleId, PipelineCache, PipelineKey},
+    preprocessor::Preprocessor,
+    shader::{capture_validation_errors, Shader},
+    texture::Texture,
+    vertex::Vertex,
+};
+
+/// How many times every pixel was drawn, counted by the overdraw view
+const OVERDRAW_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R16Float;
+
+/// Replaces the materials of everything drawn to show one property of it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugView {
+    Off,
+    Wireframe,
+    Normals,
+    TexCoords,
+    Depth,
+    /// Heatmap of how many times every pixel is drawn
+    Overdraw,
+}
+
+impl DebugView {
+    /// The view after this one, wrapping around to `Off`
+    pub fn next(self) -> Self {
+        match self {
+            DebugView::Off => DebugView::Wireframe,
+            DebugView::Wireframe => DebugView::Normals,
+            DebugView::Normals => DebugView::TexCoords,
+            DebugView::TexCoords => DebugView::Depth,
+            DebugView::Depth => DebugView::Overdraw,
+            DebugView::Overdraw => DebugView::Off,
+        }
+    }
+
+    /// Define that selects the view in debug.wgsl
+    fn define(self) -> Option<&'static str> {
+        match self {
+            DebugView::Off => None,
+            DebugView::Wireframe => Some("WIREFRAME"),
+            DebugView::Normals => Some("NORMALS"),
+            DebugView::TexCoords => Some("TEX_COORDS"),
+            DebugView::Depth => Some("DEPTH"),
+            DebugView::Overdraw => Some("OVERDRAW"),
+        }
+    }
+}
+
+struct ViewShader {
+    view: DebugView,
+    shader: Shader,
+    module: ModuleId,
+    /// Whether the mesh is read from storage buffers instead of vertex buffers
+    pull_vertices: bool,
+}
+
+/// Draws the scene with a [`DebugView`] instead of its materials
+pub struct DebugViews {
+    pub view: DebugView,
+
+    shaders: Vec<ViewShader>,
+    empty_bind_group: wgpu::BindGroup,
+    mesh_layout: Option<wgpu::BindGroupLayout>,
+    /// Storage buffer bind groups of the meshes, keyed by their address. The
+    /// mesh is kept alive so the address can't be reused, until it's replaced
+    /// by a reload and nothing else references it.
+    mesh_bind_groups: HashMap<usize, (Arc<Mesh>, wgpu::BindGroup)>,
+    overdraw: Texture,
+    overdraw_bind_group: wgpu::BindGroup,
+    /// Maps the overdraw counts to colors
+    heatmap: FullscreenPass,
+}
+
+impl DebugViews {
+    /// The wireframe uses PolygonMode::Line if the device was created with
+    /// Features::POLYGON_MODE_LINE (called NON_FILL_POLYGON_MODE before wgpu
+    /// 0.12), otherwise it draws the edges using barycentric coordinates
+    pub fn new(
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        config: &wgpu::SurfaceConfiguration,
+        camera_layout: &wgpu::BindGroupLayout,
+        camera: &Camera,
+    ) -> Result<Self> {
+        let line_mode = device
+            .features()
+            .contains(wgpu::Features::POLYGON_MODE_LINE);
+        let empty_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Empty bind group layout"),
+            entries: &[],
+        });
+        let empty_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Empty bind group"),
+            layout: &empty_layout,
+            entries: &[],
+        });
+
+        let mut shaders = Vec::new();
+        let mut mesh_layout = None;
+        let views = [
+            DebugView::Wireframe,
+            DebugView::Normals,
+            DebugView::TexCoords,
+            DebugView::Depth,
+            DebugView::Overdraw,
+        ];
+        for view in views {
+            let pull_vertices = view == DebugView::Wireframe && !line_mode;
+            let mut preprocessor = Preprocessor::new()
+                .define(view.define().unwrap(), "")
+                .define("ZNEAR", &format!("{:?}", camera.znear))
+                .define("ZFAR", &format!("{:?}", camera.zfar));
+            if pull_vertices {
+                preprocessor = preprocessor.define("PULL_VERTICES", "");
+            }
+            let mut shader = Shader::new(
+                "shaders/debug.wgsl",
+                include_str!("../shaders/debug.wgsl"),
+                preprocessor,
+            );
+
+            let reflection = shader.reflect(assets, device, queue)?;
+            reflection.check_vertex_buffers(&vertex_buffers(pull_vertices))?;
+            let group_layout = if pull_vertices {
+                let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    label: Some("Debug view mesh bind group layout"),
+                    entries: &reflection.bind_groups[0],
+                });
+                mesh_layout.insert(layout)
+            } else {
+                &empty_layout
+            };
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Debug view pipeline layout"),
+                bind_group_layouts: &[group_layout, camera_layout],
+                push_constant_ranges: &[],
+            });
+
+            let module = shader.create_module(assets, device, queue)?;
+            shaders.push(ViewShader {
+                view,
+                shader,
+                module: pipelines.add_module(&format!("{:?} debug view", view), module, layout),
+                pull_vertices,
+            });
+        }
+
+        let heatmap = FullscreenPass::new(
+            assets,
+            device,
+            queue,
+            pipelines,
+            Shader::new(
+                "shaders/overdraw.wgsl",
+                include_str!("../shaders/overdraw.wgsl"),
+                Preprocessor::new(),
+            ),
+            Some(HDR_FORMAT),
+            None,
+        )?;
+        let overdraw = create_overdraw_target(device, config);
+        let overdraw_bind_group = create_bind_group(device, &heatmap, &overdraw)?;
+
+        Ok(Self {
+            view: DebugView::Off,
+            shaders,
+            empty_bind_group,
+            mesh_layout,
+            mesh_bind_groups: HashMap::new(),
+            overdraw,
+            overdraw_bind_group,
+            heatmap,
+        })
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
+        let overdraw = create_overdraw_target(device, config);
+        match create_bind_group(device, &self.heatmap, &overdraw) {
+            Result::Ok(bind_group) => self.overdraw_bind_group = bind_group,
+            Err(e) => log::error!("{:?}", e),
+        }
+        self.overdraw = overdraw;
+    }
+
+    /// Reloads edited shaders and prepares the current view for drawing
+    pub fn update(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        draws: &[Draw],
+    ) {
+        for entry in &mut self.shaders {
+            if entry.shader.changed() {
+                let result = entry
+                    .shader
+                    .create_module(assets, device, queue)
+                    .and_then(|module| pipelines.replace_module(device, entry.module, module));
+                if let Err(e) = result {
+                    log::error!("{:?}", e);
+                }
+            }
+        }
+        self.heatmap.update(assets, device, queue, pipelines);
+        // Only referenced here once the mesh's handle and the draws moved on
+        // to a reloaded version
+        self.mesh_bind_groups
+            .retain(|_, (mesh, _)| Arc::strong_count(mesh) > 1);
+
+        let entry = match self.shaders.iter().find(|entry| entry.view == self.view) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if let Err(e) = pipelines.get_or_create(device, &pipeline_key(entry)) {
+            log::error!("{:?}", e);
+        }
+
+        if let (true, Some(layout)) = (entry.pull_vertices, &self.mesh_layout) {
+            for draw in draws {
+                self.mesh_bind_groups
+                    .entry(Arc::as_ptr(&draw.mesh) as usize)
+                    .or_insert_with(|| {
+                        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                            label: Some("Debug view mesh bind group"),
+                            layout,
+                            entries: &[
+                                wgpu::BindGroupEntry {
+                                    binding: 0,
+                                    resource: draw.mesh.vertex_buffer.as_entire_binding(),
+                                },
+                                wgpu::BindGroupEntry {
+                                    binding: 1,
+                                    resource: draw.mesh.index_buffer.as_entire_binding(),
+                                },
+                            ],
+                        });
+                        (draw.mesh.clone(), bind_group)
+                    });
+            }
+        }
+    }
+
+    /// Records the draws with the current view. The camera bind group must
+    /// already be bound, `instance_buffer` is the buffer the draws' instance
+    /// ranges refer to.
+    ///
+    /// [`DebugView::Overdraw`] has its own target and is drawn with
+    /// [`DebugViews::draw_overdraw`] instead.
+    pub fn draw<'a>(
+        &'a self,
+        pipelines: &'a PipelineCache,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        draws: &'a [Draw],
+        instance_buffer: &'a wgpu::Buffer,
+    ) {
+        let entry = match self.shaders.iter().find(|entry| entry.view == self.view) {
+            Some(entry) => entry,
+            None => return,
+        };
+        match pipelines.get(&pipeline_key(entry)) {
+            Some(pipeline) => render_pass.set_pipeline(pipeline),
+            None => return,
+        }
+
+        if !entry.pull_vertices {
+            render_pass.set_bind_group(0, &self.empty_bind_group, &[]);
+            render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+            for draw in draws {
+                draw.mesh.draw(render_pass, draw.instances.clone());
+            }
+            return;
+        }
+
+        render_pass.set_vertex_buffer(0, instance_buffer.slice(..));
+        for draw in draws {
+            if let Some((_, bind_group)) = self
+                .mesh_bind_groups
+                .get(&(Arc::as_ptr(&draw.mesh) as usize))
+            {
+                render_pass.set_bind_group(0, bind_group, &[]);
+                render_pass.draw(0..draw.mesh.num_indices, draw.instances.clone());
+            }
+        }
+    }
+
+    /// Counts how many times every pixel is drawn, then overwrites `view` with
+    /// the counts mapped to colors
+    pub fn draw_overdraw(
+        &self,
+        pipelines: &PipelineCache,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        camera_bind_group: &wgpu::BindGroup,
+        draws: &[Draw],
+        instance_buffer: &wgpu::Buffer,
+    ) {
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Overdraw Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &self.overdraw.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_bind_group(1, camera_bind_group, &[]);
+            self.draw(pipelines, &mut render_pass, draws, instance_buffer);
+        }
+        self.heatmap
+            .draw(pipelines, encoder, view, &self.overdraw_bind_group);
+    }
+}
+
+fn create_overdraw_target(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> Texture {
+    Texture::create_render_target(device, config, OVERDRAW_FORMAT, "Overdraw texture")
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    heatmap: &FullscreenPass,
+    overdraw: &Texture,
+) -> Result<wgpu::BindGroup> {
+    capture_validation_errors(device, || {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Overdraw heatmap bind group"),
+            layout: heatmap.layout(),
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&overdraw.view),
+            }],
+        })
+    })
+}
+
+fn vertex_buffers(pull_vertices: bool) -> Vec<wgpu::VertexBufferLayout<'static>> {
+    if pull_vertices {
+        vec![Instance::desc()]
+    } else {
+        vec![Vertex::desc(), Instance::desc()]
+    }
+}
+
+fn pipeline_key(entry: &ViewShader) -> PipelineKey {
+    let overdraw = entry.view == DebugView::Overdraw;
+    let additive = wgpu::BlendComponent {
+        src_factor: wgpu::BlendFactor::One,
+        dst_factor: wgpu::BlendFactor::One,
+        operation: wgpu::BlendOperation::Add,
+    };
+
+    PipelineKey {
+        module: entry.module,
+        vertex_buffers: vertex_buffers(entry.pull_vertices),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            polygon_mode: if entry.view == DebugView::Wireframe && !entry.pull_vertices {
+                wgpu::PolygonMode::Line
+            } else {
+                wgpu::PolygonMode::Fill
+            },
+            ..Default::default()
+        },
+        // Overdraw counts every fragment, even the hidden ones, so it's drawn
+        // without depth
+        depth: (!overdraw).then_some(DepthState {
+            format: Texture::DEPTH_FORMAT,
+            write_enabled: true,
+            compare: wgpu::CompareFunction::Less,
+        }),
+        targets: vec![if overdraw {
+            wgpu::ColorTargetState {
+                format: OVERDRAW_FORMAT,
+                blend: Some(wgpu::BlendState {
+                    color: additive,
+                    alpha: additive,
+                }),
+                write_mask: wgpu::ColorWrites::ALL,
+            }
+        } else {
+            wgpu::ColorTargetState {
+                format: HDR_FORMAT,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            }
+        }],
+        multisample: wgpu::MultisampleState::default(),
+    }
+}
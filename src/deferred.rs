@@ -0,0 +1,275 @@
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    assets::AssetManager,
+    camera::Camera,
+    environment::Environment,
+    fullscreen::FullscreenPass,
+    hdr::HDR_FORMAT,
+    light::Lights,
+    pipeline::PipelineCache,
+    preprocessor::Preprocessor,
+    scene::SceneSettings,
+    shader::{capture_validation_errors, Shader},
+    texture::Texture,
+};
+
+pub const ALBEDO_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+/// World space normals
+pub const NORMAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+/// Metallic, roughness and occlusion
+pub const MATERIAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+pub const EMISSIVE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// How opaque materials are lit, chosen when the state is created
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RenderPath {
+    /// Every material loops over every light while it's drawn
+    Forward,
+    /// Materials write their surface to a G-buffer, then one pass lights every
+    /// pixel of it. Blended materials are still drawn forward.
+    Deferred,
+}
+
+/// Color targets of the G-buffer, written by `gbuffer_output` in gbuffer.wgsl
+pub fn targets() -> Vec<wgpu::ColorTargetState> {
+    [
+        ALBEDO_FORMAT,
+        NORMAL_FORMAT,
+        MATERIAL_FORMAT,
+        EMISSIVE_FORMAT,
+    ]
+    .into_iter()
+    .map(|format| wgpu::ColorTargetState {
+        format,
+        blend: None,
+        write_mask: wgpu::ColorWrites::ALL,
+    })
+    .collect()
+}
+
+/// Params in lighting.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightingParams {
+    inv_view_proj: [[f32; 4]; 4],
+    camera_position: [f32; 4],
+    background: [f32; 4],
+}
+
+/// Resources the lighting pass reads that are owned by something else
+pub struct LightingInputs<'a> {
+    pub lights: &'a Lights,
+    pub environment: &'a Environment,
+    pub scene: &'a SceneSettings,
+    /// The depth texture the scene is drawn with
+    pub depth: &'a Texture,
+    /// Ambient occlusion of `depth`
+    pub occlusion: &'a wgpu::TextureView,
+}
+
+/// The G-buffer of the deferred path and the pass lighting it.
+///
+/// Opaque materials are drawn in the pass returned by [`Deferred::gbuffer_pass`],
+/// then [`Deferred::lighting`] shades them with every light.
+pub struct Deferred {
+    albedo: Texture,
+    normal: Texture,
+    material: Texture,
+    emissive: Texture,
+    params_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    pass: FullscreenPass,
+}
+
+impl Deferred {
+    pub fn new(
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        config: &wgpu::SurfaceConfiguration,
+        inputs: &LightingInputs,
+    ) -> Result<Self> {
+        let pass = FullscreenPass::new(
+            assets,
+            device,
+            queue,
+            pipelines,
+            Shader::new(
+                "shaders/lighting.wgsl",
+                include_str!("../shaders/lighting.wgsl"),
+                Preprocessor::new(),
+            ),
+            Some(HDR_FORMAT),
+            None,
+        )?;
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Lighting params buffer"),
+            contents: bytemuck::bytes_of::<LightingParams>(&bytemuck::Zeroable::zeroed()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let [albedo, normal, material, emissive] = create_targets(device, config);
+        let bind_group = create_bind_group(
+            device,
+            &pass,
+            [&albedo, &normal, &material, &emissive],
+            &params_buffer,
+            inputs,
+        )?;
+
+        Ok(Self {
+            albedo,
+            normal,
+            material,
+            emissive,
+            params_buffer,
+            bind_group,
+            pass,
+        })
+    }
+
+    /// Has to be called after the inputs are resized
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        inputs: &LightingInputs,
+    ) {
+        let [albedo, normal, material, emissive] = create_targets(device, config);
+        let bind_group = create_bind_group(
+            device,
+            &self.pass,
+            [&albedo, &normal, &material, &emissive],
+            &self.params_buffer,
+            inputs,
+        );
+        match bind_group {
+            Result::Ok(bind_group) => self.bind_group = bind_group,
+            Err(e) => log::error!("{:?}", e),
+        }
+        self.albedo = albedo;
+        self.normal = normal;
+        self.material = material;
+        self.emissive = emissive;
+    }
+
+    /// Uploads the camera and the color of pixels nothing was drawn to, and
+    /// rebuilds the pipeline if needed
+    pub fn update(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        camera: &Camera,
+        background: wgpu::Color,
+    ) {
+        self.pass.update(assets, device, queue, pipelines);
+        let params = LightingParams {
+            inv_view_proj: camera.build_vp_matrix().inverse().to_cols_array_2d(),
+            camera_position: camera.eye.extend(1.0).to_array(),
+            background: [background.r, background.g, background.b, background.a].map(|c| c as f32),
+        };
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::bytes_of(&params));
+    }
+
+    /// Starts a pass that clears the G-buffer and `depth_view`, to draw opaque
+    /// materials in with [`crate::material::Materials::draw_gbuffer`]
+    pub fn gbuffer_pass<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+        depth_view: &'a wgpu::TextureView,
+    ) -> wgpu::RenderPass<'a> {
+        let attachment = |texture: &'a Texture| wgpu::RenderPassColorAttachment {
+            view: &texture.view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                store: true,
+            },
+        };
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("G-buffer Pass"),
+            color_attachments: &[
+                attachment(&self.albedo),
+                attachment(&self.normal),
+                attachment(&self.material),
+                attachment(&self.emissive),
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        })
+    }
+
+    /// Lights the G-buffer, overwriting `view`
+    pub fn lighting(
+        &self,
+        pipelines: &PipelineCache,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+    ) {
+        self.pass.draw(pipelines, encoder, view, &self.bind_group);
+    }
+}
+
+fn create_targets(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> [Texture; 4] {
+    [
+        (ALBEDO_FORMAT, "G-buffer albedo texture"),
+        (NORMAL_FORMAT, "G-buffer normal texture"),
+        (MATERIAL_FORMAT, "G-buffer material texture"),
+        (EMISSIVE_FORMAT, "G-buffer emissive texture"),
+    ]
+    .map(|(format, label)| Texture::create_render_target(device, config, format, label))
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    pass: &FullscreenPass,
+    targets: [&Texture; 4],
+    params_buffer: &wgpu::Buffer,
+    inputs: &LightingInputs,
+) -> Result<wgpu::BindGroup> {
+    let [albedo, normal, material, emissive] = targets;
+    let texture = |binding, view| wgpu::BindGroupEntry {
+        binding,
+        resource: wgpu::BindingResource::TextureView(view),
+    };
+    let entries = [
+        wgpu::BindGroupEntry {
+            binding: 0,
+            resource: inputs.lights.buffer().as_entire_binding(),
+        },
+        wgpu::BindGroupEntry {
+            binding: 8,
+            resource: inputs.scene.buffer().as_entire_binding(),
+        },
+        texture(9, inputs.occlusion),
+        texture(10, &albedo.view),
+        texture(11, &normal.view),
+        texture(12, &material.view),
+        texture(13, &emissive.view),
+        texture(14, &inputs.depth.view),
+        wgpu::BindGroupEntry {
+            binding: 15,
+            resource: params_buffer.as_entire_binding(),
+        },
+    ]
+    .into_iter()
+    .chain(inputs.environment.entries())
+    .collect::<Vec<_>>();
+    capture_validation_errors(device, || {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Lighting bind group"),
+            layout: pass.layout(),
+            entries: &entries,
+        })
+    })
+}
@@ -0,0 +1,264 @@
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    assets::AssetManager,
+    fullscreen::FullscreenPass,
+    pipeline::PipelineCache,
+    preprocessor::Preprocessor,
+    shader::{capture_validation_errors, Shader},
+};
+
+const ENVIRONMENT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+const BRDF_LUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+/// Width and height of the faces of the cube maps
+const SKY_SIZE: u32 = 256;
+const IRRADIANCE_SIZE: u32 = 32;
+const PREFILTERED_SIZE: u32 = 128;
+/// The roughness goes from 0 at the first level to 1 at the last one
+const PREFILTERED_MIPS: u32 = 5;
+const BRDF_LUT_SIZE: u32 = 256;
+
+/// Params in environment.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct EnvironmentParams {
+    face: u32,
+    roughness: f32,
+}
+
+/// Image based lighting from a procedural sky, read by `ambient_lighting` in
+/// lights.wgsl.
+///
+/// Everything is generated once when it's created: the sky is convolved into
+/// an irradiance map for diffuse light and prefiltered into a mip chain for
+/// specular light of increasing roughness, and the BRDF of the split sum
+/// approximation is integrated into a lookup table.
+pub struct Environment {
+    irradiance: wgpu::TextureView,
+    prefiltered: wgpu::TextureView,
+    brdf_lut: wgpu::TextureView,
+    sampler: wgpu::Sampler,
+}
+
+impl Environment {
+    pub fn new(
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+    ) -> Result<Self> {
+        let mut create_pass = |define: Option<&str>, format| {
+            let mut preprocessor = Preprocessor::new();
+            if let Some(define) = define {
+                preprocessor = preprocessor.define(define, "");
+            }
+            FullscreenPass::new(
+                assets,
+                device,
+                queue,
+                pipelines,
+                Shader::new(
+                    "shaders/environment.wgsl",
+                    include_str!("../shaders/environment.wgsl"),
+                    preprocessor,
+                ),
+                Some(format),
+                None,
+            )
+        };
+        let sky_pass = create_pass(None, ENVIRONMENT_FORMAT)?;
+        let irradiance_pass = create_pass(Some("IRRADIANCE"), ENVIRONMENT_FORMAT)?;
+        let prefilter_pass = create_pass(Some("PREFILTER"), ENVIRONMENT_FORMAT)?;
+        let brdf_lut_pass = create_pass(Some("BRDF_LUT"), BRDF_LUT_FORMAT)?;
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Environment sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let faces = FaceRenderer {
+            device,
+            pipelines,
+            sampler: &sampler,
+        };
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment Encoder"),
+        });
+
+        let sky = create_cube(device, SKY_SIZE, 1, "Sky texture");
+        faces.draw(&mut encoder, &sky_pass, &sky, 0, 0.0, None)?;
+        let sky_view = cube_view(&sky);
+
+        let irradiance = create_cube(device, IRRADIANCE_SIZE, 1, "Irradiance texture");
+        faces.draw(
+            &mut encoder,
+            &irradiance_pass,
+            &irradiance,
+            0,
+            0.0,
+            Some(&sky_view),
+        )?;
+
+        let prefiltered = create_cube(
+            device,
+            PREFILTERED_SIZE,
+            PREFILTERED_MIPS,
+            "Prefiltered environment texture",
+        );
+        for mip in 0..PREFILTERED_MIPS {
+            let roughness = mip as f32 / (PREFILTERED_MIPS - 1) as f32;
+            faces.draw(
+                &mut encoder,
+                &prefilter_pass,
+                &prefiltered,
+                mip,
+                roughness,
+                Some(&sky_view),
+            )?;
+        }
+
+        let brdf_lut = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("BRDF LUT texture"),
+            size: wgpu::Extent3d {
+                width: BRDF_LUT_SIZE,
+                height: BRDF_LUT_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: BRDF_LUT_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+        let brdf_lut = brdf_lut.create_view(&wgpu::TextureViewDescriptor::default());
+        let empty_bind_group = capture_validation_errors(device, || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("BRDF LUT bind group"),
+                layout: brdf_lut_pass.layout(),
+                entries: &[],
+            })
+        })?;
+        brdf_lut_pass.draw(pipelines, &mut encoder, &brdf_lut, &empty_bind_group);
+
+        queue.submit(std::iter::once(encoder.finish()));
+
+        Ok(Self {
+            irradiance: cube_view(&irradiance),
+            prefiltered: cube_view(&prefiltered),
+            brdf_lut,
+            sampler,
+        })
+    }
+
+    /// Bindings 4 to 7 of the bind group lights.wgsl is bound with
+    pub fn entries(&self) -> [wgpu::BindGroupEntry<'_>; 4] {
+        [
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::TextureView(&self.irradiance),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::TextureView(&self.prefiltered),
+            },
+            wgpu::BindGroupEntry {
+                binding: 6,
+                resource: wgpu::BindingResource::TextureView(&self.brdf_lut),
+            },
+            wgpu::BindGroupEntry {
+                binding: 7,
+                resource: wgpu::BindingResource::Sampler(&self.sampler),
+            },
+        ]
+    }
+}
+
+/// Draws the faces of cube maps with environment.wgsl
+struct FaceRenderer<'a> {
+    device: &'a wgpu::Device,
+    pipelines: &'a PipelineCache,
+    sampler: &'a wgpu::Sampler,
+}
+
+impl FaceRenderer<'_> {
+    /// Draws every face of one mip level of `target`, reading `source` if the
+    /// pass convolves it
+    fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pass: &FullscreenPass,
+        target: &wgpu::Texture,
+        mip: u32,
+        roughness: f32,
+        source: Option<&wgpu::TextureView>,
+    ) -> Result<()> {
+        for face in 0..6 {
+            let params_buffer = self
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Environment params buffer"),
+                    contents: bytemuck::bytes_of(&EnvironmentParams { face, roughness }),
+                    usage: wgpu::BufferUsages::UNIFORM,
+                });
+            let mut entries = vec![wgpu::BindGroupEntry {
+                binding: 2,
+                resource: params_buffer.as_entire_binding(),
+            }];
+            if let Some(source) = source {
+                entries.push(wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(source),
+                });
+                entries.push(wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(self.sampler),
+                });
+            }
+            let bind_group = capture_validation_errors(self.device, || {
+                self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("Environment bind group"),
+                    layout: pass.layout(),
+                    entries: &entries,
+                })
+            })?;
+
+            let view = target.create_view(&wgpu::TextureViewDescriptor {
+                label: Some("Environment face view"),
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_mip_level: mip,
+                mip_level_count: std::num::NonZeroU32::new(1),
+                base_array_layer: face,
+                array_layer_count: std::num::NonZeroU32::new(1),
+                ..Default::default()
+            });
+            pass.draw(self.pipelines, encoder, &view, &bind_group);
+        }
+        Ok(())
+    }
+}
+
+fn create_cube(device: &wgpu::Device, size: u32, mips: u32, label: &str) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 6,
+        },
+        mip_level_count: mips,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: ENVIRONMENT_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+    })
+}
+
+fn cube_view(texture: &wgpu::Texture) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::Cube),
+        ..Default::default()
+    })
+}
@@ -0,0 +1,129 @@
+use anyhow::*;
+
+use crate::{
+    assets::AssetManager,
+    pipeline::{ModuleId, PipelineCache, PipelineKey},
+    shader::Shader,
+};
+
+/// A fragment shader drawn over the whole target with the `vs_main` of
+/// fullscreen.wgsl, reading its resources from bind group 0
+pub struct FullscreenPass {
+    shader: Shader,
+    layout: wgpu::BindGroupLayout,
+    module: ModuleId,
+    /// `None` draws to the surface
+    format: Option<wgpu::TextureFormat>,
+    blend: Option<wgpu::BlendState>,
+}
+
+impl FullscreenPass {
+    /// Passes without `blend` overwrite the target, the others are blended
+    /// over it
+    pub fn new(
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        mut shader: Shader,
+        format: Option<wgpu::TextureFormat>,
+        blend: Option<wgpu::BlendState>,
+    ) -> Result<Self> {
+        let label = shader.label();
+        let reflection = shader.reflect(assets, device, queue)?;
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some(label),
+            entries: reflection.bind_groups.first().map_or(&[], Vec::as_slice),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(label),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let module = shader.create_module(assets, device, queue)?;
+        let module = pipelines.add_module(label, module, pipeline_layout);
+
+        let pass = Self {
+            shader,
+            layout,
+            module,
+            format,
+            blend,
+        };
+        pipelines.get_or_create(device, &pass.pipeline_key(pipelines))?;
+        Ok(pass)
+    }
+
+    /// Layout of the bind group passed to [`FullscreenPass::draw`]
+    pub fn layout(&self) -> &wgpu::BindGroupLayout {
+        &self.layout
+    }
+
+    /// Rebuilds the pipeline if the shader was edited or the surface format
+    /// changed
+    pub fn update(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+    ) {
+        if self.shader.changed() {
+            let result = self
+                .shader
+                .create_module(assets, device, queue)
+                .and_then(|module| pipelines.replace_module(device, self.module, module));
+            if let Err(e) = result {
+                log::error!("{:?}", e);
+            }
+        }
+        if let Err(e) = pipelines.get_or_create(device, &self.pipeline_key(pipelines)) {
+            log::error!("{:?}", e);
+        }
+    }
+
+    pub fn draw(
+        &self,
+        pipelines: &PipelineCache,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        bind_group: &wgpu::BindGroup,
+    ) {
+        let pipeline = match pipelines.get(&self.pipeline_key(pipelines)) {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+
+        let load = match self.blend {
+            Some(_) => wgpu::LoadOp::Load,
+            None => wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+        };
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(self.shader.label()),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations { load, store: true },
+            }],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+
+    fn pipeline_key(&self, pipelines: &PipelineCache) -> PipelineKey {
+        PipelineKey {
+            module: self.module,
+            vertex_buffers: Vec::new(),
+            primitive: wgpu::PrimitiveState::default(),
+            depth: None,
+            targets: vec![wgpu::ColorTargetState {
+                format: self.format.unwrap_or_else(|| pipelines.surface_format()),
+                blend: self.blend,
+                write_mask: wgpu::ColorWrites::ALL,
+            }],
+            multisample: wgpu::MultisampleState::default(),
+        }
+    }
+}
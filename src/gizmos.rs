@@ -0,0 +1,337 @@
+use anyhow::*;
+
+use crate::{
+    assets::AssetManager,
+    pipeline::{DepthState, ModuleId, PipelineCache, PipelineKey},
+    preprocessor::Preprocessor,
+    shader::Shader,
+    texture::Texture,
+};
+
+/// Segments used for the circles of spheres
+const CIRCLE_SEGMENTS: u32 = 32;
+/// Initial size of the vertex buffer in bytes, it grows when needed
+const INITIAL_CAPACITY: wgpu::BufferAddress = 16 * 1024;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LineVertex {
+    pub position: [f32; 3],
+    pub color: [f32; 4],
+}
+
+impl LineVertex {
+    const ATTRIBS: [wgpu::VertexAttribute; 2] =
+        wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x4];
+
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<LineVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+/// Immediate mode debug drawing.
+///
+/// Shapes can be added at any point before [`Gizmos::prepare`], which uploads
+/// them and clears them for the next frame. They're drawn as lines on top of the
+/// scene, hidden behind it if `depth_test` was set when they were added.
+pub struct Gizmos {
+    /// Whether shapes added from now on are hidden by the scene
+    pub depth_test: bool,
+
+    tested: Vec<LineVertex>,
+    overlay: Vec<LineVertex>,
+
+    buffer: wgpu::Buffer,
+    capacity: wgpu::BufferAddress,
+    /// Number of depth tested and overlay vertices in `buffer`
+    counts: (u32, u32),
+
+    shader: Shader,
+    module: ModuleId,
+}
+
+impl Gizmos {
+    /// `camera_layout` is the layout of the camera bind group passed to
+    /// [`Gizmos::render`]
+    pub fn new(
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        camera_layout: &wgpu::BindGroupLayout,
+    ) -> Result<Self> {
+        let mut shader = Shader::new(
+            "shaders/gizmo.wgsl",
+            include_str!("../shaders/gizmo.wgsl"),
+            Preprocessor::new(),
+        );
+        shader
+            .reflect(assets, device, queue)?
+            .check_vertex_buffers(&[LineVertex::desc()])?;
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Gizmo pipeline layout"),
+            bind_group_layouts: &[camera_layout],
+            push_constant_ranges: &[],
+        });
+        let module = shader.create_module(assets, device, queue)?;
+        let module = pipelines.add_module("Gizmo Pipeline", module, layout);
+
+        Ok(Self {
+            depth_test: true,
+            tested: Vec::new(),
+            overlay: Vec::new(),
+            buffer: create_buffer(device, INITIAL_CAPACITY),
+            capacity: INITIAL_CAPACITY,
+            counts: (0, 0),
+            shader,
+            module,
+        })
+    }
+
+    pub fn line(&mut self, start: glam::Vec3, end: glam::Vec3, color: [f32; 4]) {
+        let vertices = if self.depth_test {
+            &mut self.tested
+        } else {
+            &mut self.overlay
+        };
+        vertices.push(LineVertex {
+            position: start.into(),
+            color,
+        });
+        vertices.push(LineVertex {
+            position: end.into(),
+            color,
+        });
+    }
+
+    pub fn aabb(&mut self, min: glam::Vec3, max: glam::Vec3, color: [f32; 4]) {
+        let corners = box_corners(|x, y, z| {
+            glam::vec3(
+                if x { max.x } else { min.x },
+                if y { max.y } else { min.y },
+                if z { max.z } else { min.z },
+            )
+        });
+        self.box_edges(&corners, color);
+    }
+
+    /// Circle around `normal`
+    pub fn circle(&mut self, center: glam::Vec3, normal: glam::Vec3, radius: f32, color: [f32; 4]) {
+        let normal = normal.normalize();
+        let tangent = normal.any_orthonormal_vector() * radius;
+        let bitangent = normal.cross(tangent);
+
+        let point = |i: u32| {
+            let angle = i as f32 / CIRCLE_SEGMENTS as f32 * std::f32::consts::TAU;
+            center + tangent * angle.cos() + bitangent * angle.sin()
+        };
+        for i in 0..CIRCLE_SEGMENTS {
+            self.line(point(i), point(i + 1), color);
+        }
+    }
+
+    /// Three circles around the axes
+    pub fn sphere(&mut self, center: glam::Vec3, radius: f32, color: [f32; 4]) {
+        self.circle(center, glam::Vec3::X, radius, color);
+        self.circle(center, glam::Vec3::Y, radius, color);
+        self.circle(center, glam::Vec3::Z, radius, color);
+    }
+
+    /// Outline of the volume a view projection matrix sees
+    pub fn frustum(&mut self, view_proj: glam::Mat4, color: [f32; 4]) {
+        let inverse = view_proj.inverse();
+        let corners = box_corners(|x, y, z| {
+            let ndc = glam::vec3(
+                if x { 1.0 } else { -1.0 },
+                if y { 1.0 } else { -1.0 },
+                if z { 1.0 } else { 0.0 },
+            );
+            inverse.project_point3(ndc)
+        });
+        self.box_edges(&corners, color);
+    }
+
+    /// Red, green and blue lines along the X, Y and Z axes of `transform`
+    pub fn axes(&mut self, transform: glam::Mat4, size: f32) {
+        let origin = transform.transform_point3(glam::Vec3::ZERO);
+        let axes = [
+            (glam::Vec3::X, [1.0, 0.0, 0.0, 1.0]),
+            (glam::Vec3::Y, [0.0, 1.0, 0.0, 1.0]),
+            (glam::Vec3::Z, [0.0, 0.0, 1.0, 1.0]),
+        ];
+        for (axis, color) in axes {
+            self.line(origin, transform.transform_point3(axis * size), color);
+        }
+    }
+
+    /// Grid on the XZ plane with `cells` cells of size `spacing` on each side
+    /// of `center`
+    pub fn grid(&mut self, center: glam::Vec3, cells: u32, spacing: f32, color: [f32; 4]) {
+        let extent = cells as f32 * spacing;
+        for i in 0..=cells * 2 {
+            let offset = i as f32 * spacing - extent;
+            self.line(
+                center + glam::vec3(offset, 0.0, -extent),
+                center + glam::vec3(offset, 0.0, extent),
+                color,
+            );
+            self.line(
+                center + glam::vec3(-extent, 0.0, offset),
+                center + glam::vec3(extent, 0.0, offset),
+                color,
+            );
+        }
+    }
+
+    /// Uploads this frame's shapes and clears them. Also rebuilds the pipelines
+    /// if the shader was edited.
+    pub fn prepare(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+    ) {
+        if self.shader.changed() {
+            let result = self
+                .shader
+                .create_module(assets, device, queue)
+                .and_then(|module| pipelines.replace_module(device, self.module, module));
+            if let Err(e) = result {
+                log::error!("{:?}", e);
+            }
+        }
+        for depth_test in [true, false] {
+            if let Err(e) =
+                pipelines.get_or_create(device, &self.pipeline_key(pipelines, depth_test))
+            {
+                log::error!("{:?}", e);
+            }
+        }
+
+        self.counts = (self.tested.len() as u32, self.overlay.len() as u32);
+        let vertices = [self.tested.as_slice(), self.overlay.as_slice()].concat();
+        let size = std::mem::size_of_val(vertices.as_slice()) as wgpu::BufferAddress;
+        if size > self.capacity {
+            self.capacity = size.next_power_of_two();
+            self.buffer = create_buffer(device, self.capacity);
+        }
+        if !vertices.is_empty() {
+            queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&vertices));
+        }
+
+        self.tested.clear();
+        self.overlay.clear();
+    }
+
+    /// Draws the shapes uploaded by the last [`Gizmos::prepare`] on top of
+    /// `view`
+    pub fn render(
+        &self,
+        pipelines: &PipelineCache,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        depth_view: &wgpu::TextureView,
+        camera_bind_group: &wgpu::BindGroup,
+    ) {
+        let (tested, overlay) = self.counts;
+        if tested + overlay == 0 {
+            return;
+        }
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Gizmo Pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.buffer.slice(..));
+
+        let ranges = [(true, 0..tested), (false, tested..tested + overlay)];
+        for (depth_test, range) in ranges {
+            if range.is_empty() {
+                continue;
+            }
+            if let Some(pipeline) = pipelines.get(&self.pipeline_key(pipelines, depth_test)) {
+                render_pass.set_pipeline(pipeline);
+                render_pass.draw(range, 0..1);
+            }
+        }
+    }
+
+    fn pipeline_key(&self, pipelines: &PipelineCache, depth_test: bool) -> PipelineKey {
+        PipelineKey {
+            module: self.module,
+            vertex_buffers: vec![LineVertex::desc()],
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::LineList,
+                ..Default::default()
+            },
+            depth: Some(DepthState {
+                format: Texture::DEPTH_FORMAT,
+                write_enabled: false,
+                compare: if depth_test {
+                    wgpu::CompareFunction::LessEqual
+                } else {
+                    wgpu::CompareFunction::Always
+                },
+            }),
+            targets: vec![wgpu::ColorTargetState {
+                format: pipelines.surface_format(),
+                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                write_mask: wgpu::ColorWrites::ALL,
+            }],
+            multisample: wgpu::MultisampleState::default(),
+        }
+    }
+
+    fn box_edges(&mut self, corners: &[glam::Vec3; 8], color: [f32; 4]) {
+        // Corners are indexed by their x, y and z bits, so an edge connects
+        // corners that differ in exactly one bit
+        for i in 0..8 {
+            for bit in [1, 2, 4] {
+                if i & bit == 0 {
+                    self.line(corners[i], corners[i | bit], color);
+                }
+            }
+        }
+    }
+}
+
+/// Calls `corner` with every combination of the x, y and z sides of a box,
+/// ordered so that the index's bits are the sides
+fn box_corners(corner: impl Fn(bool, bool, bool) -> glam::Vec3) -> [glam::Vec3; 8] {
+    let mut corners = [glam::Vec3::ZERO; 8];
+    for (i, c) in corners.iter_mut().enumerate() {
+        *c = corner(i & 1 != 0, i & 2 != 0, i & 4 != 0);
+    }
+    corners
+}
+
+fn create_buffer(device: &wgpu::Device, size: wgpu::BufferAddress) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Gizmo vertex buffer"),
+        size,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
@@ -0,0 +1,317 @@
+use std::sync::Arc;
+
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    assets::{AssetManager, Handle},
+    culling::{Batch, Frustum},
+    instance::{Instance, InstanceRaw},
+    material::{MaterialId, Materials},
+    mesh::Mesh,
+    pipeline::PipelineCache,
+    preprocessor::Preprocessor,
+    shader::{capture_validation_errors, Shader},
+};
+
+/// Must match the workgroup size in cull.wgsl
+const WORKGROUP_SIZE: u32 = 64;
+
+/// Layout of the `DrawIndexedIndirect` arguments read by `draw_indexed_indirect`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct DrawIndexedIndirect {
+    index_count: u32,
+    instance_count: u32,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+
+/// Batch in cull.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct BatchData {
+    min: [f32; 3],
+    first_instance: u32,
+    max: [f32; 3],
+    instance_count: u32,
+}
+
+struct GpuBatch {
+    mesh: Handle<Mesh>,
+    /// The mesh the bounds and index count were last written for, kept so it
+    /// outlives the render pass it's drawn in
+    current_mesh: Option<Arc<Mesh>>,
+    mesh_version: u64,
+    material: MaterialId,
+    data: BatchData,
+}
+
+/// Frustum culling in a compute shader, for instance counts where culling on
+/// the CPU and uploading the result every frame gets too slow.
+///
+/// The instances are uploaded once. Every frame the visible ones are written
+/// to a compacted buffer, and their count to indirect draw arguments, so the
+/// CPU never knows how many instances are drawn. Levels of detail aren't
+/// selected, every instance is drawn with the most detailed one.
+pub struct GpuCulling {
+    shader: Shader,
+    pipeline_layout: wgpu::PipelineLayout,
+    pipeline: wgpu::ComputePipeline,
+    bind_group: wgpu::BindGroup,
+
+    frustum_buffer: wgpu::Buffer,
+    batch_buffer: wgpu::Buffer,
+    visible_buffer: wgpu::Buffer,
+    args_buffer: wgpu::Buffer,
+    /// Written to `args_buffer` before every dispatch to reset the counts
+    initial_args: Vec<DrawIndexedIndirect>,
+
+    batches: Vec<GpuBatch>,
+    instance_count: u32,
+}
+
+impl GpuCulling {
+    pub fn new(
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        batches: &[Batch],
+    ) -> Result<Self> {
+        let mut instances = Vec::<InstanceRaw>::new();
+        let mut instance_batches = Vec::<u32>::new();
+        let mut initial_args = Vec::new();
+        let mut gpu_batches = Vec::new();
+        for (i, batch) in batches.iter().enumerate() {
+            let first_instance = instances.len() as u32;
+            instances.extend(batch.instances.iter().map(Instance::to_raw));
+            instance_batches.extend(batch.instances.iter().map(|_| i as u32));
+
+            // The instance buffer is bound at the batch's first instance, as
+            // a non zero first_instance needs Features::INDIRECT_FIRST_INSTANCE.
+            // The index count and bounds are filled in by `update` once the
+            // mesh is loaded.
+            initial_args.push(DrawIndexedIndirect {
+                index_count: 0,
+                instance_count: 0,
+                first_index: 0,
+                base_vertex: 0,
+                first_instance: 0,
+            });
+            gpu_batches.push(GpuBatch {
+                mesh: batch.lods[0].mesh.clone(),
+                current_mesh: None,
+                mesh_version: 0,
+                material: batch.material,
+                data: BatchData {
+                    min: [0.0; 3],
+                    first_instance,
+                    max: [0.0; 3],
+                    instance_count: batch.instances.len() as u32,
+                },
+            });
+        }
+        if instances.is_empty() {
+            bail!("GPU culling needs at least one instance");
+        }
+
+        let frustum_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Culling frustum buffer"),
+            size: std::mem::size_of::<[[f32; 4]; 6]>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let batch_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Culling batch buffer"),
+            contents: bytemuck::cast_slice(
+                &gpu_batches
+                    .iter()
+                    .map(|batch| batch.data)
+                    .collect::<Vec<_>>(),
+            ),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Culling instance buffer"),
+            contents: bytemuck::cast_slice(&instances),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let instance_batch_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Culling instance batch buffer"),
+            contents: bytemuck::cast_slice(&instance_batches),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let visible_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Visible instance buffer"),
+            size: std::mem::size_of_val(instances.as_slice()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::VERTEX,
+            mapped_at_creation: false,
+        });
+        let args_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Culling draw args buffer"),
+            contents: bytemuck::cast_slice(&initial_args),
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::INDIRECT
+                | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let mut shader = Shader::new(
+            "shaders/cull.wgsl",
+            include_str!("../shaders/cull.wgsl"),
+            Preprocessor::new(),
+        );
+        let reflection = shader.reflect(assets, device, queue)?;
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Culling bind group layout"),
+            entries: &reflection.bind_groups[0],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Culling pipeline layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let module = shader.create_module(assets, device, queue)?;
+        let pipeline = create_compute_pipeline(device, &pipeline_layout, &module);
+
+        let buffers = [
+            &frustum_buffer,
+            &batch_buffer,
+            &instance_buffer,
+            &instance_batch_buffer,
+            &visible_buffer,
+            &args_buffer,
+        ];
+        let entries = buffers
+            .iter()
+            .enumerate()
+            .map(|(i, buffer)| wgpu::BindGroupEntry {
+                binding: i as u32,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect::<Vec<_>>();
+        let bind_group = capture_validation_errors(device, || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Culling bind group"),
+                layout: &layout,
+                entries: &entries,
+            })
+        })?;
+
+        Ok(Self {
+            shader,
+            pipeline_layout,
+            pipeline,
+            bind_group,
+            frustum_buffer,
+            batch_buffer,
+            visible_buffer,
+            args_buffer,
+            initial_args,
+            batches: gpu_batches,
+            instance_count: instances.len() as u32,
+        })
+    }
+
+    /// Uploads the frustum for the next dispatch, the bounds of meshes that
+    /// were (re)loaded and rebuilds the pipeline if the shader was edited
+    pub fn update(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        frustum: &Frustum,
+    ) {
+        if self.shader.changed() {
+            let result = self
+                .shader
+                .create_module(assets, device, queue)
+                .and_then(|module| {
+                    capture_validation_errors(device, || {
+                        create_compute_pipeline(device, &self.pipeline_layout, &module)
+                    })
+                });
+            match result {
+                Result::Ok(pipeline) => self.pipeline = pipeline,
+                Err(e) => log::error!("{:?}", e),
+            }
+        }
+
+        let batch_size = std::mem::size_of::<BatchData>() as wgpu::BufferAddress;
+        for (i, batch) in self.batches.iter_mut().enumerate() {
+            let mesh = match batch.mesh.get() {
+                Some(mesh) if batch.mesh.version() != batch.mesh_version => mesh,
+                _ => continue,
+            };
+            batch.mesh_version = batch.mesh.version();
+            batch.data.min = mesh.bounds.min.into();
+            batch.data.max = mesh.bounds.max.into();
+            self.initial_args[i].index_count = mesh.num_indices;
+            queue.write_buffer(
+                &self.batch_buffer,
+                i as wgpu::BufferAddress * batch_size,
+                bytemuck::bytes_of(&batch.data),
+            );
+            batch.current_mesh = Some(mesh);
+        }
+
+        let planes = frustum
+            .planes
+            .map(|plane| plane.normal.extend(plane.d).to_array());
+        queue.write_buffer(&self.frustum_buffer, 0, bytemuck::cast_slice(&planes));
+        queue.write_buffer(
+            &self.args_buffer,
+            0,
+            bytemuck::cast_slice(&self.initial_args),
+        );
+    }
+
+    /// Records the culling dispatch, which has to happen before [`GpuCulling::draw`]
+    pub fn cull(&self, encoder: &mut wgpu::CommandEncoder) {
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Culling Pass"),
+        });
+        compute_pass.set_pipeline(&self.pipeline);
+        compute_pass.set_bind_group(0, &self.bind_group, &[]);
+        compute_pass.dispatch(self.instance_count.div_ceil(WORKGROUP_SIZE), 1, 1);
+    }
+
+    /// Draws the visible instances of every batch, or only the depth of the
+    /// opaque and masked ones with `depth_only`. The camera bind group must
+    /// already be bound.
+    pub fn draw<'a>(
+        &'a self,
+        pipelines: &'a PipelineCache,
+        materials: &'a Materials,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        depth_only: bool,
+    ) {
+        let stride = std::mem::size_of::<InstanceRaw>() as wgpu::BufferAddress;
+        let args_stride = std::mem::size_of::<DrawIndexedIndirect>() as wgpu::BufferAddress;
+        for (i, batch) in self.batches.iter().enumerate() {
+            let mesh = match &batch.current_mesh {
+                Some(mesh) => mesh,
+                None => continue,
+            };
+            if !materials.bind(pipelines, render_pass, batch.material, depth_only) {
+                continue;
+            }
+            let offset = batch.data.first_instance as wgpu::BufferAddress * stride;
+            render_pass.set_vertex_buffer(1, self.visible_buffer.slice(offset..));
+            mesh.draw_indirect(render_pass, &self.args_buffer, i as u64 * args_stride);
+        }
+    }
+}
+
+fn create_compute_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    module: &wgpu::ShaderModule,
+) -> wgpu::ComputePipeline {
+    device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some("Culling Pipeline"),
+        layout: Some(layout),
+        module,
+        entry_point: "cs_main",
+    })
+}
@@ -0,0 +1,172 @@
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    assets::AssetManager,
+    fullscreen::FullscreenPass,
+    pipeline::PipelineCache,
+    preprocessor::Preprocessor,
+    shader::{capture_validation_errors, Shader},
+    texture::Texture,
+};
+
+/// Format the scene is rendered in before it's tonemapped
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Curve mapping HDR colors to the 0..1 range of the surface
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tonemapper {
+    Reinhard,
+    /// Fit of the ACES filmic curve
+    Aces,
+    /// John Hable's filmic curve
+    Uncharted2,
+}
+
+impl Tonemapper {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Reinhard => Self::Aces,
+            Self::Aces => Self::Uncharted2,
+            Self::Uncharted2 => Self::Reinhard,
+        }
+    }
+}
+
+/// Params in tonemap.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct TonemapParams {
+    exposure: f32,
+    tonemapper: u32,
+    passthrough: u32,
+    _padding: u32,
+}
+
+/// The [`HDR_FORMAT`] target the scene is rendered to, and the pass
+/// tonemapping it to the surface
+pub struct Hdr {
+    /// In stops, the scene is multiplied by 2^exposure before tonemapping
+    pub exposure: f32,
+    pub tonemapper: Tonemapper,
+    /// Copies the scene to the target without exposure or tonemapping, for
+    /// colors that show data like the debug views
+    pub passthrough: bool,
+
+    texture: Texture,
+    params_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    pass: FullscreenPass,
+}
+
+impl Hdr {
+    pub fn new(
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        config: &wgpu::SurfaceConfiguration,
+    ) -> Result<Self> {
+        let pass = FullscreenPass::new(
+            assets,
+            device,
+            queue,
+            pipelines,
+            Shader::new(
+                "shaders/tonemap.wgsl",
+                include_str!("../shaders/tonemap.wgsl"),
+                Preprocessor::new(),
+            ),
+            None,
+            None,
+        )?;
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Tonemap params buffer"),
+            contents: bytemuck::bytes_of(&TonemapParams {
+                exposure: 0.0,
+                tonemapper: 0,
+                passthrough: 0,
+                _padding: 0,
+            }),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let texture = Texture::create_render_target(device, config, HDR_FORMAT, "HDR texture");
+        let bind_group = create_bind_group(device, &pass, &texture, &params_buffer)?;
+
+        Ok(Self {
+            exposure: 0.0,
+            tonemapper: Tonemapper::Aces,
+            passthrough: false,
+            texture,
+            params_buffer,
+            bind_group,
+            pass,
+        })
+    }
+
+    /// Target to render the scene to
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.texture.view
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
+        let texture = Texture::create_render_target(device, config, HDR_FORMAT, "HDR texture");
+        match create_bind_group(device, &self.pass, &texture, &self.params_buffer) {
+            Result::Ok(bind_group) => self.bind_group = bind_group,
+            Err(e) => log::error!("{:?}", e),
+        }
+        self.texture = texture;
+    }
+
+    /// Uploads the exposure and tonemapper and rebuilds the pipeline if needed
+    pub fn update(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+    ) {
+        self.pass.update(assets, device, queue, pipelines);
+        let params = TonemapParams {
+            exposure: self.exposure,
+            tonemapper: self.tonemapper as u32,
+            passthrough: self.passthrough as u32,
+            _padding: 0,
+        };
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::bytes_of(&params));
+    }
+
+    /// Tonemaps the scene to `view`
+    pub fn render(
+        &self,
+        pipelines: &PipelineCache,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+    ) {
+        self.pass.draw(pipelines, encoder, view, &self.bind_group);
+    }
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    pass: &FullscreenPass,
+    texture: &Texture,
+    params_buffer: &wgpu::Buffer,
+) -> Result<wgpu::BindGroup> {
+    capture_validation_errors(device, || {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Tonemap bind group"),
+            layout: pass.layout(),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: params_buffer.as_entire_binding(),
+                },
+            ],
+        })
+    })
+}
@@ -1,19 +1,42 @@
 pub struct Instance {
     pub position: glam::Vec3,
     pub rotation: glam::Quat,
+    /// Layer of the texture array the instance is drawn with, for materials
+    /// whose shader samples one
+    pub layer: u32,
+}
+
+/// Per instance data in the instance buffer, matching `InstanceInput` in
+/// instance.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct InstanceRaw {
+    pub model: [[f32; 4]; 4],
+    pub layer: u32,
+    /// Keeps the size a multiple of 16 bytes like the struct in cull.wgsl
+    pub _padding: [u32; 3],
 }
 
 impl Instance {
-    const ATTRIBS: [wgpu::VertexAttribute; 4] =
-        wgpu::vertex_attr_array![5 => Float32x4, 6 => Float32x4, 7 => Float32x4, 8 => Float32x4];
+    const ATTRIBS: [wgpu::VertexAttribute; 5] = wgpu::vertex_attr_array![
+        5 => Float32x4, 6 => Float32x4, 7 => Float32x4, 8 => Float32x4, 9 => Uint32
+    ];
 
     pub fn to_matrix(&self) -> [[f32; 4]; 4] {
         glam::Mat4::from_rotation_translation(self.rotation, self.position).to_cols_array_2d()
     }
 
+    pub fn to_raw(&self) -> InstanceRaw {
+        InstanceRaw {
+            model: self.to_matrix(),
+            layer: self.layer,
+            _padding: [0; 3],
+        }
+    }
+
     pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         wgpu::VertexBufferLayout {
-            array_stride: std::mem::size_of::<[[f32; 4]; 4]>() as wgpu::BufferAddress,
+            array_stride: std::mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
             step_mode: wgpu::VertexStepMode::Instance,
             attributes: &Self::ATTRIBS,
         }
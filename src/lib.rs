@@ -1,7 +1,7 @@
 use std::f32::consts::PI;
 
+use deferred::RenderPath;
 use state::State;
-use vertex::Vertex;
 use winit::{
     dpi::{PhysicalPosition, PhysicalSize},
     event::{DeviceEvent, Event, WindowEvent},
@@ -9,36 +9,38 @@ use winit::{
     window::WindowBuilder,
 };
 
+mod assets;
+mod atlas;
+mod bloom;
 mod camera;
+mod clustering;
+mod culling;
+mod debug_view;
+mod deferred;
+mod environment;
+mod fullscreen;
+mod gizmos;
+mod gpu_culling;
+mod hdr;
 mod instance;
+mod light;
+mod lod;
+mod material;
+mod mesh;
+mod oit;
+pub mod pbr;
+mod pipeline;
+mod postprocess;
+mod preprocessor;
+mod reflect;
+mod scene;
+mod shader;
+mod ssao;
 mod state;
 mod texture;
+mod texture_array;
 mod vertex;
-
-const PENTAGON_VERTICES: &[Vertex] = &[
-    Vertex {
-        position: [-0.0868241, 0.49240386, 0.0],
-        tex_coords: [0.4131759, 0.00759614],
-    }, // A
-    Vertex {
-        position: [-0.49513406, 0.06958647, 0.0],
-        tex_coords: [0.0048659444, 0.43041354],
-    }, // B
-    Vertex {
-        position: [-0.21918549, -0.44939706, 0.0],
-        tex_coords: [0.28081453, 0.949397],
-    }, // C
-    Vertex {
-        position: [0.35966998, -0.3473291, 0.0],
-        tex_coords: [0.85967, 0.84732914],
-    }, // D
-    Vertex {
-        position: [0.44147372, 0.2347359, 0.0],
-        tex_coords: [0.9414737, 0.2652641],
-    }, // E
-];
-
-const PENTAGON_INDICES: &[u16] = &[0, 1, 4, 1, 2, 4, 2, 3, 4, 0];
+mod watcher;
 
 pub fn deg_to_rad(deg: f32) -> f32 {
     deg * PI / 180.0
@@ -54,7 +56,13 @@ pub async fn run() {
         .unwrap();
     window.set_cursor_visible(false);
 
-    let mut state = State::new(&window).await;
+    // The opaque scene is lit in a deferred pass when run with --deferred
+    let path = if std::env::args().any(|arg| arg == "--deferred") {
+        RenderPath::Deferred
+    } else {
+        RenderPath::Forward
+    };
+    let mut state = State::new(&window, path).await;
 
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
@@ -0,0 +1,92 @@
+use wgpu::util::DeviceExt;
+
+/// Point lights past this are ignored
+pub const MAX_POINT_LIGHTS: usize = 256;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PointLight {
+    pub position: glam::Vec3,
+    /// Linear RGB
+    pub color: glam::Vec3,
+    pub intensity: f32,
+    /// Distance at which the light has faded out completely
+    pub range: f32,
+}
+
+/// PointLight in lights.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct GpuPointLight {
+    position: [f32; 3],
+    range: f32,
+    color: [f32; 3],
+    intensity: f32,
+}
+
+/// Lights in lights.wgsl, without the point lights that follow it
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightsHeader {
+    ambient: [f32; 3],
+    count: u32,
+}
+
+/// The lights of the scene, uploaded to a storage buffer by
+/// [`Lights::update`] and read by lights.wgsl. Shaders get it through the bind
+/// group of [`crate::clustering::LightClusters`] or their own.
+pub struct Lights {
+    /// Scales the image based lighting of the environment
+    pub ambient: glam::Vec3,
+    pub point_lights: Vec<PointLight>,
+
+    buffer: wgpu::Buffer,
+}
+
+impl Lights {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let size = std::mem::size_of::<LightsHeader>()
+            + MAX_POINT_LIGHTS * std::mem::size_of::<GpuPointLight>();
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Lights buffer"),
+            contents: &vec![0; size],
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+        Self {
+            ambient: glam::Vec3::ZERO,
+            point_lights: Vec::new(),
+            buffer,
+        }
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    /// Uploads the ambient light and the first [`MAX_POINT_LIGHTS`] point
+    /// lights
+    pub fn update(&self, queue: &wgpu::Queue) {
+        let point_lights = self
+            .point_lights
+            .iter()
+            .take(MAX_POINT_LIGHTS)
+            .map(|light| GpuPointLight {
+                position: light.position.to_array(),
+                range: light.range,
+                color: light.color.to_array(),
+                intensity: light.intensity,
+            })
+            .collect::<Vec<_>>();
+        let header = LightsHeader {
+            ambient: self.ambient.to_array(),
+            count: point_lights.len() as u32,
+        };
+        queue.write_buffer(&self.buffer, 0, bytemuck::bytes_of(&header));
+        if !point_lights.is_empty() {
+            queue.write_buffer(
+                &self.buffer,
+                std::mem::size_of::<LightsHeader>() as wgpu::BufferAddress,
+                bytemuck::cast_slice(&point_lights),
+            );
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{assets::Handle, mesh::Mesh};
+
+/// One level of detail of a model
+pub struct Lod {
+    /// Resolved every frame, so a reloaded mesh is drawn from the next one on
+    pub mesh: Handle<Mesh>,
+    /// With [`LodMetric::Distance`] the distance up to which this level is used,
+    /// with [`LodMetric::ScreenSize`] the screen size down to which it's used.
+    /// The last level is used past the threshold of the one before it, so its
+    /// own threshold is ignored.
+    pub threshold: f32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LodMetric {
+    /// Distance between the camera and the center of the instance's bounds
+    Distance,
+    /// Fraction of the screen height covered by the instance's bounding sphere
+    ScreenSize,
+}
+
+impl LodMetric {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Distance => Self::ScreenSize,
+            Self::ScreenSize => Self::Distance,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LodSettings {
+    pub metric: LodMetric,
+    /// How far past a threshold, relative to it, an instance has to get before
+    /// switching levels, so instances near a threshold don't keep popping
+    /// between two levels
+    pub hysteresis: f32,
+}
+
+impl Default for LodSettings {
+    fn default() -> Self {
+        Self {
+            metric: LodMetric::Distance,
+            hysteresis: 0.1,
+        }
+    }
+}
+
+impl LodSettings {
+    /// Level to use for an instance currently using level `current`.
+    ///
+    /// `radius` is the radius of its bounding sphere, `distance` the distance to
+    /// the camera and `fovy` the camera's vertical field of view in radians.
+    pub fn select(
+        &self,
+        lods: &[Lod],
+        current: usize,
+        distance: f32,
+        radius: f32,
+        fovy: f32,
+    ) -> usize {
+        let value = match self.metric {
+            LodMetric::Distance => distance,
+            LodMetric::ScreenSize => radius / (distance * (fovy * 0.5).tan()).max(f32::EPSILON),
+        };
+        self.select_level(lods.len(), |i| lods[i].threshold, current, value)
+    }
+
+    /// Level out of `count` for the metric's `value`, with `threshold` giving
+    /// the threshold of every level
+    fn select_level(
+        &self,
+        count: usize,
+        threshold: impl Fn(usize) -> f32,
+        current: usize,
+        value: f32,
+    ) -> usize {
+        // Whether `value` is past the threshold of `level` towards the less
+        // detailed levels, by at least `margin` relative to the threshold
+        let coarser = |level: usize, margin: f32| match self.metric {
+            LodMetric::Distance => value > threshold(level) * (1.0 + margin),
+            LodMetric::ScreenSize => value < threshold(level) * (1.0 - margin),
+        };
+
+        let mut level = current.min(count.saturating_sub(1));
+        while level + 1 < count && coarser(level, self.hysteresis) {
+            level += 1;
+        }
+        while level > 0 && !coarser(level - 1, -self.hysteresis) {
+            level -= 1;
+        }
+        level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select(settings: &LodSettings, current: usize, value: f32) -> usize {
+        let thresholds = [10.0, 20.0, 0.0];
+        settings.select_level(thresholds.len(), |i| thresholds[i], current, value)
+    }
+
+    #[test]
+    fn selects_levels_by_distance() {
+        let settings = LodSettings::default();
+        assert_eq!(select(&settings, 0, 5.0), 0);
+        assert_eq!(select(&settings, 0, 15.0), 1);
+        assert_eq!(select(&settings, 0, 100.0), 2);
+        assert_eq!(select(&settings, 2, 5.0), 0);
+    }
+
+    #[test]
+    fn keeps_the_current_level_near_thresholds() {
+        let settings = LodSettings::default();
+        assert_eq!(select(&settings, 0, 10.5), 0);
+        assert_eq!(select(&settings, 0, 11.5), 1);
+        assert_eq!(select(&settings, 1, 9.5), 1);
+        assert_eq!(select(&settings, 1, 8.5), 0);
+    }
+
+    #[test]
+    fn selects_levels_by_screen_size() {
+        let settings = LodSettings {
+            metric: LodMetric::ScreenSize,
+            hysteresis: 0.1,
+        };
+        let thresholds = [0.5, 0.1, 0.0];
+        let select = |current, value| {
+            settings.select_level(thresholds.len(), |i| thresholds[i], current, value)
+        };
+        assert_eq!(select(0, 0.8), 0);
+        assert_eq!(select(0, 0.3), 1);
+        assert_eq!(select(0, 0.01), 2);
+        assert_eq!(select(0, 0.48), 0);
+        assert_eq!(select(1, 0.52), 1);
+        assert_eq!(select(1, 0.6), 0);
+    }
+}
@@ -0,0 +1,819 @@
+use std::{collections::HashMap, ops::Range, sync::Arc};
+
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    assets::{AssetManager, Handle},
+    camera::CameraUniform,
+    deferred,
+    hdr::HDR_FORMAT,
+    instance::Instance,
+    mesh::Mesh,
+    oit,
+    pipeline::{DepthState, ModuleId, PipelineCache, PipelineKey},
+    shader::{capture_validation_errors, Shader},
+    texture::Texture,
+    vertex::Vertex,
+};
+
+/// Bind group with the resources of the material being drawn
+pub const MATERIAL_GROUP: u32 = 0;
+/// Bind group with the camera uniform, shared by every material
+pub const CAMERA_GROUP: u32 = 1;
+/// Bind group with the lights, their clusters, the environment, the scene
+/// settings and the ambient occlusion, shared by every material
+pub const LIGHTS_GROUP: u32 = 2;
+
+/// Returned by [`Materials::add_shader`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ShaderId(usize);
+
+/// Returned by [`Materials::add`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MaterialId(usize);
+
+/// How a material's alpha is used
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum AlphaMode {
+    /// Alpha is ignored
+    Opaque,
+    /// Fragments below an alpha cutoff are discarded by the shader, which is
+    /// built with `ALPHA_MASK` defined for these materials. Drawn after opaque
+    /// materials, as discarding fragments disables early depth testing.
+    Mask,
+    /// Blended with what's behind it. Drawn in the transparent pass after
+    /// everything else, back to front, without writing depth. The shader is
+    /// built with `ALPHA_BLEND` defined, as the ambient occlusion is of the
+    /// opaque scene behind it.
+    Blend,
+}
+
+/// Fixed function state of the pipeline a material is drawn with
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PipelineState {
+    pub cull_mode: Option<wgpu::Face>,
+    /// Line requires Features::POLYGON_MODE_LINE and Point requires
+    /// Features::POLYGON_MODE_POINT
+    pub polygon_mode: wgpu::PolygonMode,
+    pub blend: Option<wgpu::BlendState>,
+    pub alpha_mode: AlphaMode,
+}
+
+impl PipelineState {
+    /// State for [`AlphaMode::Blend`] with regular alpha blending
+    pub fn blended() -> Self {
+        Self {
+            blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+            alpha_mode: AlphaMode::Blend,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for PipelineState {
+    fn default() -> Self {
+        Self {
+            cull_mode: Some(wgpu::Face::Back),
+            polygon_mode: wgpu::PolygonMode::Fill,
+            blend: Some(wgpu::BlendState::REPLACE),
+            alpha_mode: AlphaMode::Opaque,
+        }
+    }
+}
+
+/// What to draw something with.
+///
+/// The resources are bound to [`MATERIAL_GROUP`]: the uniform parameters at
+/// binding 0, then every texture's view and sampler at bindings `1 + 2 * i` and
+/// `2 + 2 * i`.
+pub struct Material {
+    pub shader: ShaderId,
+    pub state: PipelineState,
+    pub textures: Vec<Handle<Texture>>,
+    pub params: Option<Vec<u8>>,
+}
+
+impl Material {
+    pub fn new(shader: ShaderId) -> Self {
+        Self {
+            shader,
+            state: PipelineState::default(),
+            textures: Vec::new(),
+            params: None,
+        }
+    }
+
+    pub fn with_state(mut self, state: PipelineState) -> Self {
+        self.state = state;
+        self
+    }
+
+    pub fn with_texture(mut self, texture: Handle<Texture>) -> Self {
+        self.textures.push(texture);
+        self
+    }
+
+    pub fn with_params<T: bytemuck::Pod>(mut self, params: &T) -> Self {
+        self.params = Some(bytemuck::bytes_of(params).to_vec());
+        self
+    }
+}
+
+/// A mesh drawn with a material
+pub struct Draw {
+    pub mesh: Arc<Mesh>,
+    pub material: MaterialId,
+    /// Range of the instance buffer to draw
+    pub instances: Range<u32>,
+    /// View depth of the closest instance, blended draws are sorted by it
+    pub depth: f32,
+}
+
+/// Pass a permutation of a material shader is drawn in
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum Pass {
+    Forward,
+    /// The forward permutation without color writes, see
+    /// [`Materials::draw_depth`]
+    Depth,
+    /// `OIT` defined, see [`Materials::enable_oit`]
+    Oit,
+    /// `GBUFFER` defined, see [`Materials::enable_gbuffer`]
+    Gbuffer,
+}
+
+impl Pass {
+    fn define(self) -> Option<&'static str> {
+        match self {
+            Pass::Forward | Pass::Depth => None,
+            Pass::Oit => Some("OIT"),
+            Pass::Gbuffer => Some("GBUFFER"),
+        }
+    }
+
+    /// Pass whose permutation this pass is drawn with
+    fn permutation(self) -> Self {
+        match self {
+            Pass::Depth => Pass::Forward,
+            pass => pass,
+        }
+    }
+}
+
+impl AlphaMode {
+    fn define(self) -> Option<&'static str> {
+        match self {
+            AlphaMode::Opaque => None,
+            AlphaMode::Mask => Some("ALPHA_MASK"),
+            AlphaMode::Blend => Some("ALPHA_BLEND"),
+        }
+    }
+}
+
+struct ShaderEntry {
+    material_layout: wgpu::BindGroupLayout,
+    oit: bool,
+    gbuffer: bool,
+    /// Permutations built for the shader's materials so far, keyed by their
+    /// pass and the alpha mode they define. The forward one of opaque
+    /// materials is the shader passed to [`Materials::add_shader`].
+    permutations: HashMap<(Pass, AlphaMode), (Shader, ModuleId)>,
+}
+
+struct MaterialEntry {
+    material: Material,
+    /// Materials with the same variant are drawn with the same pipeline
+    variant: usize,
+    params_buffer: Option<wgpu::Buffer>,
+    /// `None` until every texture is loaded
+    bind_group: Option<wgpu::BindGroup>,
+    texture_versions: Vec<u64>,
+}
+
+/// Owns every material along with the shaders they're drawn with. Their
+/// pipelines live in a [`PipelineCache`], so materials that share a shader and
+/// pipeline state share one pipeline.
+pub struct Materials {
+    camera_layout: wgpu::BindGroupLayout,
+    lights_layout: wgpu::BindGroupLayout,
+
+    shaders: Vec<ShaderEntry>,
+    variants: HashMap<(ShaderId, PipelineState), usize>,
+    materials: Vec<MaterialEntry>,
+}
+
+impl Materials {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let camera_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Camera bind group layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                count: None,
+                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+            }],
+        });
+
+        // The lights, cluster params, clusters and their light indices of
+        // lights.wgsl and clusters.wgsl, then the image based lighting of
+        // lights.wgsl, the scene settings of fog.wgsl and the ambient
+        // occlusion of lights.wgsl
+        let buffer = |binding, ty| wgpu::BindGroupLayoutEntry {
+            binding,
+            count: None,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+        };
+        let texture = |binding, view_dimension| wgpu::BindGroupLayoutEntry {
+            binding,
+            count: None,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension,
+                multisampled: false,
+            },
+        };
+        let storage = wgpu::BufferBindingType::Storage { read_only: true };
+        let lights_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Lights bind group layout"),
+            entries: &[
+                buffer(0, storage),
+                buffer(1, wgpu::BufferBindingType::Uniform),
+                buffer(2, storage),
+                buffer(3, storage),
+                texture(4, wgpu::TextureViewDimension::Cube),
+                texture(5, wgpu::TextureViewDimension::Cube),
+                texture(6, wgpu::TextureViewDimension::D2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    count: None,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                },
+                buffer(8, wgpu::BufferBindingType::Uniform),
+                texture(9, wgpu::TextureViewDimension::D2),
+            ],
+        });
+
+        Self {
+            camera_layout,
+            lights_layout,
+            shaders: Vec::new(),
+            variants: HashMap::new(),
+            materials: Vec::new(),
+        }
+    }
+
+    /// Layout of the bind group that must be bound at [`CAMERA_GROUP`]
+    pub fn camera_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.camera_layout
+    }
+
+    /// Layout of the bind group that must be bound at [`LIGHTS_GROUP`]
+    pub fn lights_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.lights_layout
+    }
+
+    pub fn add_shader(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        mut shader: Shader,
+    ) -> Result<ShaderId> {
+        let reflection = shader.reflect(assets, device, queue)?;
+        reflection.check_vertex_buffers(&vertex_buffers())?;
+        if reflection.bind_groups.len() > LIGHTS_GROUP as usize + 1 {
+            bail!("Material shaders can only use the material, camera and lights bind groups");
+        }
+        if reflection.bind_groups.len() > CAMERA_GROUP as usize {
+            reflection.check_buffer_size(
+                CAMERA_GROUP,
+                0,
+                std::mem::size_of::<CameraUniform>() as wgpu::BufferAddress,
+            )?;
+        }
+
+        let module = shader.create_module(assets, device, queue)?;
+        let material_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Material bind group layout"),
+            entries: reflection
+                .bind_groups
+                .get(MATERIAL_GROUP as usize)
+                .map_or(&[], Vec::as_slice),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Material pipeline layout"),
+            bind_group_layouts: &[&material_layout, &self.camera_layout, &self.lights_layout],
+            push_constant_ranges: &[],
+        });
+
+        let module = pipelines.add_module("Material Pipeline", module, pipeline_layout);
+
+        self.shaders.push(ShaderEntry {
+            material_layout,
+            oit: false,
+            gbuffer: false,
+            permutations: HashMap::from([((Pass::Forward, AlphaMode::Opaque), (shader, module))]),
+        });
+        Ok(ShaderId(self.shaders.len() - 1))
+    }
+
+    /// Builds the permutation of a shader with `OIT` defined, which writes to
+    /// [`oit::targets`] instead of the surface. Blended materials can only be
+    /// drawn with [`Materials::draw_oit`] once their shader has it.
+    pub fn enable_oit(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        id: ShaderId,
+    ) -> Result<()> {
+        self.shaders[id.0].oit = true;
+        self.create_pipelines(assets, device, queue, pipelines, id)
+    }
+
+    /// Builds the permutation of a shader with `GBUFFER` defined, which writes
+    /// to [`deferred::targets`] instead of being lit. Opaque and masked
+    /// materials are only drawn by [`Materials::draw_gbuffer`] once their shader
+    /// has it.
+    pub fn enable_gbuffer(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        id: ShaderId,
+    ) -> Result<()> {
+        self.shaders[id.0].gbuffer = true;
+        self.create_pipelines(assets, device, queue, pipelines, id)
+    }
+
+    /// Builds the permutation of a shader for a pass unless it already exists
+    fn build_permutation(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        id: ShaderId,
+        (pass, alpha_mode): (Pass, AlphaMode),
+    ) -> Result<()> {
+        let entry = &self.shaders[id.0];
+        if entry.permutations.contains_key(&(pass, alpha_mode)) {
+            return Ok(());
+        }
+        let base = &entry.permutations[&(Pass::Forward, AlphaMode::Opaque)].0;
+        let defines = pass
+            .define()
+            .into_iter()
+            .chain(alpha_mode.define())
+            .collect::<Vec<_>>();
+        let mut shader = base.with_define(defines[0], "");
+        for define in &defines[1..] {
+            shader = shader.with_define(define, "");
+        }
+        let module = shader.create_module(assets, device, queue)?;
+        let label = format!("Material {} Pipeline", defines.join(" "));
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(&label),
+            bind_group_layouts: &[
+                &entry.material_layout,
+                &self.camera_layout,
+                &self.lights_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        let module = pipelines.add_module(&label, module, pipeline_layout);
+        self.shaders[id.0]
+            .permutations
+            .insert((pass, alpha_mode), (shader, module));
+        Ok(())
+    }
+
+    /// Passes a material is drawn in with its shader's current permutations
+    fn passes(&self, material: &Material) -> Vec<Pass> {
+        let entry = &self.shaders[material.shader.0];
+        let blended = material.state.alpha_mode == AlphaMode::Blend;
+        let mut passes = vec![Pass::Forward];
+        if !blended {
+            passes.push(Pass::Depth);
+        }
+        if entry.oit && blended {
+            passes.push(Pass::Oit);
+        }
+        if entry.gbuffer && !blended {
+            passes.push(Pass::Gbuffer);
+        }
+        passes
+    }
+
+    /// Builds the permutations and pipelines a material needs
+    fn prepare(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        material: &Material,
+    ) -> Result<()> {
+        for pass in self.passes(material) {
+            self.build_permutation(
+                assets,
+                device,
+                queue,
+                pipelines,
+                material.shader,
+                (pass.permutation(), material.state.alpha_mode),
+            )?;
+            if let Some(key) = self.pass_pipeline_key(material, pass) {
+                pipelines.get_or_create(device, &key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Prepares every material of a shader after it got another pass
+    fn create_pipelines(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        id: ShaderId,
+    ) -> Result<()> {
+        let materials = std::mem::take(&mut self.materials);
+        let result = materials
+            .iter()
+            .filter(|entry| entry.material.shader == id)
+            .try_for_each(|entry| self.prepare(assets, device, queue, pipelines, &entry.material));
+        self.materials = materials;
+        result
+    }
+
+    /// Masked materials get a permutation of their shader with `ALPHA_MASK`
+    /// defined
+    pub fn add(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        material: Material,
+    ) -> Result<MaterialId> {
+        self.prepare(assets, device, queue, pipelines, &material)?;
+        let variants = self.variants.len();
+        let variant = *self
+            .variants
+            .entry((material.shader, material.state))
+            .or_insert(variants);
+        let params_buffer = material.params.as_ref().map(|params| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Material params buffer"),
+                contents: params,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            })
+        });
+
+        let mut entry = MaterialEntry {
+            material,
+            variant,
+            params_buffer,
+            bind_group: None,
+            texture_versions: Vec::new(),
+        };
+        entry.bind_group = self.create_bind_group(device, &entry)?;
+        entry.texture_versions = entry
+            .material
+            .textures
+            .iter()
+            .map(Handle::version)
+            .collect();
+
+        self.materials.push(entry);
+        Ok(MaterialId(self.materials.len() - 1))
+    }
+
+    /// Overwrites the uniform parameters of a material
+    pub fn set_params<T: bytemuck::Pod>(&self, queue: &wgpu::Queue, id: MaterialId, params: &T) {
+        match &self.materials[id.0].params_buffer {
+            Some(buffer) => queue.write_buffer(buffer, 0, bytemuck::bytes_of(params)),
+            None => log::warn!("Material {:?} has no parameters", id),
+        }
+    }
+
+    /// Rebuilds pipelines of reloaded shaders and bind groups of reloaded
+    /// textures. Anything that fails to rebuild keeps its previous version.
+    ///
+    /// Also recreates pipelines that were evicted from the cache, e.g. after the
+    /// surface format changed.
+    pub fn update(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+    ) {
+        for (i, entry) in self.shaders.iter_mut().enumerate() {
+            for (shader, module) in entry.permutations.values_mut() {
+                if shader.changed() {
+                    let result = shader
+                        .create_module(assets, device, queue)
+                        .and_then(|new| pipelines.replace_module(device, *module, new));
+                    match result {
+                        Result::Ok(()) => log::info!("Rebuilt the pipelines of shader {}", i),
+                        Err(e) => log::error!("{:?}", e),
+                    }
+                }
+            }
+        }
+
+        for entry in &self.materials {
+            let keys = self
+                .passes(&entry.material)
+                .into_iter()
+                .filter_map(|pass| self.pass_pipeline_key(&entry.material, pass));
+            for key in keys {
+                if pipelines.get(&key).is_none() {
+                    if let Err(e) = pipelines.get_or_create(device, &key) {
+                        log::error!("{:?}", e);
+                    }
+                }
+            }
+        }
+
+        for i in 0..self.materials.len() {
+            let entry = &self.materials[i];
+            let versions = entry
+                .material
+                .textures
+                .iter()
+                .map(Handle::version)
+                .collect::<Vec<_>>();
+            if versions == entry.texture_versions {
+                continue;
+            }
+
+            match self.create_bind_group(device, entry) {
+                Result::Ok(bind_group) => self.materials[i].bind_group = bind_group,
+                Err(e) => log::error!("{:?}", e),
+            }
+            self.materials[i].texture_versions = versions;
+        }
+    }
+
+    pub fn alpha_mode(&self, id: MaterialId) -> AlphaMode {
+        self.materials[id.0].material.state.alpha_mode
+    }
+
+    /// Orders draws by alpha mode, then to minimize pipeline and bind group
+    /// changes. Blended ones are ordered back to front instead with
+    /// [`oit::Transparency::Sorted`].
+    pub fn sort(&self, draws: &mut [Draw], transparency: oit::Transparency) {
+        draws.sort_by(|a, b| {
+            let (a_entry, b_entry) = (&self.materials[a.material.0], &self.materials[b.material.0]);
+            let alpha_mode = a_entry.material.state.alpha_mode;
+            alpha_mode
+                .cmp(&b_entry.material.state.alpha_mode)
+                .then_with(|| match alpha_mode {
+                    AlphaMode::Blend if transparency == oit::Transparency::Sorted => {
+                        b.depth.total_cmp(&a.depth)
+                    }
+                    _ => (a_entry.variant, a.material).cmp(&(b_entry.variant, b.material)),
+                })
+        });
+    }
+
+    /// Index of the first blended draw in draws ordered by [`Materials::sort`],
+    /// the draws from there on belong in the transparent pass
+    pub fn transparent_start(&self, draws: &[Draw]) -> usize {
+        draws.partition_point(|draw| self.alpha_mode(draw.material) != AlphaMode::Blend)
+    }
+
+    /// Records the draws, which should already be sorted. The camera bind group
+    /// and instance buffer must already be bound.
+    pub fn draw<'a>(
+        &'a self,
+        pipelines: &'a PipelineCache,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        draws: &'a [Draw],
+    ) {
+        self.draw_with(pipelines, render_pass, draws, |material| {
+            self.pass_pipeline_key(material, Pass::Forward)
+        });
+    }
+
+    /// Records opaque and masked draws without writing color, so their depth
+    /// is known before they're shaded. The pass needs an [`HDR_FORMAT`] color
+    /// target like the forward one, the camera and lights bind groups and the
+    /// instance buffer must already be bound.
+    pub fn draw_depth<'a>(
+        &'a self,
+        pipelines: &'a PipelineCache,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        draws: &'a [Draw],
+    ) {
+        self.draw_with(pipelines, render_pass, draws, |material| {
+            self.pass_pipeline_key(material, Pass::Depth)
+        });
+    }
+
+    /// Records blended draws into a pass started by
+    /// [`oit::Oit::accumulation_pass`], skipping materials whose shader doesn't
+    /// have [`Materials::enable_oit`]. They don't need to be sorted.
+    pub fn draw_oit<'a>(
+        &'a self,
+        pipelines: &'a PipelineCache,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        draws: &'a [Draw],
+    ) {
+        self.draw_with(pipelines, render_pass, draws, |material| {
+            self.pass_pipeline_key(material, Pass::Oit)
+        });
+    }
+
+    /// Records opaque and masked draws into a pass started by
+    /// [`deferred::Deferred::gbuffer_pass`], skipping materials whose shader
+    /// doesn't have [`Materials::enable_gbuffer`]
+    pub fn draw_gbuffer<'a>(
+        &'a self,
+        pipelines: &'a PipelineCache,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        draws: &'a [Draw],
+    ) {
+        self.draw_with(pipelines, render_pass, draws, |material| {
+            self.pass_pipeline_key(material, Pass::Gbuffer)
+        });
+    }
+
+    fn draw_with<'a>(
+        &'a self,
+        pipelines: &'a PipelineCache,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        draws: &'a [Draw],
+        key: impl Fn(&Material) -> Option<PipelineKey>,
+    ) {
+        let mut current_variant = None;
+        let mut current_material = None;
+        for draw in draws {
+            let entry = &self.materials[draw.material.0];
+            let bind_group = match &entry.bind_group {
+                Some(bind_group) => bind_group,
+                None => continue,
+            };
+
+            if current_variant != Some(entry.variant) {
+                match key(&entry.material).and_then(|key| pipelines.get(&key)) {
+                    Some(pipeline) => render_pass.set_pipeline(pipeline),
+                    None => continue,
+                }
+                current_variant = Some(entry.variant);
+            }
+            if current_material != Some(draw.material) {
+                render_pass.set_bind_group(MATERIAL_GROUP, bind_group, &[]);
+                current_material = Some(draw.material);
+            }
+            draw.mesh.draw(render_pass, draw.instances.clone());
+        }
+    }
+
+    /// Sets the pipeline and bind group of a material for draws recorded
+    /// outside of [`Materials::draw`], or [`Materials::draw_depth`] with
+    /// `depth_only`. Returns false if the material can't be drawn yet, or
+    /// doesn't write depth.
+    pub fn bind<'a>(
+        &'a self,
+        pipelines: &'a PipelineCache,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        material: MaterialId,
+        depth_only: bool,
+    ) -> bool {
+        let entry = &self.materials[material.0];
+        let pass = if depth_only {
+            Pass::Depth
+        } else {
+            Pass::Forward
+        };
+        let pipeline = self
+            .pass_pipeline_key(&entry.material, pass)
+            .and_then(|key| pipelines.get(&key));
+        match (pipeline, &entry.bind_group) {
+            (Some(pipeline), Some(bind_group)) => {
+                render_pass.set_pipeline(pipeline);
+                render_pass.set_bind_group(MATERIAL_GROUP, bind_group, &[]);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Key of the pipeline drawing a material in a pass, if its shader has the
+    /// permutation for it. Only blended materials are drawn to [`oit::targets`]
+    /// and only opaque and masked ones to [`deferred::targets`].
+    fn pass_pipeline_key(&self, material: &Material, pass: Pass) -> Option<PipelineKey> {
+        let blended = material.state.alpha_mode == AlphaMode::Blend;
+        let targets = match pass {
+            Pass::Forward => vec![wgpu::ColorTargetState {
+                format: HDR_FORMAT,
+                blend: material.state.blend,
+                write_mask: wgpu::ColorWrites::ALL,
+            }],
+            Pass::Depth if !blended => vec![wgpu::ColorTargetState {
+                format: HDR_FORMAT,
+                blend: None,
+                write_mask: wgpu::ColorWrites::empty(),
+            }],
+            Pass::Oit if blended => oit::targets(),
+            Pass::Gbuffer if !blended => deferred::targets(),
+            _ => return None,
+        };
+        let (_, module) = self.shaders[material.shader.0]
+            .permutations
+            .get(&(pass.permutation(), material.state.alpha_mode))?;
+
+        Some(PipelineKey {
+            module: *module,
+            vertex_buffers: vertex_buffers().to_vec(),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: material.state.cull_mode,
+                polygon_mode: material.state.polygon_mode,
+                ..Default::default()
+            },
+            // Equal depths pass, so the forward pass can test against the
+            // depth prepass
+            depth: Some(DepthState {
+                format: Texture::DEPTH_FORMAT,
+                write_enabled: !blended,
+                compare: wgpu::CompareFunction::LessEqual,
+            }),
+            targets,
+            multisample: wgpu::MultisampleState::default(),
+        })
+    }
+
+    /// Returns `None` if some of the textures aren't loaded yet
+    fn create_bind_group(
+        &self,
+        device: &wgpu::Device,
+        entry: &MaterialEntry,
+    ) -> Result<Option<wgpu::BindGroup>> {
+        let textures = match entry
+            .material
+            .textures
+            .iter()
+            .map(Handle::get)
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(textures) => textures,
+            None => return Ok(None),
+        };
+
+        let mut entries = Vec::new();
+        if let Some(buffer) = &entry.params_buffer {
+            entries.push(wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            });
+        }
+        for (i, texture) in textures.iter().enumerate() {
+            entries.push(wgpu::BindGroupEntry {
+                binding: 1 + 2 * i as u32,
+                resource: wgpu::BindingResource::TextureView(&texture.view),
+            });
+            entries.push(wgpu::BindGroupEntry {
+                binding: 2 + 2 * i as u32,
+                resource: wgpu::BindingResource::Sampler(&texture.sampler),
+            });
+        }
+
+        let layout = &self.shaders[entry.material.shader.0].material_layout;
+        let bind_group = capture_validation_errors(device, || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Material bind group"),
+                layout,
+                entries: &entries,
+            })
+        })
+        .context("Material resources don't match its shader")?;
+        Ok(Some(bind_group))
+    }
+}
+
+/// Vertex buffers every material is drawn with
+fn vertex_buffers() -> [wgpu::VertexBufferLayout<'static>; 2] {
+    [Vertex::desc(), Instance::desc()]
+}
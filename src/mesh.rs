@@ -0,0 +1,360 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::{culling::Aabb, vertex::Vertex};
+
+/// CPU side mesh data, before it's uploaded to the GPU
+pub struct MeshData {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+}
+
+impl MeshData {
+    /// Loads every model in an OBJ file into a single mesh
+    pub fn load_obj(path: &Path) -> Result<Self> {
+        let (models, _) = tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS)?;
+
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for model in models {
+            let mesh = model.mesh;
+            let base_index = vertices.len() as u32;
+            vertices.extend((0..mesh.positions.len() / 3).map(|i| Vertex {
+                position: [
+                    mesh.positions[i * 3],
+                    mesh.positions[i * 3 + 1],
+                    mesh.positions[i * 3 + 2],
+                ],
+                // OBJ texture coordinates have the origin in the bottom left corner
+                tex_coords: match mesh.texcoords.get(i * 2..i * 2 + 2) {
+                    Some(uv) => [uv[0], 1.0 - uv[1]],
+                    None => [0.0, 0.0],
+                },
+                normal: match mesh.normals.get(i * 3..i * 3 + 3) {
+                    Some(normal) => [normal[0], normal[1], normal[2]],
+                    None => [0.0, 0.0, 0.0],
+                },
+                tangent: [0.0; 3],
+                bitangent: [0.0; 3],
+            }));
+            indices.extend(mesh.indices.iter().map(|index| index + base_index));
+        }
+
+        if indices.is_empty() {
+            bail!("{} contains no faces", path.display());
+        }
+        Ok(Self::new(vertices, indices))
+    }
+
+    /// Generates the tangents of the vertices, overwriting the ones they have
+    pub fn new(vertices: Vec<Vertex>, indices: Vec<u32>) -> Self {
+        let mut data = Self { vertices, indices };
+        data.generate_tangents();
+        data
+    }
+
+    /// Computes MikkTSpace tangents, the same ones the tools baking normal
+    /// maps use. The tangent points in the direction u increases in and the
+    /// bitangent in the direction v decreases in, which is towards the top of
+    /// the texture.
+    ///
+    /// The bitangent is `cross(normal, tangent)`, negated where the texture is
+    /// mirrored, like glTF's `cross(normal, tangent.xyz) * tangent.w`.
+    /// Vertices whose triangles disagree on the frame, like the ones on the
+    /// line a texture is mirrored at, are split into a vertex per frame.
+    /// Vertices without texture coordinates get an arbitrary frame around the
+    /// normal, and vertices no triangle uses are dropped.
+    pub fn generate_tangents(&mut self) {
+        let mut geometry = TangentSpace {
+            data: self,
+            tangents: vec![[0.0; 4]; self.indices.len()],
+        };
+        // Only fails if there are no triangles, when there's nothing to do
+        bevy_mikktspace::generate_tangents(&mut geometry);
+        let tangents = geometry.tangents;
+
+        let mut vertices = Vec::with_capacity(self.vertices.len());
+        // Index of the split vertex for every vertex and frame
+        let mut split = HashMap::new();
+        for (index, tangent) in self.indices.iter_mut().zip(tangents) {
+            let mut vertex = self.vertices[*index as usize];
+            let normal = glam::Vec3::from(vertex.normal).normalize_or_zero();
+            let mut sign = tangent[3];
+            let mut tangent = glam::Vec3::new(tangent[0], tangent[1], tangent[2]);
+            tangent = (tangent - normal * normal.dot(tangent)).normalize_or_zero();
+            if !tangent.is_finite() || tangent == glam::Vec3::ZERO {
+                // Any frame works where there are no texture coordinates
+                tangent = if normal == glam::Vec3::ZERO {
+                    glam::Vec3::ZERO
+                } else {
+                    normal.any_orthonormal_vector()
+                };
+                sign = 1.0;
+            }
+            vertex.tangent = tangent.to_array();
+            vertex.bitangent = (normal.cross(tangent) * sign).to_array();
+
+            let key = (
+                *index,
+                vertex.tangent.map(f32::to_bits),
+                vertex.bitangent.map(f32::to_bits),
+            );
+            *index = *split.entry(key).or_insert_with(|| {
+                vertices.push(vertex);
+                vertices.len() as u32 - 1
+            });
+        }
+        self.vertices = vertices;
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        Aabb::from_points(self.vertices.iter().map(|v| glam::Vec3::from(v.position)))
+            .unwrap_or_else(|| Aabb::new(glam::Vec3::ZERO, glam::Vec3::ZERO))
+    }
+}
+
+/// The triangles of a mesh as MikkTSpace sees them, collecting the tangent it
+/// computes for every corner
+struct TangentSpace<'a> {
+    data: &'a MeshData,
+    /// Tangent and bitangent sign of every index
+    tangents: Vec<[f32; 4]>,
+}
+
+impl TangentSpace<'_> {
+    fn vertex(&self, face: usize, vert: usize) -> &Vertex {
+        &self.data.vertices[self.data.indices[face * 3 + vert] as usize]
+    }
+}
+
+impl bevy_mikktspace::Geometry for TangentSpace<'_> {
+    fn num_faces(&self) -> usize {
+        self.data.indices.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).position
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).normal
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        // MikkTSpace expects v to point up, like the tools using it
+        let [u, v] = self.vertex(face, vert).tex_coords;
+        [u, 1.0 - v]
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.tangents[face * 3 + vert] = tangent;
+    }
+}
+
+pub struct Mesh {
+    pub vertex_buffer: wgpu::Buffer,
+    pub index_buffer: wgpu::Buffer,
+    pub num_indices: u32,
+    pub bounds: Aabb,
+}
+
+impl Mesh {
+    pub fn new(device: &wgpu::Device, data: &MeshData, label: Option<&str>) -> Self {
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label,
+            contents: bytemuck::cast_slice(&data.vertices),
+            // Also read as storage buffers by the wireframe debug view when
+            // PolygonMode::Line isn't supported
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label,
+            contents: bytemuck::cast_slice(&data.indices),
+            usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::STORAGE,
+        });
+
+        Self {
+            vertex_buffer,
+            index_buffer,
+            num_indices: data.indices.len() as u32,
+            bounds: data.bounds(),
+        }
+    }
+
+    pub fn draw<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        instances: std::ops::Range<u32>,
+    ) {
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        render_pass.draw_indexed(0..self.num_indices, 0, instances);
+    }
+
+    /// Draws with the arguments of a `DrawIndexedIndirect` at `offset` in
+    /// `indirect_buffer`
+    pub fn draw_indirect<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        indirect_buffer: &'a wgpu::Buffer,
+        offset: wgpu::BufferAddress,
+    ) {
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        render_pass.draw_indexed_indirect(indirect_buffer, offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(position: [f32; 3], tex_coords: [f32; 2]) -> Vertex {
+        Vertex {
+            position,
+            tex_coords,
+            normal: [0.0, 0.0, 1.0],
+            tangent: [0.0; 3],
+            bitangent: [0.0; 3],
+        }
+    }
+
+    #[test]
+    fn loads_obj_files() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("pentagon.obj");
+        let pentagon = MeshData::load_obj(&path).unwrap();
+        assert_eq!(pentagon.vertices.len(), 5);
+        assert_eq!(pentagon.indices, [0, 1, 2, 1, 3, 2, 3, 4, 2]);
+        // Flipped so the origin is in the top left corner
+        let tex_coords = glam::Vec2::from(pentagon.vertices[0].tex_coords);
+        assert!(tex_coords.abs_diff_eq(glam::vec2(0.4131759, 0.00759614), 1e-5));
+    }
+
+    #[test]
+    fn tangents_follow_the_texture_coordinates() {
+        // A quad facing +z, with v increasing downwards like in an image
+        let quad = MeshData::new(
+            vec![
+                vertex([0.0, 0.0, 0.0], [0.0, 1.0]),
+                vertex([1.0, 0.0, 0.0], [1.0, 1.0]),
+                vertex([1.0, 1.0, 0.0], [1.0, 0.0]),
+                vertex([0.0, 1.0, 0.0], [0.0, 0.0]),
+            ],
+            vec![0, 1, 2, 0, 2, 3],
+        );
+        for vertex in &quad.vertices {
+            assert!(glam::Vec3::from(vertex.tangent).abs_diff_eq(glam::Vec3::X, 1e-5));
+            // Towards the top of the texture
+            assert!(glam::Vec3::from(vertex.bitangent).abs_diff_eq(glam::Vec3::Y, 1e-5));
+        }
+    }
+
+    /// Normal from a normal map texel, decoded like pbr.wgsl does
+    fn apply_normal_map(vertex: &Vertex, texel: [u8; 3]) -> glam::Vec3 {
+        let sample = glam::Vec3::from(texel.map(|c| c as f32 / 255.0)) * 2.0 - glam::Vec3::ONE;
+        let tbn = glam::Mat3::from_cols(
+            vertex.tangent.into(),
+            vertex.bitangent.into(),
+            vertex.normal.into(),
+        );
+        (tbn * sample).normalize()
+    }
+
+    #[test]
+    fn normal_maps_tilt_towards_the_top_right_of_the_texture() {
+        // The top of the texture is at +y
+        let quad = MeshData::new(
+            vec![
+                vertex([0.0, 0.0, 0.0], [0.0, 1.0]),
+                vertex([1.0, 0.0, 0.0], [1.0, 1.0]),
+                vertex([1.0, 1.0, 0.0], [1.0, 0.0]),
+                vertex([0.0, 1.0, 0.0], [0.0, 0.0]),
+            ],
+            vec![0, 1, 2, 0, 2, 3],
+        );
+        // Pointing up in a glTF normal map, with green up
+        let up = apply_normal_map(&quad.vertices[0], [128, 218, 218]);
+        assert!(up.y > 0.6 && up.x.abs() < 0.01);
+        let right = apply_normal_map(&quad.vertices[0], [218, 128, 218]);
+        assert!(right.x > 0.6 && right.y.abs() < 0.01);
+    }
+
+    #[test]
+    fn mirrored_textures_flip_the_tangent_but_not_the_bitangent() {
+        // u increases to the left
+        let quad = MeshData::new(
+            vec![
+                vertex([0.0, 0.0, 0.0], [1.0, 1.0]),
+                vertex([1.0, 0.0, 0.0], [0.0, 1.0]),
+                vertex([1.0, 1.0, 0.0], [0.0, 0.0]),
+                vertex([0.0, 1.0, 0.0], [1.0, 0.0]),
+            ],
+            vec![0, 1, 2, 0, 2, 3],
+        );
+        for vertex in &quad.vertices {
+            assert!(glam::Vec3::from(vertex.tangent).abs_diff_eq(-glam::Vec3::X, 1e-5));
+            assert!(glam::Vec3::from(vertex.bitangent).abs_diff_eq(glam::Vec3::Y, 1e-5));
+        }
+        // Still tilts towards the top right of the texture, which is at -x
+        let right = apply_normal_map(&quad.vertices[0], [218, 128, 218]);
+        assert!(right.x < -0.6);
+    }
+
+    #[test]
+    fn vertices_on_uv_seams_are_split() {
+        // Two quads facing +z sharing the edge at x = 1, where the texture is
+        // mirrored so u increases towards it from both sides
+        let quads = MeshData::new(
+            vec![
+                vertex([0.0, 0.0, 0.0], [0.0, 1.0]),
+                vertex([1.0, 0.0, 0.0], [1.0, 1.0]),
+                vertex([2.0, 0.0, 0.0], [0.0, 1.0]),
+                vertex([0.0, 1.0, 0.0], [0.0, 0.0]),
+                vertex([1.0, 1.0, 0.0], [1.0, 0.0]),
+                vertex([2.0, 1.0, 0.0], [0.0, 0.0]),
+            ],
+            vec![0, 1, 4, 0, 4, 3, 1, 2, 5, 1, 5, 4],
+        );
+        // Both vertices on the seam get one copy per side
+        assert_eq!(quads.vertices.len(), 8);
+        for (triangle, expected) in quads.indices.chunks_exact(3).zip([
+            glam::Vec3::X,
+            glam::Vec3::X,
+            -glam::Vec3::X,
+            -glam::Vec3::X,
+        ]) {
+            for &index in triangle {
+                let vertex = &quads.vertices[index as usize];
+                assert!(glam::Vec3::from(vertex.tangent).abs_diff_eq(expected, 1e-5));
+                assert!(glam::Vec3::from(vertex.bitangent).abs_diff_eq(glam::Vec3::Y, 1e-5));
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_texture_coordinates_get_an_orthonormal_frame() {
+        let triangle = MeshData::new(
+            vec![
+                vertex([0.0, 0.0, 0.0], [0.5, 0.5]),
+                vertex([1.0, 0.0, 0.0], [0.5, 0.5]),
+                vertex([0.0, 1.0, 0.0], [0.5, 0.5]),
+            ],
+            vec![0, 1, 2],
+        );
+        for vertex in &triangle.vertices {
+            let normal = glam::Vec3::from(vertex.normal);
+            let tangent = glam::Vec3::from(vertex.tangent);
+            let bitangent = glam::Vec3::from(vertex.bitangent);
+            assert!(tangent.is_normalized() && bitangent.is_normalized());
+            assert!(tangent.dot(normal).abs() < 1e-5);
+            assert!(bitangent.dot(normal).abs() < 1e-5);
+            assert!(tangent.dot(bitangent).abs() < 1e-5);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use anyhow::*;
+
+use crate::{
+    assets::AssetManager,
+    fullscreen::FullscreenPass,
+    hdr::HDR_FORMAT,
+    pipeline::PipelineCache,
+    preprocessor::Preprocessor,
+    shader::{capture_validation_errors, Shader},
+    texture::Texture,
+};
+
+/// Sum of the weighted premultiplied colors and alphas
+pub const ACCUM_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+/// Product of one minus the alphas
+pub const REVEALAGE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+/// How blended materials are drawn
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Transparency {
+    /// Sorted back to front by instance, which breaks down for overlapping or
+    /// intersecting instances
+    Sorted,
+    /// Weighted blended order-independent transparency, which doesn't need
+    /// sorting but only approximates the right result
+    WeightedBlended,
+}
+
+impl Transparency {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Sorted => Self::WeightedBlended,
+            Self::WeightedBlended => Self::Sorted,
+        }
+    }
+}
+
+/// Color targets blended materials are drawn to with
+/// [`Transparency::WeightedBlended`], written by `oit_output` in oit.wgsl
+pub fn targets() -> Vec<wgpu::ColorTargetState> {
+    let add = wgpu::BlendComponent {
+        src_factor: wgpu::BlendFactor::One,
+        dst_factor: wgpu::BlendFactor::One,
+        operation: wgpu::BlendOperation::Add,
+    };
+    let multiply_inverse = wgpu::BlendComponent {
+        src_factor: wgpu::BlendFactor::Zero,
+        dst_factor: wgpu::BlendFactor::OneMinusSrc,
+        operation: wgpu::BlendOperation::Add,
+    };
+    vec![
+        wgpu::ColorTargetState {
+            format: ACCUM_FORMAT,
+            blend: Some(wgpu::BlendState {
+                color: add,
+                alpha: add,
+            }),
+            write_mask: wgpu::ColorWrites::ALL,
+        },
+        wgpu::ColorTargetState {
+            format: REVEALAGE_FORMAT,
+            blend: Some(wgpu::BlendState {
+                color: multiply_inverse,
+                alpha: multiply_inverse,
+            }),
+            write_mask: wgpu::ColorWrites::ALL,
+        },
+    ]
+}
+
+/// Targets of weighted blended order-independent transparency and the pass
+/// compositing them over the opaque scene.
+///
+/// Blended materials are drawn in any order in the pass returned by
+/// [`Oit::accumulation_pass`], then [`Oit::composite`] resolves them.
+pub struct Oit {
+    accum: Texture,
+    revealage: Texture,
+    bind_group: wgpu::BindGroup,
+    pass: FullscreenPass,
+}
+
+impl Oit {
+    pub fn new(
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        config: &wgpu::SurfaceConfiguration,
+    ) -> Result<Self> {
+        let pass = FullscreenPass::new(
+            assets,
+            device,
+            queue,
+            pipelines,
+            Shader::new(
+                "shaders/oit_composite.wgsl",
+                include_str!("../shaders/oit_composite.wgsl"),
+                Preprocessor::new(),
+            ),
+            Some(HDR_FORMAT),
+            Some(wgpu::BlendState::ALPHA_BLENDING),
+        )?;
+        let (accum, revealage) = create_targets(device, config);
+        let bind_group = create_bind_group(device, &pass, &accum, &revealage)?;
+        Ok(Self {
+            accum,
+            revealage,
+            bind_group,
+            pass,
+        })
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
+        let (accum, revealage) = create_targets(device, config);
+        match create_bind_group(device, &self.pass, &accum, &revealage) {
+            Result::Ok(bind_group) => self.bind_group = bind_group,
+            Err(e) => log::error!("{:?}", e),
+        }
+        self.accum = accum;
+        self.revealage = revealage;
+    }
+
+    /// Rebuilds the composite pipeline if the shader was edited
+    pub fn update(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+    ) {
+        self.pass.update(assets, device, queue, pipelines);
+    }
+    /// Clears the targets and starts the pass blended materials are drawn in,
+    /// testing against `depth_view` without writing to it
+    pub fn accumulation_pass<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+        depth_view: &'a wgpu::TextureView,
+    ) -> wgpu::RenderPass<'a> {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("OIT Accumulation Pass"),
+            color_attachments: &[
+                wgpu::RenderPassColorAttachment {
+                    view: &self.accum.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                },
+                wgpu::RenderPassColorAttachment {
+                    view: &self.revealage.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                        store: true,
+                    },
+                },
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        })
+    }
+
+    /// Blends the accumulated fragments over `view`, which should be the HDR
+    /// target the scene was rendered to
+    pub fn composite(
+        &self,
+        pipelines: &PipelineCache,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+    ) {
+        self.pass.draw(pipelines, encoder, view, &self.bind_group);
+    }
+}
+
+fn create_targets(
+    device: &wgpu::Device,
+    config: &wgpu::SurfaceConfiguration,
+) -> (Texture, Texture) {
+    (
+        Texture::create_render_target(device, config, ACCUM_FORMAT, "OIT accumulation texture"),
+        Texture::create_render_target(device, config, REVEALAGE_FORMAT, "OIT revealage texture"),
+    )
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    pass: &FullscreenPass,
+    accum: &Texture,
+    revealage: &Texture,
+) -> Result<wgpu::BindGroup> {
+    capture_validation_errors(device, || {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("OIT composite bind group"),
+            layout: pass.layout(),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&accum.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&revealage.view),
+                },
+            ],
+        })
+    })
+}
@@ -0,0 +1,159 @@
+use crate::{
+    assets::Handle,
+    material::{Material, ShaderId},
+    texture::{ColorSpace, Texture},
+};
+
+/// Uniform parameters of materials using pbr.wgsl, the factors of a glTF
+/// metallic-roughness material. Every factor is multiplied with its texture.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct PbrParams {
+    /// Linear RGBA
+    pub base_color: [f32; 4],
+    /// Linear RGB
+    pub emissive: [f32; 3],
+    /// Only used by shaders with `ALPHA_MASK` defined
+    pub alpha_cutoff: f32,
+    pub metallic: f32,
+    pub roughness: f32,
+    /// Scales the x and y of the normal map
+    pub normal_scale: f32,
+    /// 0 ignores the occlusion map, 1 applies all of it
+    pub occlusion_strength: f32,
+    /// Texture coordinates are scaled and then offset by these before sampling
+    /// any of the textures, e.g. to pick an image out of an atlas
+    pub uv_offset: [f32; 2],
+    pub uv_scale: [f32; 2],
+}
+
+impl Default for PbrParams {
+    /// The defaults of glTF
+    fn default() -> Self {
+        Self {
+            base_color: [1.0; 4],
+            emissive: [0.0; 3],
+            alpha_cutoff: 0.5,
+            metallic: 1.0,
+            roughness: 1.0,
+            normal_scale: 1.0,
+            occlusion_strength: 1.0,
+            uv_offset: [0.0; 2],
+            uv_scale: [1.0; 2],
+        }
+    }
+}
+
+/// Textures of a glTF metallic-roughness material
+#[derive(Clone)]
+pub struct PbrTextures {
+    /// sRGB
+    pub base_color: Handle<Texture>,
+    /// Linear, with roughness in green and metallic in blue
+    pub metallic_roughness: Handle<Texture>,
+    /// Linear, in the tangent space of the mesh's tangents. Load it with
+    /// [`ColorSpace::Linear`].
+    pub normal: Handle<Texture>,
+    /// Linear, with the occlusion in red
+    pub occlusion: Handle<Texture>,
+    /// sRGB
+    pub emissive: Handle<Texture>,
+}
+
+impl PbrTextures {
+    /// 1x1 textures that leave the factors unchanged and the normal flat, for
+    /// materials missing some of the maps
+    pub fn defaults(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let texture = |name: &str, color, color_space| {
+            Handle::from_asset(
+                name,
+                Texture::from_color(device, queue, color, color_space, name),
+            )
+        };
+        Self {
+            base_color: texture("Default base color texture", [255; 4], ColorSpace::Srgb),
+            metallic_roughness: texture(
+                "Default metallic roughness texture",
+                [255; 4],
+                ColorSpace::Linear,
+            ),
+            normal: texture(
+                "Default normal texture",
+                [128, 128, 255, 255],
+                ColorSpace::Linear,
+            ),
+            occlusion: texture("Default occlusion texture", [255; 4], ColorSpace::Linear),
+            emissive: texture("Default emissive texture", [255; 4], ColorSpace::Srgb),
+        }
+    }
+}
+
+/// A material drawn with a shader compiled from pbr.wgsl
+pub fn material(shader: ShaderId, params: &PbrParams, textures: PbrTextures) -> Material {
+    Material::new(shader)
+        .with_params(params)
+        .with_texture(textures.base_color)
+        .with_texture(textures.metallic_roughness)
+        .with_texture(textures.normal)
+        .with_texture(textures.occlusion)
+        .with_texture(textures.emissive)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        mem::{offset_of, size_of},
+        path::Path,
+    };
+
+    use super::*;
+    use crate::preprocessor::Preprocessor;
+
+    #[test]
+    fn params_match_the_shader() {
+        let include_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("shaders/include");
+        let source = Preprocessor::new()
+            .process(
+                "pbr.wgsl",
+                include_str!("../shaders/pbr.wgsl"),
+                &mut |name| Ok(std::fs::read_to_string(include_dir.join(name))?),
+            )
+            .unwrap();
+        let module = naga::front::wgsl::parse_str(&source).unwrap();
+        let (members, span) = module
+            .types
+            .iter()
+            .find_map(|(_, ty)| match &ty.inner {
+                naga::TypeInner::Struct { members, span }
+                    if ty.name.as_deref() == Some("Material") =>
+                {
+                    Some((members, *span))
+                }
+                _ => None,
+            })
+            .unwrap();
+
+        let offsets = members
+            .iter()
+            .map(|member| (member.name.as_deref().unwrap(), member.offset as usize))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            offsets,
+            [
+                ("base_color", offset_of!(PbrParams, base_color)),
+                ("emissive", offset_of!(PbrParams, emissive)),
+                ("alpha_cutoff", offset_of!(PbrParams, alpha_cutoff)),
+                ("metallic", offset_of!(PbrParams, metallic)),
+                ("roughness", offset_of!(PbrParams, roughness)),
+                ("normal_scale", offset_of!(PbrParams, normal_scale)),
+                (
+                    "occlusion_strength",
+                    offset_of!(PbrParams, occlusion_strength)
+                ),
+                ("uv_offset", offset_of!(PbrParams, uv_offset)),
+                ("uv_scale", offset_of!(PbrParams, uv_scale)),
+            ]
+        );
+        assert_eq!(span as usize, size_of::<PbrParams>());
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+use anyhow::*;
+
+use crate::shader::capture_validation_errors;
+
+/// Returned by [`PipelineCache::add_module`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ModuleId(usize);
+
+/// Depth buffer state of a pipeline. Unlike [`wgpu::DepthStencilState`] it can
+/// be hashed, which is why depth bias and stencil aren't supported.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DepthState {
+    pub format: wgpu::TextureFormat,
+    pub write_enabled: bool,
+    pub compare: wgpu::CompareFunction,
+}
+
+/// Everything a render pipeline is created from, apart from the module's
+/// pipeline layout, which is fixed when the module is added
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PipelineKey {
+    pub module: ModuleId,
+    pub vertex_buffers: Vec<wgpu::VertexBufferLayout<'static>>,
+    pub primitive: wgpu::PrimitiveState,
+    pub depth: Option<DepthState>,
+    pub targets: Vec<wgpu::ColorTargetState>,
+    pub multisample: wgpu::MultisampleState,
+}
+
+struct Module {
+    label: String,
+    module: wgpu::ShaderModule,
+    layout: wgpu::PipelineLayout,
+}
+
+/// Creates render pipelines on first use and keeps them around, so pipelines
+/// can be requested every frame.
+///
+/// Modules are expected to have a `vs_main` and a `fs_main` entry point.
+pub struct PipelineCache {
+    surface_format: wgpu::TextureFormat,
+    modules: Vec<Module>,
+    pipelines: HashMap<PipelineKey, wgpu::RenderPipeline>,
+}
+
+impl PipelineCache {
+    pub fn new(surface_format: wgpu::TextureFormat) -> Self {
+        Self {
+            surface_format,
+            modules: Vec::new(),
+            pipelines: HashMap::new(),
+        }
+    }
+
+    /// Format of the surface, which keys of pipelines drawing to it should use
+    pub fn surface_format(&self) -> wgpu::TextureFormat {
+        self.surface_format
+    }
+
+    /// Evicts every pipeline drawing to the old surface format if it changed
+    pub fn set_surface_format(&mut self, format: wgpu::TextureFormat) {
+        if format == self.surface_format {
+            return;
+        }
+
+        let old = self.surface_format;
+        self.pipelines
+            .retain(|key, _| key.targets.iter().all(|target| target.format != old));
+        self.surface_format = format;
+    }
+
+    pub fn add_module(
+        &mut self,
+        label: &str,
+        module: wgpu::ShaderModule,
+        layout: wgpu::PipelineLayout,
+    ) -> ModuleId {
+        self.modules.push(Module {
+            label: label.to_owned(),
+            module,
+            layout,
+        });
+        ModuleId(self.modules.len() - 1)
+    }
+
+    /// Replaces a module after its shader was edited and rebuilds every pipeline
+    /// using it. If any pipeline fails to build, the old module and pipelines
+    /// are kept.
+    pub fn replace_module(
+        &mut self,
+        device: &wgpu::Device,
+        id: ModuleId,
+        module: wgpu::ShaderModule,
+    ) -> Result<()> {
+        let entry = &self.modules[id.0];
+        let mut pipelines = Vec::new();
+        for key in self.pipelines.keys().filter(|key| key.module == id) {
+            let pipeline = capture_validation_errors(device, || {
+                create_render_pipeline(device, &entry.label, &entry.layout, &module, key)
+            })
+            .with_context(|| format!("Failed to rebuild a {} pipeline", entry.label))?;
+            pipelines.push((key.clone(), pipeline));
+        }
+
+        self.modules[id.0].module = module;
+        self.pipelines.extend(pipelines);
+        Ok(())
+    }
+
+    /// Returns the pipeline for `key`, creating it if it isn't cached
+    pub fn get_or_create(
+        &mut self,
+        device: &wgpu::Device,
+        key: &PipelineKey,
+    ) -> Result<&wgpu::RenderPipeline> {
+        if !self.pipelines.contains_key(key) {
+            let entry = &self.modules[key.module.0];
+            let pipeline = capture_validation_errors(device, || {
+                create_render_pipeline(device, &entry.label, &entry.layout, &entry.module, key)
+            })
+            .with_context(|| format!("Failed to create a {} pipeline", entry.label))?;
+            log::debug!("Created a {} pipeline", entry.label);
+            self.pipelines.insert(key.clone(), pipeline);
+        }
+        Ok(&self.pipelines[key])
+    }
+
+    /// Returns the pipeline for `key` if it was already created
+    pub fn get(&self, key: &PipelineKey) -> Option<&wgpu::RenderPipeline> {
+        self.pipelines.get(key)
+    }
+}
+
+fn create_render_pipeline(
+    device: &wgpu::Device,
+    label: &str,
+    layout: &wgpu::PipelineLayout,
+    module: &wgpu::ShaderModule,
+    key: &PipelineKey,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module,
+            entry_point: "vs_main",
+            buffers: &key.vertex_buffers,
+        },
+        fragment: Some(wgpu::FragmentState {
+            module,
+            entry_point: "fs_main",
+            targets: &key.targets,
+        }),
+        primitive: key.primitive,
+        depth_stencil: key.depth.map(|depth| wgpu::DepthStencilState {
+            format: depth.format,
+            depth_write_enabled: depth.write_enabled,
+            depth_compare: depth.compare,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: key.multisample,
+        multiview: None,
+    })
+}
@@ -0,0 +1,377 @@
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    assets::AssetManager,
+    fullscreen::FullscreenPass,
+    pipeline::PipelineCache,
+    preprocessor::Preprocessor,
+    shader::{capture_validation_errors, Shader},
+    texture::Texture,
+};
+
+/// Returned by [`PostProcess::add`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EffectId(usize);
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct GrayscaleParams {
+    /// 0 keeps the colors, 1 is fully gray
+    pub amount: f32,
+}
+
+impl Default for GrayscaleParams {
+    fn default() -> Self {
+        Self { amount: 1.0 }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct VignetteParams {
+    /// How much the corners are darkened
+    pub intensity: f32,
+    /// Distance from the center, in half screen heights, where darkening
+    /// starts
+    pub radius: f32,
+    /// Distance over which it fades in
+    pub softness: f32,
+}
+
+impl Default for VignetteParams {
+    fn default() -> Self {
+        Self {
+            intensity: 0.6,
+            radius: 0.6,
+            softness: 1.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ChromaticAberrationParams {
+    /// Offset of the red and blue channels at the edges of the screen, in
+    /// texture coordinates
+    pub strength: f32,
+}
+
+impl Default for ChromaticAberrationParams {
+    fn default() -> Self {
+        Self { strength: 0.01 }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SharpenParams {
+    pub strength: f32,
+}
+
+impl Default for SharpenParams {
+    fn default() -> Self {
+        Self { strength: 0.3 }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FxaaParams {
+    /// Minimum contrast, relative to the brightest neighbour, of edges that get
+    /// anti-aliased
+    pub edge_threshold: f32,
+    /// Contrast below which dark areas are skipped
+    pub edge_threshold_min: f32,
+    /// Longest blur along an edge in pixels
+    pub span_max: f32,
+}
+
+impl Default for FxaaParams {
+    fn default() -> Self {
+        Self {
+            edge_threshold: 0.125,
+            edge_threshold_min: 0.0312,
+            span_max: 8.0,
+        }
+    }
+}
+
+/// Returned by [`PostProcess::add_builtin_effects`]
+pub struct BuiltinEffects {
+    pub sharpen: EffectId,
+    pub chromatic_aberration: EffectId,
+    pub grayscale: EffectId,
+    pub vignette: EffectId,
+    pub fxaa: EffectId,
+}
+
+struct Effect {
+    enabled: bool,
+    pass: FullscreenPass,
+    params_buffer: wgpu::Buffer,
+    /// Reading from the first and from the second target
+    bind_groups: Vec<wgpu::BindGroup>,
+}
+
+/// Ordered list of fullscreen effects applied to the tonemapped scene.
+///
+/// Effects are fragment shaders including postprocess.wgsl, which reads the
+/// output of the previous effect, with their params at binding 2. They
+/// ping-pong between two targets the size of the surface and the last enabled
+/// one draws to the surface.
+pub struct PostProcess {
+    effects: Vec<Effect>,
+    targets: [Texture; 2],
+    sampler: wgpu::Sampler,
+}
+
+impl PostProcess {
+    pub fn new(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> Self {
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Post processing sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        Self {
+            effects: Vec::new(),
+            targets: create_targets(device, config),
+            sampler,
+        }
+    }
+
+    /// Appends an enabled effect to the end of the chain
+    pub fn add<T: bytemuck::Pod>(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        shader: Shader,
+        params: &T,
+    ) -> Result<EffectId> {
+        let pass = FullscreenPass::new(assets, device, queue, pipelines, shader, None, None)?;
+        // Uniform buffers are kept to a multiple of 16 bytes
+        let mut contents = bytemuck::bytes_of(params).to_vec();
+        contents.resize(contents.len().div_ceil(16).max(1) * 16, 0);
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Post processing params buffer"),
+            contents: &contents,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_groups = self.create_bind_groups(device, &pass, &params_buffer)?;
+
+        self.effects.push(Effect {
+            enabled: true,
+            pass,
+            params_buffer,
+            bind_groups,
+        });
+        Ok(EffectId(self.effects.len() - 1))
+    }
+
+    /// Adds sharpen, chromatic aberration, grayscale, vignette and FXAA in that
+    /// order, with only FXAA enabled
+    pub fn add_builtin_effects(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+    ) -> Result<BuiltinEffects> {
+        let effects = BuiltinEffects {
+            sharpen: self.add(
+                assets,
+                device,
+                queue,
+                pipelines,
+                Shader::new(
+                    "shaders/sharpen.wgsl",
+                    include_str!("../shaders/sharpen.wgsl"),
+                    Preprocessor::new(),
+                ),
+                &SharpenParams::default(),
+            )?,
+            chromatic_aberration: self.add(
+                assets,
+                device,
+                queue,
+                pipelines,
+                Shader::new(
+                    "shaders/chromatic_aberration.wgsl",
+                    include_str!("../shaders/chromatic_aberration.wgsl"),
+                    Preprocessor::new(),
+                ),
+                &ChromaticAberrationParams::default(),
+            )?,
+            grayscale: self.add(
+                assets,
+                device,
+                queue,
+                pipelines,
+                Shader::new(
+                    "shaders/grayscale.wgsl",
+                    include_str!("../shaders/grayscale.wgsl"),
+                    Preprocessor::new(),
+                ),
+                &GrayscaleParams::default(),
+            )?,
+            vignette: self.add(
+                assets,
+                device,
+                queue,
+                pipelines,
+                Shader::new(
+                    "shaders/vignette.wgsl",
+                    include_str!("../shaders/vignette.wgsl"),
+                    Preprocessor::new(),
+                ),
+                &VignetteParams::default(),
+            )?,
+            fxaa: self.add(
+                assets,
+                device,
+                queue,
+                pipelines,
+                Shader::new(
+                    "shaders/fxaa.wgsl",
+                    include_str!("../shaders/fxaa.wgsl"),
+                    Preprocessor::new(),
+                ),
+                &FxaaParams::default(),
+            )?,
+        };
+        for id in [
+            effects.sharpen,
+            effects.chromatic_aberration,
+            effects.grayscale,
+            effects.vignette,
+        ] {
+            self.set_enabled(id, false);
+        }
+        Ok(effects)
+    }
+
+    pub fn set_enabled(&mut self, id: EffectId, enabled: bool) {
+        self.effects[id.0].enabled = enabled;
+    }
+
+    pub fn is_enabled(&self, id: EffectId) -> bool {
+        self.effects[id.0].enabled
+    }
+
+    /// Overwrites the params of an effect, which must be the type it was added
+    /// with
+    pub fn set_params<T: bytemuck::Pod>(&self, queue: &wgpu::Queue, id: EffectId, params: &T) {
+        queue.write_buffer(
+            &self.effects[id.0].params_buffer,
+            0,
+            bytemuck::bytes_of(params),
+        );
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
+        self.targets = create_targets(device, config);
+        for i in 0..self.effects.len() {
+            let effect = &self.effects[i];
+            match self.create_bind_groups(device, &effect.pass, &effect.params_buffer) {
+                Result::Ok(bind_groups) => self.effects[i].bind_groups = bind_groups,
+                Err(e) => log::error!("{:?}", e),
+            }
+        }
+    }
+
+    /// Rebuilds the pipelines of edited effects
+    pub fn update(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+    ) {
+        for effect in &mut self.effects {
+            effect.pass.update(assets, device, queue, pipelines);
+        }
+    }
+
+    /// Where the input of the chain should be drawn, or `None` if no effect is
+    /// enabled and it should be drawn to the surface directly
+    pub fn input(&self) -> Option<&wgpu::TextureView> {
+        self.effects
+            .iter()
+            .any(|effect| effect.enabled)
+            .then(|| &self.targets[0].view)
+    }
+
+    /// Applies the enabled effects to the input, drawing the result to `view`
+    pub fn render(
+        &self,
+        pipelines: &PipelineCache,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+    ) {
+        let enabled = self
+            .effects
+            .iter()
+            .filter(|effect| effect.enabled)
+            .collect::<Vec<_>>();
+        for (i, effect) in enabled.iter().enumerate() {
+            let source = i % 2;
+            let target = if i + 1 == enabled.len() {
+                view
+            } else {
+                &self.targets[1 - source].view
+            };
+            effect
+                .pass
+                .draw(pipelines, encoder, target, &effect.bind_groups[source]);
+        }
+    }
+
+    fn create_bind_groups(
+        &self,
+        device: &wgpu::Device,
+        pass: &FullscreenPass,
+        params_buffer: &wgpu::Buffer,
+    ) -> Result<Vec<wgpu::BindGroup>> {
+        self.targets
+            .iter()
+            .map(|target| {
+                capture_validation_errors(device, || {
+                    device.create_bind_group(&wgpu::BindGroupDescriptor {
+                        label: Some("Post processing bind group"),
+                        layout: pass.layout(),
+                        entries: &[
+                            wgpu::BindGroupEntry {
+                                binding: 0,
+                                resource: wgpu::BindingResource::TextureView(&target.view),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 1,
+                                resource: wgpu::BindingResource::Sampler(&self.sampler),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 2,
+                                resource: params_buffer.as_entire_binding(),
+                            },
+                        ],
+                    })
+                })
+                .context("Effect resources don't match its shader")
+            })
+            .collect()
+    }
+}
+
+/// The targets are in the surface format, so effects can draw to either
+fn create_targets(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> [Texture; 2] {
+    [0, 1].map(|i| {
+        Texture::create_render_target(
+            device,
+            config,
+            config.format,
+            &format!("Post processing texture {}", i),
+        )
+    })
+}
@@ -0,0 +1,288 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::*;
+
+/// A small C-like preprocessor for WGSL.
+///
+/// Supports:
+/// - `#include "file.wgsl"`, every file is only included once
+/// - `#define NAME` and `#define NAME value`, the value replaces `NAME` in the
+///   lines following the define
+/// - `#ifdef NAME`, `#ifndef NAME`, `#else` and `#endif`, which can be nested
+#[derive(Clone, Default)]
+pub struct Preprocessor {
+    defines: HashMap<String, String>,
+}
+
+struct Condition {
+    /// Whether the branch we're currently in is kept
+    active: bool,
+    /// Whether the enclosing block is kept
+    parent_active: bool,
+    seen_else: bool,
+}
+
+struct Expansion<'a> {
+    defines: HashMap<String, String>,
+    included: HashSet<String>,
+    resolve: &'a mut dyn FnMut(&str) -> Result<String>,
+    output: String,
+}
+
+impl Preprocessor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn define(mut self, name: &str, value: &str) -> Self {
+        self.defines.insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    /// Processes `source`, calling `resolve` with the name of every included
+    /// file to get its contents
+    pub fn process(
+        &self,
+        name: &str,
+        source: &str,
+        resolve: &mut dyn FnMut(&str) -> Result<String>,
+    ) -> Result<String> {
+        let mut expansion = Expansion {
+            defines: self.defines.clone(),
+            included: HashSet::from([name.to_owned()]),
+            resolve,
+            output: String::new(),
+        };
+        expansion.process(name, source)?;
+        Ok(expansion.output)
+    }
+}
+
+impl Expansion<'_> {
+    fn process(&mut self, name: &str, source: &str) -> Result<()> {
+        let mut conditions: Vec<Condition> = Vec::new();
+
+        for (i, line) in source.lines().enumerate() {
+            let error = || format!("{}:{}", name, i + 1);
+            let active = conditions.last().is_none_or(|c| c.active);
+
+            let trimmed = line.trim();
+            let directive = match trimmed.strip_prefix('#') {
+                Some(directive) => directive,
+                None => {
+                    if active {
+                        self.output.push_str(&self.expand(line));
+                        self.output.push('\n');
+                    }
+                    continue;
+                }
+            };
+            let (keyword, argument) = match directive.split_once(char::is_whitespace) {
+                Some((keyword, argument)) => (keyword, argument.trim()),
+                None => (directive, ""),
+            };
+
+            match keyword {
+                "ifdef" | "ifndef" => {
+                    let name = parse_identifier(argument).with_context(error)?;
+                    let defined = self.defines.contains_key(name);
+                    conditions.push(Condition {
+                        active: active && defined == (keyword == "ifdef"),
+                        parent_active: active,
+                        seen_else: false,
+                    });
+                }
+                "else" => {
+                    let condition = conditions
+                        .last_mut()
+                        .ok_or_else(|| anyhow!("#else without #ifdef"))
+                        .with_context(error)?;
+                    if condition.seen_else {
+                        return Err(anyhow!("Duplicate #else")).with_context(error);
+                    }
+                    condition.seen_else = true;
+                    condition.active = condition.parent_active && !condition.active;
+                }
+                "endif" => {
+                    conditions
+                        .pop()
+                        .ok_or_else(|| anyhow!("#endif without #ifdef"))
+                        .with_context(error)?;
+                }
+                _ if !active => {}
+                "define" => {
+                    let (define, value) = match argument.split_once(char::is_whitespace) {
+                        Some((define, value)) => (define, value.trim()),
+                        None => (argument, ""),
+                    };
+                    let define = parse_identifier(define).with_context(error)?;
+                    let value = self.expand(value);
+                    self.defines.insert(define.to_owned(), value);
+                }
+                "include" => {
+                    let file = argument
+                        .strip_prefix('"')
+                        .and_then(|argument| argument.strip_suffix('"'))
+                        .ok_or_else(|| anyhow!("Expected #include \"file\""))
+                        .with_context(error)?;
+                    if self.included.insert(file.to_owned()) {
+                        let source = (self.resolve)(file).with_context(error)?;
+                        self.process(file, &source).with_context(error)?;
+                    }
+                }
+                _ => return Err(anyhow!("Unknown directive #{}", keyword)).with_context(error),
+            }
+        }
+
+        if !conditions.is_empty() {
+            bail!("{}: #ifdef without #endif", name);
+        }
+        Ok(())
+    }
+
+    /// Replaces every defined identifier in `line` with its value
+    fn expand(&self, line: &str) -> String {
+        if self.defines.is_empty() {
+            return line.to_owned();
+        }
+
+        let mut output = String::with_capacity(line.len());
+        let mut rest = line;
+        while let Some(start) = rest.find(is_identifier_start) {
+            let (before, word) = rest.split_at(start);
+            output.push_str(before);
+
+            let end = word
+                .find(|c: char| !is_identifier_char(c))
+                .unwrap_or(word.len());
+            let (word, after) = word.split_at(end);
+            match self.defines.get(word) {
+                Some(value) => output.push_str(value),
+                None => output.push_str(word),
+            }
+            rest = after;
+        }
+        output.push_str(rest);
+        output
+    }
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn parse_identifier(s: &str) -> Result<&str> {
+    let valid = s.starts_with(is_identifier_start) && s.chars().all(is_identifier_char);
+    if !valid {
+        bail!("Expected an identifier, got {:?}", s);
+    }
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_includes(name: &str) -> Result<String> {
+        bail!("Unexpected include {}", name)
+    }
+
+    fn process(preprocessor: &Preprocessor, source: &str) -> Result<String> {
+        preprocessor.process("test.wgsl", source, &mut no_includes)
+    }
+
+    #[test]
+    fn passes_through_plain_source() {
+        let source = "fn main() {\n    let a = 1;\n}\n";
+        assert_eq!(process(&Preprocessor::new(), source).unwrap(), source);
+    }
+
+    #[test]
+    fn replaces_defines() {
+        let source = "#define GROUP 1\n[[group(GROUP), binding(0)]]\nlet GROUPS = GROUP;\n";
+        assert_eq!(
+            process(&Preprocessor::new(), source).unwrap(),
+            "[[group(1), binding(0)]]\nlet GROUPS = 1;\n"
+        );
+    }
+
+    #[test]
+    fn replaces_defines_given_by_the_caller() {
+        let preprocessor = Preprocessor::new().define("COUNT", "4");
+        assert_eq!(
+            process(&preprocessor, "var a: array<f32, COUNT>;").unwrap(),
+            "var a: array<f32, 4>;\n"
+        );
+    }
+
+    #[test]
+    fn selects_branches() {
+        let source = "#ifdef TEXTURED\ntextured\n#else\nuntextured\n#endif\n";
+        assert_eq!(
+            process(&Preprocessor::new(), source).unwrap(),
+            "untextured\n"
+        );
+        assert_eq!(
+            process(&Preprocessor::new().define("TEXTURED", ""), source).unwrap(),
+            "textured\n"
+        );
+    }
+
+    #[test]
+    fn nests_conditions() {
+        let source = "\
+#ifndef LIT
+unlit
+#else
+#ifdef TEXTURED
+lit textured
+#else
+lit untextured
+#endif
+#endif
+";
+        let lit = Preprocessor::new().define("LIT", "");
+        assert_eq!(process(&lit, source).unwrap(), "lit untextured\n");
+        assert_eq!(
+            process(&lit.define("TEXTURED", ""), source).unwrap(),
+            "lit textured\n"
+        );
+        assert_eq!(process(&Preprocessor::new(), source).unwrap(), "unlit\n");
+    }
+
+    #[test]
+    fn ignores_defines_in_inactive_branches() {
+        let source = "#ifdef A\n#define B 1\n#endif\nB\n";
+        assert_eq!(process(&Preprocessor::new(), source).unwrap(), "B\n");
+    }
+
+    #[test]
+    fn includes_files_once() {
+        let mut resolve = |name: &str| match name {
+            "camera.wgsl" => Ok("#include \"common.wgsl\"\ncamera".to_owned()),
+            "common.wgsl" => Ok("common".to_owned()),
+            _ => bail!("Missing {}", name),
+        };
+        let source = "#include \"camera.wgsl\"\n#include \"common.wgsl\"\nmain\n";
+        let output = Preprocessor::new()
+            .process("main.wgsl", source, &mut resolve)
+            .unwrap();
+        assert_eq!(output, "common\ncamera\nmain\n");
+    }
+
+    #[test]
+    fn reports_errors_with_location() {
+        let error = process(&Preprocessor::new(), "a\n#endif\n").unwrap_err();
+        assert_eq!(error.to_string(), "test.wgsl:2");
+        assert_eq!(error.root_cause().to_string(), "#endif without #ifdef");
+
+        assert!(process(&Preprocessor::new(), "#ifdef A\n").is_err());
+        assert!(process(&Preprocessor::new(), "#include camera.wgsl\n").is_err());
+        assert!(process(&Preprocessor::new(), "#include \"camera.wgsl\"\n").is_err());
+        assert!(process(&Preprocessor::new(), "#pragma once\n").is_err());
+    }
+}
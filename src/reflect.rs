@@ -0,0 +1,455 @@
+use std::{collections::BTreeMap, num::NonZeroU64};
+
+use anyhow::*;
+
+/// A vertex shader input
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VertexInput {
+    pub location: u32,
+    pub format: wgpu::VertexFormat,
+}
+
+/// Resource bindings and vertex inputs of a WGSL module, read with naga, so the
+/// Rust side doesn't need to repeat what the shader already declares.
+pub struct Reflection {
+    /// Layout entries of every bind group, indexed by group. Groups the shader
+    /// doesn't use are empty.
+    pub bind_groups: Vec<Vec<wgpu::BindGroupLayoutEntry>>,
+    pub vertex_inputs: Vec<VertexInput>,
+}
+
+impl Reflection {
+    pub fn from_wgsl(code: &str) -> Result<Self> {
+        let module = naga::front::wgsl::parse_str(code)
+            .map_err(|e| anyhow!("{}", e.emit_to_string(code)))?;
+        let info = naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .map_err(|e| anyhow!("{:?}", e))?;
+
+        let mut layouter = naga::proc::Layouter::default();
+        layouter
+            .update(&module.types, &module.constants)
+            .map_err(|e| anyhow!("{:?}", e))?;
+
+        let mut groups = BTreeMap::<u32, Vec<wgpu::BindGroupLayoutEntry>>::new();
+        for (handle, global) in module.global_variables.iter() {
+            let binding = match &global.binding {
+                Some(binding) => binding,
+                None => continue,
+            };
+
+            let mut visibility = wgpu::ShaderStages::NONE;
+            for (i, entry_point) in module.entry_points.iter().enumerate() {
+                if !info.get_entry_point(i)[handle].is_empty() {
+                    visibility |= match entry_point.stage {
+                        naga::ShaderStage::Vertex => wgpu::ShaderStages::VERTEX,
+                        naga::ShaderStage::Fragment => wgpu::ShaderStages::FRAGMENT,
+                        naga::ShaderStage::Compute => wgpu::ShaderStages::COMPUTE,
+                    };
+                }
+            }
+            // Bindings no entry point uses don't need to be in the layout
+            if visibility.is_empty() {
+                continue;
+            }
+
+            let size = NonZeroU64::new(layouter[global.ty].size as u64);
+            let ty = binding_type(&module.types[global.ty].inner, global.class, size)
+                .with_context(|| {
+                    format!(
+                        "Unsupported binding {} at group {} binding {}",
+                        global.name.as_deref().unwrap_or("<unnamed>"),
+                        binding.group,
+                        binding.binding
+                    )
+                })?;
+
+            groups
+                .entry(binding.group)
+                .or_default()
+                .push(wgpu::BindGroupLayoutEntry {
+                    binding: binding.binding,
+                    visibility,
+                    ty,
+                    count: None,
+                });
+        }
+
+        let group_count = groups.keys().next_back().map_or(0, |group| group + 1);
+        let bind_groups = (0..group_count)
+            .map(|group| {
+                let mut entries = groups.remove(&group).unwrap_or_default();
+                entries.sort_by_key(|entry| entry.binding);
+                entries
+            })
+            .collect();
+
+        Ok(Self {
+            bind_groups,
+            vertex_inputs: vertex_inputs(&module)?,
+        })
+    }
+
+    /// Checks that every vertex input is provided by one of the buffers with the
+    /// same format
+    pub fn check_vertex_buffers(&self, buffers: &[wgpu::VertexBufferLayout]) -> Result<()> {
+        let attributes = buffers
+            .iter()
+            .flat_map(|buffer| buffer.attributes)
+            .collect::<Vec<_>>();
+
+        let mut errors = Vec::new();
+        for input in &self.vertex_inputs {
+            match attributes
+                .iter()
+                .find(|attribute| attribute.shader_location == input.location)
+            {
+                Some(attribute) if attribute.format != input.format => errors.push(format!(
+                    "location {} is {:?} in the shader but {:?} in the vertex buffer",
+                    input.location, input.format, attribute.format
+                )),
+                Some(_) => {}
+                None => errors.push(format!(
+                    "location {} isn't provided by any vertex buffer",
+                    input.location
+                )),
+            }
+        }
+
+        if !errors.is_empty() {
+            bail!(
+                "Vertex buffers don't match the shader: {}",
+                errors.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks that a buffer of `size` bytes is big enough for the buffer
+    /// binding at `group` and `binding`
+    pub fn check_buffer_size(&self, group: u32, binding: u32, size: u64) -> Result<()> {
+        let entry = self
+            .bind_groups
+            .get(group as usize)
+            .and_then(|entries| entries.iter().find(|entry| entry.binding == binding))
+            .ok_or_else(|| anyhow!("The shader has no binding {} in group {}", binding, group))?;
+
+        match entry.ty {
+            wgpu::BindingType::Buffer {
+                min_binding_size: Some(min_size),
+                ..
+            } if size < min_size.get() => bail!(
+                "Buffer for group {} binding {} is {} bytes, the shader needs {}",
+                group,
+                binding,
+                size,
+                min_size
+            ),
+            wgpu::BindingType::Buffer { .. } => Ok(()),
+            _ => bail!("Group {} binding {} isn't a buffer", group, binding),
+        }
+    }
+}
+
+fn binding_type(
+    inner: &naga::TypeInner,
+    class: naga::StorageClass,
+    size: Option<NonZeroU64>,
+) -> Result<wgpu::BindingType> {
+    Ok(match (class, inner) {
+        (naga::StorageClass::Uniform, _) => wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: size,
+        },
+        (naga::StorageClass::Storage { access }, _) => wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage {
+                read_only: !access.contains(naga::StorageAccess::STORE),
+            },
+            has_dynamic_offset: false,
+            min_binding_size: size,
+        },
+        (naga::StorageClass::Handle, naga::TypeInner::Sampler { comparison }) => {
+            wgpu::BindingType::Sampler(if *comparison {
+                wgpu::SamplerBindingType::Comparison
+            } else {
+                wgpu::SamplerBindingType::Filtering
+            })
+        }
+        (
+            naga::StorageClass::Handle,
+            naga::TypeInner::Image {
+                dim,
+                arrayed,
+                class,
+            },
+        ) => {
+            let view_dimension = view_dimension(*dim, *arrayed)?;
+            match *class {
+                naga::ImageClass::Sampled { kind, multi } => wgpu::BindingType::Texture {
+                    sample_type: match kind {
+                        // Whether it's filterable depends on the texture format,
+                        // which the shader doesn't know
+                        naga::ScalarKind::Float => {
+                            wgpu::TextureSampleType::Float { filterable: !multi }
+                        }
+                        naga::ScalarKind::Sint => wgpu::TextureSampleType::Sint,
+                        naga::ScalarKind::Uint => wgpu::TextureSampleType::Uint,
+                        naga::ScalarKind::Bool => bail!("Bool textures don't exist"),
+                    },
+                    view_dimension,
+                    multisampled: multi,
+                },
+                naga::ImageClass::Depth { multi } => wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Depth,
+                    view_dimension,
+                    multisampled: multi,
+                },
+                naga::ImageClass::Storage { format, access } => wgpu::BindingType::StorageTexture {
+                    access: if access
+                        .contains(naga::StorageAccess::LOAD | naga::StorageAccess::STORE)
+                    {
+                        wgpu::StorageTextureAccess::ReadWrite
+                    } else if access.contains(naga::StorageAccess::STORE) {
+                        wgpu::StorageTextureAccess::WriteOnly
+                    } else {
+                        wgpu::StorageTextureAccess::ReadOnly
+                    },
+                    format: storage_format(format),
+                    view_dimension,
+                },
+            }
+        }
+        _ => bail!("{:?} in {:?}", inner, class),
+    })
+}
+
+fn view_dimension(dim: naga::ImageDimension, arrayed: bool) -> Result<wgpu::TextureViewDimension> {
+    Ok(match (dim, arrayed) {
+        (naga::ImageDimension::D1, false) => wgpu::TextureViewDimension::D1,
+        (naga::ImageDimension::D2, false) => wgpu::TextureViewDimension::D2,
+        (naga::ImageDimension::D2, true) => wgpu::TextureViewDimension::D2Array,
+        (naga::ImageDimension::D3, false) => wgpu::TextureViewDimension::D3,
+        (naga::ImageDimension::Cube, false) => wgpu::TextureViewDimension::Cube,
+        (naga::ImageDimension::Cube, true) => wgpu::TextureViewDimension::CubeArray,
+        _ => bail!("Arrays of {:?} textures don't exist", dim),
+    })
+}
+
+fn storage_format(format: naga::StorageFormat) -> wgpu::TextureFormat {
+    use naga::StorageFormat as Sf;
+    use wgpu::TextureFormat as Tf;
+
+    match format {
+        Sf::R8Unorm => Tf::R8Unorm,
+        Sf::R8Snorm => Tf::R8Snorm,
+        Sf::R8Uint => Tf::R8Uint,
+        Sf::R8Sint => Tf::R8Sint,
+        Sf::R16Uint => Tf::R16Uint,
+        Sf::R16Sint => Tf::R16Sint,
+        Sf::R16Float => Tf::R16Float,
+        Sf::Rg8Unorm => Tf::Rg8Unorm,
+        Sf::Rg8Snorm => Tf::Rg8Snorm,
+        Sf::Rg8Uint => Tf::Rg8Uint,
+        Sf::Rg8Sint => Tf::Rg8Sint,
+        Sf::R32Uint => Tf::R32Uint,
+        Sf::R32Sint => Tf::R32Sint,
+        Sf::R32Float => Tf::R32Float,
+        Sf::Rg16Uint => Tf::Rg16Uint,
+        Sf::Rg16Sint => Tf::Rg16Sint,
+        Sf::Rg16Float => Tf::Rg16Float,
+        Sf::Rgba8Unorm => Tf::Rgba8Unorm,
+        Sf::Rgba8Snorm => Tf::Rgba8Snorm,
+        Sf::Rgba8Uint => Tf::Rgba8Uint,
+        Sf::Rgba8Sint => Tf::Rgba8Sint,
+        Sf::Rgb10a2Unorm => Tf::Rgb10a2Unorm,
+        Sf::Rg11b10Float => Tf::Rg11b10Float,
+        Sf::Rg32Uint => Tf::Rg32Uint,
+        Sf::Rg32Sint => Tf::Rg32Sint,
+        Sf::Rg32Float => Tf::Rg32Float,
+        Sf::Rgba16Uint => Tf::Rgba16Uint,
+        Sf::Rgba16Sint => Tf::Rgba16Sint,
+        Sf::Rgba16Float => Tf::Rgba16Float,
+        Sf::Rgba32Uint => Tf::Rgba32Uint,
+        Sf::Rgba32Sint => Tf::Rgba32Sint,
+        Sf::Rgba32Float => Tf::Rgba32Float,
+    }
+}
+
+fn vertex_format(inner: &naga::TypeInner) -> Option<wgpu::VertexFormat> {
+    use naga::{ScalarKind, TypeInner, VectorSize};
+    use wgpu::VertexFormat;
+
+    let (size, kind) = match *inner {
+        TypeInner::Scalar { kind, width: 4 } => (None, kind),
+        TypeInner::Vector {
+            size,
+            kind,
+            width: 4,
+        } => (Some(size), kind),
+        _ => return None,
+    };
+    Some(match (kind, size) {
+        (ScalarKind::Float, None) => VertexFormat::Float32,
+        (ScalarKind::Float, Some(VectorSize::Bi)) => VertexFormat::Float32x2,
+        (ScalarKind::Float, Some(VectorSize::Tri)) => VertexFormat::Float32x3,
+        (ScalarKind::Float, Some(VectorSize::Quad)) => VertexFormat::Float32x4,
+        (ScalarKind::Sint, None) => VertexFormat::Sint32,
+        (ScalarKind::Sint, Some(VectorSize::Bi)) => VertexFormat::Sint32x2,
+        (ScalarKind::Sint, Some(VectorSize::Tri)) => VertexFormat::Sint32x3,
+        (ScalarKind::Sint, Some(VectorSize::Quad)) => VertexFormat::Sint32x4,
+        (ScalarKind::Uint, None) => VertexFormat::Uint32,
+        (ScalarKind::Uint, Some(VectorSize::Bi)) => VertexFormat::Uint32x2,
+        (ScalarKind::Uint, Some(VectorSize::Tri)) => VertexFormat::Uint32x3,
+        (ScalarKind::Uint, Some(VectorSize::Quad)) => VertexFormat::Uint32x4,
+        _ => return None,
+    })
+}
+
+/// Location and format of every vertex shader input
+fn vertex_inputs(module: &naga::Module) -> Result<Vec<VertexInput>> {
+    let mut inputs = Vec::new();
+    let mut push = |location: u32, inner: &naga::TypeInner| -> Result<()> {
+        let format = vertex_format(inner)
+            .ok_or_else(|| anyhow!("Unsupported vertex input type {:?}", inner))?;
+        inputs.push(VertexInput { location, format });
+        Ok(())
+    };
+
+    let vertex_entry_points = module
+        .entry_points
+        .iter()
+        .filter(|entry_point| entry_point.stage == naga::ShaderStage::Vertex);
+    for entry_point in vertex_entry_points {
+        for argument in &entry_point.function.arguments {
+            let inner = &module.types[argument.ty].inner;
+            match (&argument.binding, inner) {
+                (Some(naga::Binding::Location { location, .. }), _) => push(*location, inner)?,
+                (None, naga::TypeInner::Struct { members, .. }) => {
+                    for member in members {
+                        if let Some(naga::Binding::Location { location, .. }) = member.binding {
+                            push(location, &module.types[member.ty].inner)?;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    inputs.sort_by_key(|input| input.location);
+    inputs.dedup();
+    Ok(inputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHADER: &str = r#"
+struct Camera {
+    view_proj: mat4x4<f32>;
+};
+[[group(1), binding(0)]]
+var<uniform> camera: Camera;
+
+[[group(0), binding(0)]]
+var t_diffuse: texture_2d<f32>;
+[[group(0), binding(1)]]
+var s_diffuse: sampler;
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] tex_coords: vec2<f32>;
+};
+
+[[stage(vertex)]]
+fn vs_main(
+    [[location(0)]] position: vec3<f32>,
+    [[location(1)]] tex_coords: vec2<f32>,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coords = tex_coords;
+    out.clip_position = camera.view_proj * vec4<f32>(position, 1.0);
+    return out;
+}
+
+[[stage(fragment)]]
+fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return textureSample(t_diffuse, s_diffuse, in.tex_coords);
+}
+"#;
+
+    #[test]
+    fn reflects_bind_groups() {
+        let reflection = Reflection::from_wgsl(SHADER).unwrap();
+        assert_eq!(
+            reflection.bind_groups,
+            vec![
+                vec![
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+                vec![wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: NonZeroU64::new(64),
+                    },
+                    count: None,
+                }],
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_vertex_buffer_mismatches() {
+        let reflection = Reflection::from_wgsl(SHADER).unwrap();
+        let matching = wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x2];
+        let wrong_format = wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x3];
+        let missing = wgpu::vertex_attr_array![0 => Float32x3];
+        let buffer = |attributes| wgpu::VertexBufferLayout {
+            array_stride: 0,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes,
+        };
+
+        assert!(reflection
+            .check_vertex_buffers(&[buffer(&matching)])
+            .is_ok());
+        assert!(reflection
+            .check_vertex_buffers(&[buffer(&wrong_format)])
+            .is_err());
+        assert!(reflection
+            .check_vertex_buffers(&[buffer(&missing)])
+            .is_err());
+    }
+
+    #[test]
+    fn reports_small_buffers() {
+        let reflection = Reflection::from_wgsl(SHADER).unwrap();
+        assert!(reflection.check_buffer_size(1, 0, 64).is_ok());
+        assert!(reflection.check_buffer_size(1, 0, 48).is_err());
+        assert!(reflection.check_buffer_size(0, 0, 64).is_err());
+        assert!(reflection.check_buffer_size(2, 0, 64).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use wgpu::util::DeviceExt;
+
+/// How distance fog thickens away from the camera
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FogMode {
+    Off,
+    /// Fades in between [`Fog::start`] and [`Fog::end`]
+    Linear,
+    /// Light is absorbed at a constant [`Fog::density`]
+    Exponential,
+    /// Clearer close to the camera and thicker far away than exponential fog
+    ExponentialSquared,
+}
+
+impl FogMode {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Off => Self::Linear,
+            Self::Linear => Self::Exponential,
+            Self::Exponential => Self::ExponentialSquared,
+            Self::ExponentialSquared => Self::Off,
+        }
+    }
+}
+
+/// Fog that thins out with height, added on top of the distance fog
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HeightFog {
+    /// Density at `base`
+    pub density: f32,
+    pub base: f32,
+    /// How fast the density falls off above `base`, and rises below it
+    pub falloff: f32,
+}
+
+impl Default for HeightFog {
+    fn default() -> Self {
+        Self {
+            density: 0.3,
+            base: -0.5,
+            falloff: 1.5,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Fog {
+    pub mode: FogMode,
+    /// Linear RGB
+    pub color: glam::Vec3,
+    /// Distances from the camera where linear fog starts and becomes opaque
+    pub start: f32,
+    pub end: f32,
+    /// Of the exponential modes, per world unit
+    pub density: f32,
+    pub height: Option<HeightFog>,
+}
+
+impl Default for Fog {
+    fn default() -> Self {
+        Self {
+            mode: FogMode::Off,
+            color: glam::vec3(0.5, 0.6, 0.7),
+            start: 2.0,
+            end: 20.0,
+            density: 0.05,
+            height: None,
+        }
+    }
+}
+
+/// Scene in fog.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct SceneUniform {
+    fog_color: [f32; 3],
+    fog_mode: u32,
+    fog_start: f32,
+    fog_end: f32,
+    fog_density: f32,
+    /// 0 without height fog
+    height_density: f32,
+    height_base: f32,
+    height_falloff: f32,
+    _padding: [f32; 2],
+}
+
+/// Settings of the whole scene, uploaded to a uniform buffer by
+/// [`SceneSettings::update`] and read by fog.wgsl. Shaders get it through the
+/// bind group of [`crate::clustering::LightClusters`] or their own.
+pub struct SceneSettings {
+    pub fog: Fog,
+
+    buffer: wgpu::Buffer,
+}
+
+impl SceneSettings {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Scene buffer"),
+            contents: bytemuck::bytes_of::<SceneUniform>(&bytemuck::Zeroable::zeroed()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        Self {
+            fog: Fog::default(),
+            buffer,
+        }
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    pub fn update(&self, queue: &wgpu::Queue) {
+        let fog = &self.fog;
+        let height = fog.height.unwrap_or(HeightFog {
+            density: 0.0,
+            ..Default::default()
+        });
+        let uniform = SceneUniform {
+            fog_color: fog.color.to_array(),
+            // Matches the FOG_ constants in fog.wgsl
+            fog_mode: match fog.mode {
+                FogMode::Off => 0,
+                FogMode::Linear => 1,
+                FogMode::Exponential => 2,
+                FogMode::ExponentialSquared => 3,
+            },
+            fog_start: fog.start,
+            fog_end: fog.end,
+            fog_density: fog.density,
+            height_density: height.density,
+            height_base: height.base,
+            height_falloff: height.falloff,
+            _padding: [0.0; 2],
+        };
+        queue.write_buffer(&self.buffer, 0, bytemuck::bytes_of(&uniform));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::{offset_of, size_of};
+
+    use super::*;
+    use crate::preprocessor::Preprocessor;
+
+    #[test]
+    fn uniform_matches_the_shader() {
+        let source = Preprocessor::new()
+            .define("LIGHTS_GROUP", "0")
+            .process(
+                "fog.wgsl",
+                include_str!("../shaders/include/fog.wgsl"),
+                &mut |name| anyhow::bail!("Unexpected include of {}", name),
+            )
+            .unwrap();
+        let module = naga::front::wgsl::parse_str(&source).unwrap();
+        let (members, span) = module
+            .types
+            .iter()
+            .find_map(|(_, ty)| match &ty.inner {
+                naga::TypeInner::Struct { members, span }
+                    if ty.name.as_deref() == Some("Scene") =>
+                {
+                    Some((members, *span))
+                }
+                _ => None,
+            })
+            .unwrap();
+
+        let offsets = members
+            .iter()
+            .map(|member| (member.name.as_deref().unwrap(), member.offset as usize))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            offsets,
+            [
+                ("fog_color", offset_of!(SceneUniform, fog_color)),
+                ("fog_mode", offset_of!(SceneUniform, fog_mode)),
+                ("fog_start", offset_of!(SceneUniform, fog_start)),
+                ("fog_end", offset_of!(SceneUniform, fog_end)),
+                ("fog_density", offset_of!(SceneUniform, fog_density)),
+                ("height_density", offset_of!(SceneUniform, height_density)),
+                ("height_base", offset_of!(SceneUniform, height_base)),
+                ("height_falloff", offset_of!(SceneUniform, height_falloff)),
+            ]
+        );
+        assert_eq!(span as usize, size_of::<SceneUniform>());
+    }
+}
@@ -0,0 +1,512 @@
+use std::path::Path;
+
+use anyhow::*;
+
+use crate::{
+    assets::{AssetManager, Handle, ShaderSource},
+    preprocessor::Preprocessor,
+    reflect::Reflection,
+};
+
+/// Directory `#include`s are resolved in, relative to the asset root
+const INCLUDE_DIR: &str = "shaders/include";
+
+/// Files that can be `#include`d, embedded for release builds
+const INCLUDES: &[(&str, &str)] = &[
+    (
+        "camera.wgsl",
+        include_str!("../shaders/include/camera.wgsl"),
+    ),
+    (
+        "clusters.wgsl",
+        include_str!("../shaders/include/clusters.wgsl"),
+    ),
+    ("fog.wgsl", include_str!("../shaders/include/fog.wgsl")),
+    (
+        "fullscreen.wgsl",
+        include_str!("../shaders/include/fullscreen.wgsl"),
+    ),
+    (
+        "gbuffer.wgsl",
+        include_str!("../shaders/include/gbuffer.wgsl"),
+    ),
+    (
+        "instance.wgsl",
+        include_str!("../shaders/include/instance.wgsl"),
+    ),
+    (
+        "lights.wgsl",
+        include_str!("../shaders/include/lights.wgsl"),
+    ),
+    ("oit.wgsl", include_str!("../shaders/include/oit.wgsl")),
+    (
+        "postprocess.wgsl",
+        include_str!("../shaders/include/postprocess.wgsl"),
+    ),
+];
+
+/// A WGSL shader that is read from disk in debug builds, so it can be edited
+/// while the program is running, and embedded in the binary in release builds.
+///
+/// The source goes through the [`Preprocessor`] before being compiled, so one
+/// file can be compiled into several permutations using different defines.
+///
+/// If the files on disk don't compile the first time the shader is built, the
+/// error is logged and the embedded source is used until they're edited again.
+pub struct Shader {
+    label: &'static str,
+    embedded: &'static str,
+    preprocessor: Preprocessor,
+    /// Whether a module was ever created, after which the caller keeps the
+    /// previous one when the files stop compiling
+    built: bool,
+    /// Set when the files failed to compile before the shader was built
+    use_embedded: bool,
+
+    /// The shader file and every file it included the last time it was built
+    files: Vec<Handle<ShaderSource>>,
+    versions: Vec<u64>,
+}
+
+impl Shader {
+    /// `path` is relative to the asset root, `embedded` should be the
+    /// `include_str!` of the same file. It's also used if the file can't be
+    /// read or doesn't compile the first time.
+    pub fn new(path: &'static str, embedded: &'static str, preprocessor: Preprocessor) -> Self {
+        Self {
+            label: path,
+            embedded,
+            preprocessor,
+            built: false,
+            use_embedded: false,
+            files: Vec::new(),
+            versions: Vec::new(),
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        self.label
+    }
+
+    /// The same file with another define, e.g. to build a second permutation
+    pub fn with_define(&self, name: &str, value: &str) -> Self {
+        Self::new(
+            self.label,
+            self.embedded,
+            self.preprocessor.clone().define(name, value),
+        )
+    }
+
+    /// Returns true once after the shader or one of its includes was reloaded
+    pub fn changed(&mut self) -> bool {
+        let versions = self.files.iter().map(Handle::version).collect::<Vec<_>>();
+        let changed = versions != self.versions;
+        self.versions = versions;
+        if changed {
+            self.use_embedded = false;
+        }
+        changed
+    }
+
+    /// Preprocessed source code
+    fn code(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<String> {
+        let use_embedded = self.use_embedded;
+        let mut files = Vec::new();
+        let mut read = |path: &Path, embedded: Option<&str>| -> Result<String> {
+            if cfg!(debug_assertions) {
+                // Still watched while the embedded source is used, so editing
+                // the file gives it another try
+                let file = assets.load::<ShaderSource>(device, queue, path);
+                let code = file.get();
+                files.push(file);
+                match code {
+                    Some(source) if !use_embedded => return Ok(source.code.clone()),
+                    _ => {}
+                }
+            }
+            embedded
+                .map(str::to_owned)
+                .ok_or_else(|| anyhow!("Can't read {}", path.display()))
+        };
+
+        let source = read(Path::new(self.label), Some(self.embedded))?;
+        let code = self.preprocessor.process(self.label, &source, &mut |name| {
+            let embedded = INCLUDES
+                .iter()
+                .find(|(include, _)| *include == name)
+                .map(|(_, code)| *code);
+            read(&Path::new(INCLUDE_DIR).join(name), embedded)
+        });
+
+        self.files = files;
+        self.versions = self.files.iter().map(Handle::version).collect();
+        code
+    }
+
+    /// Runs `f` on the source code, falling back to the embedded source if
+    /// the files on disk fail before the shader was ever built
+    fn with_code<T>(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        f: impl Fn(String) -> Result<T>,
+    ) -> Result<T> {
+        let result = self.code(assets, device, queue).and_then(&f);
+        match result {
+            Err(e) if cfg!(debug_assertions) && !self.built && !self.use_embedded => {
+                log::error!("{:?}\nUsing the embedded {} instead", e, self.label);
+                self.use_embedded = true;
+                self.code(assets, device, queue).and_then(f)
+            }
+            result => result,
+        }
+    }
+
+    pub fn reflect(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<Reflection> {
+        let label = self.label;
+        self.with_code(assets, device, queue, |code| {
+            Reflection::from_wgsl(&code).with_context(|| format!("Failed to reflect {}", label))
+        })
+    }
+
+    /// Compiles the shader, returning the preprocessor, naga parsing or
+    /// validation error instead of panicking if it's invalid
+    pub fn create_module(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<wgpu::ShaderModule> {
+        let label = self.label;
+        let module = self.with_code(assets, device, queue, |code| {
+            capture_validation_errors(device, || {
+                device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                    label: Some(label),
+                    source: wgpu::ShaderSource::Wgsl(code.into()),
+                })
+            })
+            .with_context(|| format!("Failed to compile {}", label))
+        })?;
+        self.built = true;
+        Ok(module)
+    }
+}
+
+/// Runs `f`, turning any validation error it causes into an `Err` instead of
+/// the default behaviour of panicking
+pub fn capture_validation_errors<T>(device: &wgpu::Device, f: impl FnOnce() -> T) -> Result<T> {
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let value = f();
+    match async_std::task::block_on(device.pop_error_scope()) {
+        Some(error) => Err(anyhow!("{}", error)),
+        None => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{gizmos::LineVertex, instance::Instance, vertex::Vertex};
+
+    struct Permutation {
+        file: &'static str,
+        preprocessor: Preprocessor,
+        vertex_buffers: Vec<wgpu::VertexBufferLayout<'static>>,
+    }
+
+    /// Every shader in `shaders/` with the defines it's compiled with and the
+    /// vertex buffers it's drawn with
+    fn permutations() -> Vec<Permutation> {
+        vec![
+            Permutation {
+                file: "bloom.wgsl",
+                preprocessor: Preprocessor::new().define("PREFILTER", ""),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "bloom.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "bloom.wgsl",
+                preprocessor: Preprocessor::new().define("UPSAMPLE", ""),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "chromatic_aberration.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "cluster_lights.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "corners.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![Vertex::desc()],
+            },
+            Permutation {
+                file: "cull.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "debug.wgsl",
+                preprocessor: Preprocessor::new()
+                    .define("WIREFRAME", "")
+                    .define("PULL_VERTICES", ""),
+                vertex_buffers: vec![Instance::desc()],
+            },
+            Permutation {
+                file: "debug.wgsl",
+                preprocessor: Preprocessor::new().define("WIREFRAME", ""),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "debug.wgsl",
+                preprocessor: Preprocessor::new().define("NORMALS", ""),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "debug.wgsl",
+                preprocessor: Preprocessor::new().define("TEX_COORDS", ""),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "debug.wgsl",
+                preprocessor: Preprocessor::new()
+                    .define("DEPTH", "")
+                    .define("ZNEAR", "0.1")
+                    .define("ZFAR", "100.0"),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "debug.wgsl",
+                preprocessor: Preprocessor::new().define("OVERDRAW", ""),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "environment.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "environment.wgsl",
+                preprocessor: Preprocessor::new().define("IRRADIANCE", ""),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "environment.wgsl",
+                preprocessor: Preprocessor::new().define("PREFILTER", ""),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "environment.wgsl",
+                preprocessor: Preprocessor::new().define("BRDF_LUT", ""),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "fxaa.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "gizmo.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![LineVertex::desc()],
+            },
+            Permutation {
+                file: "grayscale.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "lighting.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "oit_composite.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "overdraw.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "pbr.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "pbr.wgsl",
+                preprocessor: Preprocessor::new()
+                    .define("ALPHA_MASK", "")
+                    .define("GBUFFER", ""),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "pbr.wgsl",
+                preprocessor: Preprocessor::new().define("OIT", ""),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "pbr.wgsl",
+                preprocessor: Preprocessor::new()
+                    .define("OIT", "")
+                    .define("ALPHA_BLEND", ""),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "sharpen.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "solid.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "solid.wgsl",
+                preprocessor: Preprocessor::new().define("TEXTURED", ""),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "solid.wgsl",
+                preprocessor: Preprocessor::new()
+                    .define("TEXTURED", "")
+                    .define("ALPHA_MASK", ""),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "solid.wgsl",
+                preprocessor: Preprocessor::new().define("OIT", ""),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "solid.wgsl",
+                preprocessor: Preprocessor::new()
+                    .define("TEXTURED", "")
+                    .define("TEXTURE_ARRAY", ""),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "solid.wgsl",
+                preprocessor: Preprocessor::new()
+                    .define("TEXTURED", "")
+                    .define("TEXTURE_ARRAY", "")
+                    .define("OIT", ""),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "solid.wgsl",
+                preprocessor: Preprocessor::new()
+                    .define("TEXTURED", "")
+                    .define("TEXTURE_ARRAY", "")
+                    .define("ALPHA_BLEND", ""),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "solid.wgsl",
+                preprocessor: Preprocessor::new()
+                    .define("TEXTURED", "")
+                    .define("ALPHA_MASK", "")
+                    .define("GBUFFER", ""),
+                vertex_buffers: vec![Vertex::desc(), Instance::desc()],
+            },
+            Permutation {
+                file: "ssao.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "ssao.wgsl",
+                preprocessor: Preprocessor::new().define("BLUR", ""),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "tonemap.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![],
+            },
+            Permutation {
+                file: "vignette.wgsl",
+                preprocessor: Preprocessor::new(),
+                vertex_buffers: vec![],
+            },
+        ]
+    }
+
+    fn shader_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("shaders")
+    }
+
+    fn reflect(permutation: &Permutation) -> Reflection {
+        let source = std::fs::read_to_string(shader_dir().join(permutation.file)).unwrap();
+        let code = permutation
+            .preprocessor
+            .process(permutation.file, &source, &mut |name| {
+                Ok(std::fs::read_to_string(
+                    shader_dir().join("include").join(name),
+                )?)
+            })
+            .unwrap();
+
+        Reflection::from_wgsl(&code)
+            .unwrap_or_else(|e| panic!("{} is invalid: {:?}", permutation.file, e))
+    }
+
+    #[test]
+    fn every_shader_is_tested() {
+        let permutations = permutations();
+        for entry in std::fs::read_dir(shader_dir()).unwrap() {
+            let path = entry.unwrap().path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "wgsl")
+            {
+                let file = path.file_name().unwrap().to_str().unwrap();
+                assert!(
+                    permutations.iter().any(|p| p.file == file),
+                    "{} is missing from the tested permutations",
+                    file
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn shaders_are_valid() {
+        for permutation in permutations() {
+            reflect(&permutation);
+        }
+    }
+
+    #[test]
+    fn vertex_inputs_match_vertex_buffers() {
+        for permutation in permutations() {
+            reflect(&permutation)
+                .check_vertex_buffers(&permutation.vertex_buffers)
+                .unwrap_or_else(|e| panic!("{}: {}", permutation.file, e));
+        }
+    }
+}
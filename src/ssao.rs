@@ -0,0 +1,374 @@
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    assets::AssetManager,
+    camera::Camera,
+    fullscreen::FullscreenPass,
+    pipeline::PipelineCache,
+    preprocessor::Preprocessor,
+    shader::{capture_validation_errors, Shader},
+    texture::Texture,
+};
+
+const AO_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+/// Size of the kernel in ssao.wgsl
+const MAX_SAMPLES: usize = 32;
+/// Width and height of the rotation noise, which the blur has to cover
+const NOISE_SIZE: u32 = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SsaoQuality {
+    Low,
+    Medium,
+    High,
+}
+
+impl SsaoQuality {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Low => Self::Medium,
+            Self::Medium => Self::High,
+            Self::High => Self::Low,
+        }
+    }
+
+    /// Kernel samples per pixel
+    fn samples(self) -> usize {
+        match self {
+            Self::Low => 8,
+            Self::Medium => 16,
+            Self::High => MAX_SAMPLES,
+        }
+    }
+}
+
+/// Params in ssao.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct SsaoParams {
+    proj: [[f32; 4]; 4],
+    inv_proj: [[f32; 4]; 4],
+    kernel: [[f32; 4]; MAX_SAMPLES],
+    radius: f32,
+    bias: f32,
+    intensity: f32,
+    sample_count: u32,
+}
+
+/// Screen space ambient occlusion.
+///
+/// Points in a hemisphere around the normal reconstructed from the depth
+/// buffer are compared against it, and the fraction that's behind other
+/// surfaces darkens the ambient light. Opaque materials and the deferred
+/// lighting pass read it from [`Ssao::view`], which is bound with the lights.
+/// The forward path draws a depth prepass to compute it from.
+pub struct Ssao {
+    pub enabled: bool,
+    pub quality: SsaoQuality,
+    /// Of the sampled hemisphere, in world units
+    pub radius: f32,
+    /// Depth difference below which samples don't occlude, to avoid acne on
+    /// flat surfaces
+    pub bias: f32,
+    /// Higher values darken occluded parts more
+    pub intensity: f32,
+
+    noise: wgpu::TextureView,
+    params_buffer: wgpu::Buffer,
+    /// The occlusion and the blurred occlusion
+    targets: [Texture; 2],
+
+    passes: Passes,
+    bind_groups: BindGroups,
+}
+
+struct Passes {
+    occlusion: FullscreenPass,
+    blur: FullscreenPass,
+}
+
+struct BindGroups {
+    occlusion: wgpu::BindGroup,
+    blur: wgpu::BindGroup,
+}
+
+impl Ssao {
+    pub fn new(
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        config: &wgpu::SurfaceConfiguration,
+        depth: &Texture,
+    ) -> Result<Self> {
+        let mut pass = |preprocessor, format, blend| {
+            FullscreenPass::new(
+                assets,
+                device,
+                queue,
+                pipelines,
+                Shader::new(
+                    "shaders/ssao.wgsl",
+                    include_str!("../shaders/ssao.wgsl"),
+                    preprocessor,
+                ),
+                Some(format),
+                blend,
+            )
+        };
+        let passes = Passes {
+            occlusion: pass(Preprocessor::new(), AO_FORMAT, None)?,
+            blur: pass(Preprocessor::new().define("BLUR", ""), AO_FORMAT, None)?,
+        };
+
+        let noise = device
+            .create_texture_with_data(
+                queue,
+                &wgpu::TextureDescriptor {
+                    label: Some("SSAO noise texture"),
+                    size: wgpu::Extent3d {
+                        width: NOISE_SIZE,
+                        height: NOISE_SIZE,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: wgpu::TextureFormat::Rgba8Snorm,
+                    usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                },
+                &noise(),
+            )
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("SSAO params buffer"),
+            contents: bytemuck::bytes_of::<SsaoParams>(&bytemuck::Zeroable::zeroed()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let targets = create_targets(device, config);
+        let bind_groups =
+            BindGroups::new(device, &passes, depth, &noise, &targets, &params_buffer)?;
+        Ok(Self {
+            enabled: true,
+            quality: SsaoQuality::Medium,
+            radius: 0.5,
+            bias: 0.025,
+            intensity: 1.5,
+            noise,
+            params_buffer,
+            targets,
+            passes,
+            bind_groups,
+        })
+    }
+
+    /// Has to be called after the depth texture is recreated
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        depth: &Texture,
+    ) {
+        self.targets = create_targets(device, config);
+        let bind_groups = BindGroups::new(
+            device,
+            &self.passes,
+            depth,
+            &self.noise,
+            &self.targets,
+            &self.params_buffer,
+        );
+        match bind_groups {
+            Result::Ok(bind_groups) => self.bind_groups = bind_groups,
+            Err(e) => log::error!("{:?}", e),
+        }
+    }
+
+    /// Uploads the settings and projection of `camera`, and rebuilds the
+    /// pipelines of edited shaders
+    pub fn update(
+        &mut self,
+        assets: &mut AssetManager,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        camera: &Camera,
+    ) {
+        let passes = &mut self.passes;
+        for pass in [&mut passes.occlusion, &mut passes.blur] {
+            pass.update(assets, device, queue, pipelines);
+        }
+
+        let proj = camera.build_proj_matrix();
+        let samples = kernel(self.quality.samples());
+        let mut params = SsaoParams {
+            proj: proj.to_cols_array_2d(),
+            inv_proj: proj.inverse().to_cols_array_2d(),
+            kernel: [[0.0; 4]; MAX_SAMPLES],
+            radius: self.radius,
+            bias: self.bias,
+            intensity: self.intensity,
+            sample_count: samples.len() as u32,
+        };
+        params.kernel[..samples.len()].copy_from_slice(&samples);
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::bytes_of(&params));
+    }
+
+    /// Blurred occlusion of the depth texture, written by [`Ssao::compute`]
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.targets[1].view
+    }
+
+    /// Computes the occlusion of the depth texture this was created with, or
+    /// clears it to unoccluded if disabled
+    pub fn compute(&self, pipelines: &PipelineCache, encoder: &mut wgpu::CommandEncoder) {
+        if !self.enabled {
+            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("SSAO Clear Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: self.view(),
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            return;
+        }
+
+        let (passes, bind_groups) = (&self.passes, &self.bind_groups);
+        passes.occlusion.draw(
+            pipelines,
+            encoder,
+            &self.targets[0].view,
+            &bind_groups.occlusion,
+        );
+        passes
+            .blur
+            .draw(pipelines, encoder, self.view(), &bind_groups.blur);
+    }
+}
+
+impl BindGroups {
+    fn new(
+        device: &wgpu::Device,
+        passes: &Passes,
+        depth: &Texture,
+        noise: &wgpu::TextureView,
+        targets: &[Texture; 2],
+        params_buffer: &wgpu::Buffer,
+    ) -> Result<Self> {
+        let bind_group = |pass: &FullscreenPass, entries: &[wgpu::BindGroupEntry]| {
+            capture_validation_errors(device, || {
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("SSAO bind group"),
+                    layout: pass.layout(),
+                    entries,
+                })
+            })
+        };
+        // Both passes read the depth, their input and the params
+        let entries = |input| {
+            [
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&depth.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(input),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: params_buffer.as_entire_binding(),
+                },
+            ]
+        };
+
+        Ok(Self {
+            occlusion: bind_group(&passes.occlusion, &entries(noise))?,
+            blur: bind_group(&passes.blur, &entries(&targets[0].view))?,
+        })
+    }
+}
+
+fn create_targets(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> [Texture; 2] {
+    [
+        Texture::create_render_target(device, config, AO_FORMAT, "SSAO texture"),
+        Texture::create_render_target(device, config, AO_FORMAT, "SSAO blur texture"),
+    ]
+}
+
+/// Xorshift generator, so the kernel and noise are the same every run
+struct Random(u32);
+
+impl Random {
+    /// In 0..1
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 8) as f32 / (1 << 24) as f32
+    }
+
+    /// In -1..1
+    fn signed(&mut self) -> f32 {
+        self.next() * 2.0 - 1.0
+    }
+}
+
+/// Offsets in the hemisphere around +z, getting longer so there are more
+/// samples close to the center, where occluders matter the most
+fn kernel(samples: usize) -> Vec<[f32; 4]> {
+    let mut random = Random(0x5EED);
+    (0..samples)
+        .map(|i| {
+            // Rejection sampling, so directions are spread evenly. Directions
+            // almost along the surface are skipped as they'd always hit it.
+            let direction = loop {
+                let v = glam::vec3(random.signed(), random.signed(), random.next());
+                if v.z > 0.1 && v.length_squared() <= 1.0 {
+                    break v.normalize();
+                }
+            };
+            let t = (i + 1) as f32 / samples as f32;
+            (direction * (0.1 + 0.9 * t * t)).extend(0.0).to_array()
+        })
+        .collect()
+}
+
+/// Random directions in the xy plane, as RGBA snorm bytes
+fn noise() -> Vec<u8> {
+    let mut random = Random(0xA0);
+    (0..NOISE_SIZE * NOISE_SIZE)
+        .flat_map(|_| {
+            let direction = glam::vec2(random.signed(), random.signed()).normalize_or_zero();
+            let [x, y] = (direction * 127.0).to_array().map(|v| v as i8 as u8);
+            [x, y, 0, 0]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_is_in_the_hemisphere() {
+        let kernel = kernel(MAX_SAMPLES);
+        assert_eq!(kernel.len(), MAX_SAMPLES);
+        let lengths = kernel
+            .iter()
+            .map(|&[x, y, z, _]| {
+                assert!(z > 0.0);
+                glam::vec3(x, y, z).length()
+            })
+            .collect::<Vec<_>>();
+        assert!(lengths.iter().all(|&length| length <= 1.0 + 1e-5));
+        assert!(lengths.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -1,47 +1,242 @@
+use std::{path::PathBuf, sync::Arc};
+
 use wgpu::util::DeviceExt;
-use winit::{event::WindowEvent, window::Window};
+use winit::{
+    event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent},
+    window::Window,
+};
 
 use crate::{
+    assets::{AssetManager, Handle, ShaderSource},
+    atlas::{Atlas, UvRect},
+    bloom::Bloom,
     camera::{Camera, CameraController},
+    clustering::LightClusters,
+    culling::{self, Aabb, Batch, CullStats, Frustum},
+    debug_view::{DebugView, DebugViews},
+    deferred::{Deferred, LightingInputs, RenderPath},
     deg_to_rad,
-    instance::Instance,
-    texture,
-    vertex::Vertex,
+    environment::Environment,
+    gizmos::Gizmos,
+    gpu_culling::GpuCulling,
+    hdr::Hdr,
+    instance::{Instance, InstanceRaw},
+    light::{Lights, PointLight},
+    lod::{Lod, LodMetric, LodSettings},
+    material::{
+        AlphaMode, Draw, Material, MaterialId, Materials, PipelineState, CAMERA_GROUP, LIGHTS_GROUP,
+    },
+    mesh::Mesh,
+    oit::{Oit, Transparency},
+    pbr::{self, PbrParams, PbrTextures},
+    pipeline::PipelineCache,
+    postprocess::{BuiltinEffects, EffectId, PostProcess, VignetteParams},
+    preprocessor::Preprocessor,
+    scene::{FogMode, HeightFog, SceneSettings},
+    shader::Shader,
+    ssao::Ssao,
+    texture::Texture,
+    texture_array::TextureArrayBuilder,
 };
 
 pub struct State {
     surface: wgpu::Surface,
+    adapter: wgpu::Adapter,
     device: wgpu::Device,
     queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
     pub size: winit::dpi::PhysicalSize<u32>,
+    depth_texture: Texture,
+    hdr: Hdr,
+    bloom: Bloom,
+    ssao: Ssao,
+    /// `Some` with [`RenderPath::Deferred`]. Debug views and GPU culling are
+    /// always drawn forward.
+    deferred: Option<Deferred>,
+    post_process: PostProcess,
+    effects: BuiltinEffects,
+    vignette: VignetteParams,
 
-    render_pipeline: wgpu::RenderPipeline,
-
-    diffuse_bind_group: wgpu::BindGroup,
-    diffuse_texture: texture::Texture,
-
-    vertex_buffer: wgpu::Buffer,
-    index_buffer: wgpu::Buffer,
-    num_vertices: u32,
-    num_indices: u32,
+    assets: AssetManager,
+    pipelines: PipelineCache,
+    materials: Materials,
+    tree_textures: TreeTextures,
+    /// Use the images of the tree atlas, in the same order
+    tree_materials: Vec<MaterialId>,
+    lights: Lights,
+    scene: SceneSettings,
+    environment: Environment,
+    light_clusters: LightClusters,
+    batches: Vec<Batch>,
+    /// Visible instances of the batches, rebuilt every frame
+    draws: Vec<Draw>,
+    cull_stats: CullStats,
+    /// Assets that failed to load the last time they were checked
+    failed_assets: Vec<PathBuf>,
+    lod: LodSettings,
+    debug_views: DebugViews,
+    pub gizmos: Gizmos,
+    show_gizmos: bool,
+    /// The camera's view projection when the gizmos were turned on, so what
+    /// it saw can be looked at from elsewhere
+    gizmo_frustum: glam::Mat4,
+    gpu_culling: GpuCulling,
+    use_gpu_culling: bool,
+    oit: Oit,
+    transparency: Transparency,
 
     camera: Camera,
     pub camera_controller: CameraController,
     camera_buffer: wgpu::Buffer,
     camera_bind_group: wgpu::BindGroup,
 
-    instances: Vec<Instance>,
     instance_buffer: wgpu::Buffer,
 
-    bg_color: wgpu::Color,
     last_time: std::time::Instant,
 }
 
 const NUM_INSTANCES_PER_ROW: u32 = 10;
 
+/// Uniform parameters of materials using solid.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SolidParams {
+    color: [f32; 4],
+    alpha_cutoff: f32,
+    metallic: f32,
+    roughness: f32,
+    _padding: f32,
+}
+
+impl SolidParams {
+    fn new(color: [f32; 4]) -> Self {
+        Self {
+            color,
+            alpha_cutoff: 0.5,
+            metallic: 0.0,
+            roughness: 0.5,
+            _padding: 0.0,
+        }
+    }
+}
+
+/// Parameters of the opaque tree materials, sampling `rect` of the tree atlas
+fn tree_params(rect: &UvRect) -> PbrParams {
+    PbrParams {
+        metallic: 0.0,
+        roughness: 0.6,
+        uv_offset: rect.min.to_array(),
+        uv_scale: rect.size().to_array(),
+        ..Default::default()
+    }
+}
+
+/// Where the pentagons switch to their low detail mesh, about 6 units from
+/// the camera
+fn pentagon_lod_threshold(metric: LodMetric) -> f32 {
+    match metric {
+        LodMetric::Distance => 6.0,
+        // The fraction of the screen height a pentagon covers from there
+        LodMetric::ScreenSize => 0.25,
+    }
+}
+
+/// Textures generated from tree.png, rebuilt when it's reloaded
+struct TreeTextures {
+    image: Handle<image::DynamicImage>,
+    version: u64,
+    /// Differently colored trees in one texture, so instances can pick one
+    /// while still being drawn with a single material
+    array: Handle<Texture>,
+    /// The full size tree and a smaller, differently colored one in one
+    /// texture, each material samples its part of it
+    atlas: Handle<Texture>,
+    atlas_rects: Vec<UvRect>,
+}
+
+impl TreeTextures {
+    /// Hue rotation of every layer of the texture array
+    const HUES: [i32; 3] = [0, 120, 240];
+
+    /// The image is loaded in the background, until then the textures are
+    /// built from a placeholder
+    fn new(assets: &mut AssetManager, device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let image = assets.load_async::<image::DynamicImage>("tree.png");
+        let (array, atlas) = Self::build(device, queue, &image).unwrap();
+        Self {
+            version: image.version(),
+            image,
+            array: Handle::from_asset("Tree texture array", array),
+            atlas: Handle::from_asset("Tree atlas", atlas.texture),
+            atlas_rects: atlas.rects,
+        }
+    }
+
+    /// Rebuilds the textures if the image was (re)loaded, returns whether it did.
+    /// If they can't be built from the new image the old ones are kept.
+    fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> bool {
+        if self.image.version() == self.version {
+            return false;
+        }
+        self.version = self.image.version();
+        match Self::build(device, queue, &self.image) {
+            Ok((array, atlas)) => {
+                self.array.replace(array);
+                self.atlas.replace(atlas.texture);
+                self.atlas_rects = atlas.rects;
+                true
+            }
+            Err(error) => {
+                log::error!("Failed to rebuild the tree textures: {:?}", error);
+                false
+            }
+        }
+    }
+
+    /// Builds the texture array and atlas, from a white placeholder if the
+    /// image isn't loaded so the trees are still drawn
+    fn build(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        image: &Handle<image::DynamicImage>,
+    ) -> anyhow::Result<(Texture, Atlas)> {
+        let image = image.get().unwrap_or_else(|| {
+            if !image.is_loading() {
+                log::error!(
+                    "{} failed to load, the trees use a white placeholder",
+                    image.path().display()
+                );
+            }
+            Arc::new(image::DynamicImage::ImageRgba8(
+                image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4])),
+            ))
+        });
+
+        let mut layers = TextureArrayBuilder::new(image.width(), image.height());
+        for hue in Self::HUES {
+            layers.push(&image.huerotate(hue))?;
+        }
+        let array = layers.build(device, queue, Some("Tree texture array"))?;
+
+        let small_image = image
+            .resize(
+                (image.width() / 2).max(1),
+                (image.height() / 2).max(1),
+                image::imageops::FilterType::Triangle,
+            )
+            .huerotate(60);
+        let atlas = Atlas::from_images(
+            device,
+            queue,
+            &[(*image).clone(), small_image],
+            Some("Tree atlas"),
+        )?;
+        Ok((array, atlas))
+    }
+}
+
 impl State {
-    pub async fn new(window: &Window) -> Self {
+    pub async fn new(window: &Window, path: RenderPath) -> Self {
         let size = window.inner_size();
 
         let instance = wgpu::Instance::new(wgpu::Backends::all());
@@ -59,7 +254,8 @@ impl State {
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::empty(),
+                    // Used by the wireframe debug view if available
+                    features: adapter.features() & wgpu::Features::POLYGON_MODE_LINE,
                     label: None,
                     limits: wgpu::Limits::default(),
                 },
@@ -76,48 +272,147 @@ impl State {
             present_mode: wgpu::PresentMode::Fifo,
         };
         surface.configure(&device, &config);
+        let depth_texture = Texture::create_depth_texture(&device, &config, "Depth texture");
 
-        let diffuse_bytes = include_bytes!("../tree.png");
-        let diffuse_texture =
-            texture::Texture::from_bytes(&device, &queue, diffuse_bytes, Some("Texture image"))
-                .unwrap();
+        let mut assets = AssetManager::new(env!("CARGO_MANIFEST_DIR"));
+        let tree_textures = TreeTextures::new(&mut assets, &device, &queue);
 
-        let diffuse_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("Texture Bind Group Layout"),
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        count: None,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            multisampled: false,
+        let mut pipelines = PipelineCache::new(config.format);
+        let mut materials = Materials::new(&device);
+        let pbr_shader = materials
+            .add_shader(
+                &mut assets,
+                &device,
+                &queue,
+                &mut pipelines,
+                Shader::new(
+                    "shaders/pbr.wgsl",
+                    include_str!("../shaders/pbr.wgsl"),
+                    Preprocessor::new(),
+                ),
+            )
+            .unwrap();
+        let solid_shader = materials
+            .add_shader(
+                &mut assets,
+                &device,
+                &queue,
+                &mut pipelines,
+                Shader::new(
+                    "shaders/solid.wgsl",
+                    include_str!("../shaders/solid.wgsl"),
+                    Preprocessor::new()
+                        .define("TEXTURED", "")
+                        .define("TEXTURE_ARRAY", ""),
+                ),
+            )
+            .unwrap();
+        for shader in [pbr_shader, solid_shader] {
+            materials
+                .enable_oit(&mut assets, &device, &queue, &mut pipelines, shader)
+                .unwrap();
+        }
+        if path == RenderPath::Deferred {
+            for shader in [pbr_shader, solid_shader] {
+                materials
+                    .enable_gbuffer(&mut assets, &device, &queue, &mut pipelines, shader)
+                    .unwrap();
+            }
+        }
+        let mut tree_materials = Vec::new();
+        for rect in &tree_textures.atlas_rects {
+            let material = materials
+                .add(
+                    &mut assets,
+                    &device,
+                    &queue,
+                    &mut pipelines,
+                    // Alpha tested, so the transparent parts of the texture cut out
+                    pbr::material(
+                        pbr_shader,
+                        &tree_params(rect),
+                        PbrTextures {
+                            base_color: tree_textures.atlas.clone(),
+                            ..PbrTextures::defaults(&device, &queue)
                         },
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        count: None,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    },
-                ],
-            });
-        let diffuse_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Texture Bind Group"),
-            layout: &diffuse_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                },
-            ],
-        });
+                    )
+                    .with_state(PipelineState {
+                        alpha_mode: AlphaMode::Mask,
+                        ..Default::default()
+                    }),
+                )
+                .unwrap();
+            tree_materials.push(material);
+        }
+        let solid_material = materials
+            .add(
+                &mut assets,
+                &device,
+                &queue,
+                &mut pipelines,
+                // Translucent and double sided, so it's drawn in the transparent
+                // pass with a separate pipeline
+                Material::new(solid_shader)
+                    .with_state(PipelineState {
+                        cull_mode: None,
+                        ..PipelineState::blended()
+                    })
+                    .with_texture(tree_textures.array.clone())
+                    .with_params(&SolidParams::new([1.0, 1.0, 1.0, 0.8])),
+            )
+            .unwrap();
+
+        let mut lights = Lights::new(&device);
+        lights.ambient = glam::Vec3::splat(0.3);
+        // Small colored lights spread over the grid in a spiral
+        let colors = [
+            glam::vec3(1.0, 0.3, 0.3),
+            glam::vec3(0.3, 1.0, 0.3),
+            glam::vec3(0.3, 0.3, 1.0),
+            glam::vec3(1.0, 1.0, 0.3),
+            glam::vec3(1.0, 0.3, 1.0),
+            glam::vec3(0.3, 1.0, 1.0),
+        ];
+        let light_count = 200;
+        lights.point_lights = (0..light_count)
+            .map(|i| {
+                // Golden angle, so neighbours don't line up
+                let angle = i as f32 * 2.4;
+                let radius = 7.0 * (i as f32 / light_count as f32).sqrt();
+                PointLight {
+                    position: glam::vec3(angle.cos() * radius, 0.5, angle.sin() * radius),
+                    color: colors[i % colors.len()],
+                    intensity: 6.0,
+                    range: 2.0,
+                }
+            })
+            .collect();
+        let mut scene = SceneSettings::new(&device);
+        scene.fog.mode = FogMode::Exponential;
+        let environment = Environment::new(&mut assets, &device, &queue, &mut pipelines).unwrap();
+        let ssao = Ssao::new(
+            &mut assets,
+            &device,
+            &queue,
+            &mut pipelines,
+            &config,
+            &depth_texture,
+        )
+        .unwrap();
+        let light_clusters = LightClusters::new(
+            &mut assets,
+            &device,
+            &queue,
+            materials.lights_layout(),
+            &LightingInputs {
+                lights: &lights,
+                environment: &environment,
+                scene: &scene,
+                depth: &depth_texture,
+                occlusion: ssao.view(),
+            },
+        )
+        .unwrap();
 
         let camera = Camera {
             eye: (0.0, 1.0, 2.0).into(),
@@ -133,26 +428,12 @@ impl State {
 
         let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Camera buffer"),
-            contents: bytemuck::cast_slice(&camera.build_vp_matrix().to_cols_array_2d()),
+            contents: bytemuck::bytes_of(&camera.to_uniform()),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
-        let camera_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("Camera bind group layout"),
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    count: None,
-                    visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                }],
-            });
         let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Camera bind group"),
-            layout: &camera_bind_group_layout,
+            layout: materials.camera_layout(),
             entries: &[wgpu::BindGroupEntry {
                 binding: 0,
                 resource: camera_buffer.as_entire_binding(),
@@ -174,108 +455,141 @@ impl State {
                         glam::Quat::from_axis_angle(position.normalize(), deg_to_rad(45.0))
                     };
 
-                    Instance { position, rotation }
+                    Instance {
+                        position,
+                        rotation,
+                        layer: (x + z) % TreeTextures::HUES.len() as u32,
+                    }
                 })
             })
             .collect::<Vec<_>>();
-        let instance_data = instances
-            .iter()
-            .map(Instance::to_matrix)
-            .collect::<Vec<_>>();
-        println!("{:?}", instance_data);
-        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        // Only the visible instances are written every frame
+        let instance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Instance buffer"),
-            contents: bytemuck::cast_slice(&instance_data),
-            usage: wgpu::BufferUsages::VERTEX,
+            size: (instances.len() * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
         });
 
-        let shader = device.create_shader_module(&wgpu::include_wgsl!("../shaders/solid.wgsl"));
-        let render_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Render Pipeline layout"),
-                bind_group_layouts: &[&diffuse_bind_group_layout, &camera_bind_group_layout],
-                push_constant_ranges: &[],
-            });
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &[Vertex::desc(), Instance::desc()],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[wgpu::ColorTargetState {
-                    format: config.format,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL,
-                }],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                // Requires Features::DEPTH_CLIP_CONTROL
-                unclipped_depth: false,
-                // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
-                polygon_mode: wgpu::PolygonMode::Fill,
-                // Requires Features::CONSERVATIVE_RASTERIZATION
-                conservative: false,
-            },
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
-        });
+        // Reloaded when the files change, like the textures
+        let mesh = assets.load_async::<Mesh>("pentagon.obj");
+        let lod_mesh = assets.load_async::<Mesh>("pentagon_lod.obj");
+        let lods = || {
+            vec![
+                Lod {
+                    mesh: mesh.clone(),
+                    threshold: pentagon_lod_threshold(LodSettings::default().metric),
+                },
+                Lod {
+                    mesh: lod_mesh.clone(),
+                    threshold: f32::INFINITY,
+                },
+            ]
+        };
 
-        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Pentagon Vertex Buffer"),
-            contents: bytemuck::cast_slice(crate::PENTAGON_VERTICES),
-            usage: wgpu::BufferUsages::VERTEX,
-        });
-        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Pentagon Index Buffer"),
-            contents: bytemuck::cast_slice(crate::PENTAGON_INDICES),
-            usage: wgpu::BufferUsages::INDEX,
+        // The back half of the grid is drawn translucent, every instance with
+        // its layer of the texture array. The front half is split between the
+        // two images of the atlas.
+        let mut instances = instances;
+        let solid_instances = instances.split_off(instances.len() / 2);
+        let small_tree_instances = instances.split_off(instances.len() / 2);
+        let batches = vec![
+            Batch::new(lods(), tree_materials[0], instances),
+            Batch::new(lods(), tree_materials[1], small_tree_instances),
+            Batch::new(lods(), solid_material, solid_instances),
+        ];
+
+        let debug_views = DebugViews::new(
+            &mut assets,
+            &device,
+            &queue,
+            &mut pipelines,
+            &config,
+            materials.camera_layout(),
+            &camera,
+        )
+        .unwrap();
+        let gpu_culling = GpuCulling::new(&mut assets, &device, &queue, &batches).unwrap();
+        let gizmos = Gizmos::new(
+            &mut assets,
+            &device,
+            &queue,
+            &mut pipelines,
+            materials.camera_layout(),
+        )
+        .unwrap();
+        let oit = Oit::new(&mut assets, &device, &queue, &mut pipelines, &config).unwrap();
+        let hdr = Hdr::new(&mut assets, &device, &queue, &mut pipelines, &config).unwrap();
+        let bloom =
+            Bloom::new(&mut assets, &device, &queue, &mut pipelines, &config, &hdr).unwrap();
+        let deferred = (path == RenderPath::Deferred).then(|| {
+            Deferred::new(
+                &mut assets,
+                &device,
+                &queue,
+                &mut pipelines,
+                &config,
+                &LightingInputs {
+                    lights: &lights,
+                    environment: &environment,
+                    scene: &scene,
+                    depth: &depth_texture,
+                    occlusion: ssao.view(),
+                },
+            )
+            .unwrap()
         });
+        let mut post_process = PostProcess::new(&device, &config);
+        let effects = post_process
+            .add_builtin_effects(&mut assets, &device, &queue, &mut pipelines)
+            .unwrap();
 
         Self {
             surface,
+            adapter,
             device,
             queue,
             config,
             size,
+            depth_texture,
+            hdr,
+            bloom,
+            ssao,
+            deferred,
+            post_process,
+            effects,
+            vignette: VignetteParams::default(),
 
-            render_pipeline,
-
-            diffuse_bind_group,
-            diffuse_texture,
-
-            vertex_buffer,
-            index_buffer,
-            num_vertices: crate::PENTAGON_VERTICES.len() as u32,
-            num_indices: crate::PENTAGON_INDICES.len() as u32,
+            assets,
+            pipelines,
+            materials,
+            tree_textures,
+            tree_materials,
+            lights,
+            scene,
+            environment,
+            light_clusters,
+            batches,
+            draws: Vec::new(),
+            cull_stats: CullStats::default(),
+            failed_assets: Vec::new(),
+            lod: LodSettings::default(),
+            debug_views,
+            gizmos,
+            show_gizmos: false,
+            gizmo_frustum: glam::Mat4::IDENTITY,
+            gpu_culling,
+            use_gpu_culling: false,
+            oit,
+            transparency: Transparency::Sorted,
 
             camera,
             camera_controller,
             camera_buffer,
             camera_bind_group,
 
-            instances,
             instance_buffer,
 
-            bg_color: wgpu::Color {
-                r: 0.1,
-                g: 0.2,
-                b: 0.2,
-                a: 1.0,
-            },
             last_time: std::time::Instant::now(),
         }
     }
@@ -284,34 +598,515 @@ impl State {
         self.size = new_size;
         self.config.width = new_size.width;
         self.config.height = new_size.height;
+        // The preferred format can change, e.g. when moving to another monitor
+        if let Some(format) = self.surface.get_preferred_format(&self.adapter) {
+            self.config.format = format;
+        }
         self.surface.configure(&self.device, &self.config);
+        self.depth_texture =
+            Texture::create_depth_texture(&self.device, &self.config, "Depth texture");
+        self.ssao
+            .resize(&self.device, &self.config, &self.depth_texture);
+        let inputs = LightingInputs {
+            lights: &self.lights,
+            environment: &self.environment,
+            scene: &self.scene,
+            depth: &self.depth_texture,
+            occlusion: self.ssao.view(),
+        };
+        self.light_clusters
+            .resize(&self.device, self.materials.lights_layout(), &inputs);
+        if let Some(deferred) = &mut self.deferred {
+            deferred.resize(&self.device, &self.config, &inputs);
+        }
+        self.pipelines.set_surface_format(self.config.format);
+        self.oit.resize(&self.device, &self.config);
+        self.debug_views.resize(&self.device, &self.config);
+        self.hdr.resize(&self.device, &self.config);
+        self.bloom.resize(&self.device, &self.config, &self.hdr);
+        self.post_process.resize(&self.device, &self.config);
     }
 
     pub fn input(&mut self, event: &WindowEvent) {
-        match event {
-            WindowEvent::CursorMoved { position, .. } => {
-                // self.bg_color.r = position.x / self.size.width as f64;
-                // self.bg_color.g = position.y / self.size.height as f64;
-                // println!("{:?}", position);
+        self.camera_controller.process_events(event);
+
+        if let WindowEvent::KeyboardInput {
+            input:
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(key),
+                    ..
+                },
+            ..
+        } = event
+        {
+            match key {
+                VirtualKeyCode::F1 => {
+                    self.debug_views.view = self.debug_views.view.next();
+                    log::info!("Debug view: {:?}", self.debug_views.view);
+                }
+                VirtualKeyCode::F2 => {
+                    self.show_gizmos = !self.show_gizmos;
+                    self.gizmo_frustum = self.camera.build_vp_matrix();
+                }
+                VirtualKeyCode::F3 => {
+                    self.use_gpu_culling = !self.use_gpu_culling;
+                    log::info!("GPU culling: {}", self.use_gpu_culling);
+                }
+                VirtualKeyCode::F4 => {
+                    self.transparency = self.transparency.next();
+                    log::info!("Transparency: {:?}", self.transparency);
+                }
+                VirtualKeyCode::F5 => {
+                    self.hdr.tonemapper = self.hdr.tonemapper.next();
+                    log::info!("Tonemapper: {:?}", self.hdr.tonemapper);
+                }
+                VirtualKeyCode::F6 => {
+                    self.bloom.enabled = !self.bloom.enabled;
+                    log::info!("Bloom: {}", self.bloom.enabled);
+                }
+                VirtualKeyCode::F7 => {
+                    self.ssao.enabled = !self.ssao.enabled;
+                    log::info!("SSAO: {}", self.ssao.enabled);
+                }
+                VirtualKeyCode::F8 => {
+                    self.ssao.quality = self.ssao.quality.next();
+                    log::info!("SSAO quality: {:?}", self.ssao.quality);
+                }
+                VirtualKeyCode::F9 => {
+                    self.scene.fog.mode = self.scene.fog.mode.next();
+                    log::info!("Fog: {:?}", self.scene.fog.mode);
+                }
+                VirtualKeyCode::F10 => {
+                    self.scene.fog.height = match self.scene.fog.height {
+                        Some(_) => None,
+                        None => Some(HeightFog::default()),
+                    };
+                    log::info!("Height fog: {}", self.scene.fog.height.is_some());
+                }
+                VirtualKeyCode::F11 => {
+                    self.lod.metric = self.lod.metric.next();
+                    let threshold = pentagon_lod_threshold(self.lod.metric);
+                    for batch in &mut self.batches {
+                        batch.lods[0].threshold = threshold;
+                    }
+                    log::info!("LOD metric: {:?}", self.lod.metric);
+                }
+                VirtualKeyCode::Minus | VirtualKeyCode::Equals => {
+                    let step = if *key == VirtualKeyCode::Minus {
+                        -0.5
+                    } else {
+                        0.5
+                    };
+                    self.hdr.exposure += step;
+                    log::info!("Exposure: {}", self.hdr.exposure);
+                }
+                VirtualKeyCode::Key1 => self.toggle_effect("Sharpen", self.effects.sharpen),
+                VirtualKeyCode::Key2 => {
+                    self.toggle_effect("Chromatic aberration", self.effects.chromatic_aberration)
+                }
+                VirtualKeyCode::Key3 => self.toggle_effect("Grayscale", self.effects.grayscale),
+                VirtualKeyCode::Key4 => self.toggle_effect("Vignette", self.effects.vignette),
+                VirtualKeyCode::Key5 => self.toggle_effect("FXAA", self.effects.fxaa),
+                VirtualKeyCode::LBracket | VirtualKeyCode::RBracket => {
+                    let step = if *key == VirtualKeyCode::LBracket {
+                        -0.1
+                    } else {
+                        0.1
+                    };
+                    self.vignette.intensity = (self.vignette.intensity + step).clamp(0.0, 1.0);
+                    self.post_process.set_params(
+                        &self.queue,
+                        self.effects.vignette,
+                        &self.vignette,
+                    );
+                    log::info!("Vignette intensity: {}", self.vignette.intensity);
+                }
+                _ => {}
             }
-            _ => {}
         }
-        self.camera_controller.process_events(event);
+    }
+
+    fn toggle_effect(&mut self, name: &str, id: EffectId) {
+        let enabled = !self.post_process.is_enabled(id);
+        self.post_process.set_enabled(id, enabled);
+        log::info!("{}: {}", name, enabled);
+    }
+
+    /// The fog color, so distant geometry fades into the background
+    fn clear_color(&self) -> wgpu::Color {
+        let [r, g, b] = self.scene.fog.color.to_array().map(f64::from);
+        wgpu::Color { r, g, b, a: 1.0 }
+    }
+
+    /// Debug views always draw the instances culled on the CPU
+    fn gpu_culling_active(&self) -> bool {
+        self.use_gpu_culling && self.debug_views.view == DebugView::Off
     }
 
     pub fn update(&mut self) {
         let current_time = std::time::Instant::now();
         let delta_time = current_time.duration_since(self.last_time).as_secs_f32();
         self.last_time = current_time;
+        self.assets.update(&self.device, &self.queue);
+        self.report_asset_errors();
+        if self.tree_textures.update(&self.device, &self.queue) {
+            // The images can move around the atlas if their size changed
+            for (&material, rect) in self
+                .tree_materials
+                .iter()
+                .zip(&self.tree_textures.atlas_rects)
+            {
+                self.materials
+                    .set_params(&self.queue, material, &tree_params(rect));
+            }
+        }
+        self.materials.update(
+            &mut self.assets,
+            &self.device,
+            &self.queue,
+            &mut self.pipelines,
+        );
+        self.debug_views.update(
+            &mut self.assets,
+            &self.device,
+            &self.queue,
+            &mut self.pipelines,
+            &self.draws,
+        );
+        self.oit.update(
+            &mut self.assets,
+            &self.device,
+            &self.queue,
+            &mut self.pipelines,
+        );
+        // Debug views show data, which tonemapping would distort
+        self.hdr.passthrough = self.debug_views.view != DebugView::Off;
+        self.hdr.update(
+            &mut self.assets,
+            &self.device,
+            &self.queue,
+            &mut self.pipelines,
+        );
+        self.bloom.update(
+            &mut self.assets,
+            &self.device,
+            &self.queue,
+            &mut self.pipelines,
+        );
+        self.post_process.update(
+            &mut self.assets,
+            &self.device,
+            &self.queue,
+            &mut self.pipelines,
+        );
         self.camera_controller
             .update_camera(&mut self.camera, delta_time);
+        self.ssao.update(
+            &mut self.assets,
+            &self.device,
+            &self.queue,
+            &mut self.pipelines,
+            &self.camera,
+        );
+        let clear_color = self.clear_color();
+        if let Some(deferred) = &mut self.deferred {
+            deferred.update(
+                &mut self.assets,
+                &self.device,
+                &self.queue,
+                &mut self.pipelines,
+                &self.camera,
+                clear_color,
+            );
+        }
+        self.lights.update(&self.queue);
+        self.scene.update(&self.queue);
+        self.light_clusters.update(
+            &mut self.assets,
+            &self.device,
+            &self.queue,
+            &self.camera,
+            &self.config,
+        );
         self.queue.write_buffer(
             &self.camera_buffer,
             0,
-            bytemuck::cast_slice(&self.camera.build_vp_matrix().to_cols_array_2d()),
+            bytemuck::bytes_of(&self.camera.to_uniform()),
+        );
+        if self.gpu_culling_active() {
+            let frustum = Frustum::from_matrix(self.camera.build_vp_matrix());
+            self.gpu_culling
+                .update(&mut self.assets, &self.device, &self.queue, &frustum);
+        } else {
+            self.cull();
+        }
+
+        if self.show_gizmos {
+            self.draw_gizmos();
+        }
+        self.gizmos.prepare(
+            &mut self.assets,
+            &self.device,
+            &self.queue,
+            &mut self.pipelines,
         );
     }
 
+    /// Adds the debug shapes of the scene to the gizmos
+    fn draw_gizmos(&mut self) {
+        let ground = glam::vec3(0.0, -0.5, 0.0);
+        self.gizmos.depth_test = true;
+        self.gizmos.grid(ground, 10, 1.0, [0.5, 0.5, 0.5, 1.0]);
+        // Bounds of all the instances of every batch, culled or not
+        for batch in &self.batches {
+            let mesh = match batch.lods[0].mesh.get() {
+                Some(mesh) => mesh,
+                None => continue,
+            };
+            let bounds = Aabb::from_points(batch.instances.iter().flat_map(|instance| {
+                let matrix = glam::Mat4::from_cols_array_2d(&instance.to_raw().model);
+                let bounds = mesh.bounds.transformed(&matrix);
+                [bounds.min, bounds.max]
+            }));
+            if let Some(bounds) = bounds {
+                self.gizmos
+                    .aabb(bounds.min, bounds.max, [1.0, 1.0, 0.0, 1.0]);
+            }
+        }
+        for light in &self.lights.point_lights {
+            self.gizmos
+                .sphere(light.position, light.range, light.color.extend(1.0).into());
+        }
+        // Where the pentagons switch to their low detail mesh
+        if self.lod.metric == LodMetric::Distance {
+            let center = self.camera.eye * glam::vec3(1.0, 0.0, 1.0) + ground;
+            let radius = pentagon_lod_threshold(LodMetric::Distance);
+            self.gizmos
+                .circle(center, glam::Vec3::Y, radius, [0.0, 1.0, 1.0, 1.0]);
+        }
+        self.gizmos
+            .frustum(self.gizmo_frustum, [1.0, 0.5, 0.0, 1.0]);
+
+        self.gizmos.depth_test = false;
+        self.gizmos.axes(glam::Mat4::IDENTITY, 1.0);
+    }
+
+    /// Logs which assets are failing to load whenever that changes
+    fn report_asset_errors(&mut self) {
+        let mut errors = self.assets.errors::<Mesh>();
+        errors.extend(self.assets.errors::<Texture>());
+        errors.extend(self.assets.errors::<image::DynamicImage>());
+        errors.extend(self.assets.errors::<ShaderSource>());
+        errors.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let failed = errors
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        if failed == self.failed_assets {
+            return;
+        }
+        if errors.is_empty() {
+            log::info!("Every asset loaded");
+        }
+        for (path, error) in &errors {
+            log::warn!("{} isn't loaded: {}", path.display(), error);
+        }
+        self.failed_assets = failed;
+    }
+
+    /// Rebuilds the draws and instance buffer from the instances the camera can
+    /// see
+    fn cull(&mut self) {
+        let mut instance_data = Vec::new();
+        self.draws.clear();
+        let stats = culling::cull(
+            &self.camera,
+            &self.lod,
+            self.transparency,
+            &self.materials,
+            &mut self.batches,
+            &mut instance_data,
+            &mut self.draws,
+        );
+        self.materials.sort(&mut self.draws, self.transparency);
+        self.queue.write_buffer(
+            &self.instance_buffer,
+            0,
+            bytemuck::cast_slice(&instance_data),
+        );
+
+        if stats != self.cull_stats {
+            log::debug!("Drawn {} instances, culled {}", stats.drawn, stats.culled);
+            self.cull_stats = stats;
+        }
+    }
+
+    fn opaque_draws(&self) -> &[Draw] {
+        &self.draws[..self.materials.transparent_start(&self.draws)]
+    }
+
+    /// Draws blended materials over the opaque ones, testing against their
+    /// depth without writing to it
+    fn render_transparent(&self, encoder: &mut wgpu::CommandEncoder) {
+        let draws = &self.draws[self.materials.transparent_start(&self.draws)..];
+        if draws.is_empty() {
+            return;
+        }
+
+        if self.transparency == Transparency::WeightedBlended {
+            {
+                let mut render_pass = self
+                    .oit
+                    .accumulation_pass(encoder, &self.depth_texture.view);
+                render_pass.set_bind_group(CAMERA_GROUP, &self.camera_bind_group, &[]);
+                render_pass.set_bind_group(LIGHTS_GROUP, self.light_clusters.bind_group(), &[]);
+                render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+                self.materials
+                    .draw_oit(&self.pipelines, &mut render_pass, draws);
+            }
+            self.oit
+                .composite(&self.pipelines, encoder, self.hdr.view());
+            return;
+        }
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Transparent Pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: self.hdr.view(),
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_bind_group(CAMERA_GROUP, &self.camera_bind_group, &[]);
+        render_pass.set_bind_group(LIGHTS_GROUP, self.light_clusters.bind_group(), &[]);
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+        self.materials
+            .draw(&self.pipelines, &mut render_pass, draws);
+    }
+
+    /// Starts a pass drawing to the HDR and depth targets, clearing them to
+    /// `clear_color` and the far plane, or keeping their contents if `None`
+    fn begin_forward_pass<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+        label: &'static str,
+        clear_color: Option<wgpu::Color>,
+    ) -> wgpu::RenderPass<'a> {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(label),
+            color_attachments: &[
+                // This is what [[location(0)]] in the fragment shader targets
+                wgpu::RenderPassColorAttachment {
+                    view: self.hdr.view(),
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: clear_color.map_or(wgpu::LoadOp::Load, wgpu::LoadOp::Clear),
+                        store: true,
+                    },
+                },
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: match clear_color {
+                        Some(_) => wgpu::LoadOp::Clear(1.0),
+                        None => wgpu::LoadOp::Load,
+                    },
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_bind_group(CAMERA_GROUP, &self.camera_bind_group, &[]);
+        render_pass.set_bind_group(LIGHTS_GROUP, self.light_clusters.bind_group(), &[]);
+        render_pass
+    }
+
+    /// Draws the opaque scene, or the debug view, to the HDR target with the
+    /// materials' forward pipelines. The scene's depth is drawn first, so the
+    /// ambient occlusion the materials read can be computed from it.
+    fn render_forward(&self, encoder: &mut wgpu::CommandEncoder, clear_color: wgpu::Color) {
+        let prepass = self.debug_views.view == DebugView::Off;
+        if prepass {
+            // Extra block required because begin_render_pass takes &mut self
+            // encoder.finish is only callable after the borrow is released
+            {
+                let mut render_pass =
+                    self.begin_forward_pass(encoder, "Depth Prepass", Some(clear_color));
+                if self.gpu_culling_active() {
+                    self.gpu_culling
+                        .draw(&self.pipelines, &self.materials, &mut render_pass, true);
+                } else {
+                    render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+                    self.materials.draw_depth(
+                        &self.pipelines,
+                        &mut render_pass,
+                        self.opaque_draws(),
+                    );
+                }
+            }
+            self.ssao.compute(&self.pipelines, encoder);
+        }
+
+        {
+            let mut render_pass =
+                self.begin_forward_pass(encoder, "Render Pass", (!prepass).then_some(clear_color));
+            if self.gpu_culling_active() {
+                // Blended instances aren't sorted when culled on the GPU
+                self.gpu_culling
+                    .draw(&self.pipelines, &self.materials, &mut render_pass, false);
+            } else if self.debug_views.view == DebugView::Off {
+                render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+                self.materials
+                    .draw(&self.pipelines, &mut render_pass, self.opaque_draws());
+            } else if self.debug_views.view != DebugView::Overdraw {
+                self.debug_views.draw(
+                    &self.pipelines,
+                    &mut render_pass,
+                    &self.draws,
+                    &self.instance_buffer,
+                );
+            }
+        }
+        if self.debug_views.view == DebugView::Overdraw {
+            self.debug_views.draw_overdraw(
+                &self.pipelines,
+                encoder,
+                self.hdr.view(),
+                &self.camera_bind_group,
+                &self.draws,
+                &self.instance_buffer,
+            );
+        }
+    }
+
+    /// Draws the opaque scene to the G-buffer and lights it into the HDR target
+    fn render_deferred(&self, deferred: &Deferred, encoder: &mut wgpu::CommandEncoder) {
+        {
+            let mut render_pass = deferred.gbuffer_pass(encoder, &self.depth_texture.view);
+            render_pass.set_bind_group(CAMERA_GROUP, &self.camera_bind_group, &[]);
+            render_pass.set_bind_group(LIGHTS_GROUP, self.light_clusters.bind_group(), &[]);
+            render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+            self.materials
+                .draw_gbuffer(&self.pipelines, &mut render_pass, self.opaque_draws());
+        }
+        self.ssao.compute(&self.pipelines, encoder);
+        deferred.lighting(&self.pipelines, encoder, self.hdr.view());
+    }
+
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
         let output = self.surface.get_current_texture()?;
         let view = output
@@ -323,33 +1118,45 @@ impl State {
                 label: Some("Render Encoder"),
             });
 
-        // Extra block required because begin_render_pass takes &mut self
-        // encoder.finish is only callable after the borrow is released
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
-                color_attachments: &[
-                    // This is what [[location(0)]] in the fragment shader targets
-                    wgpu::RenderPassColorAttachment {
-                        view: &view,
-                        resolve_target: None,
-                        ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Clear(self.bg_color),
-                            store: true,
-                        },
-                    },
-                ],
-                depth_stencil_attachment: None,
-            });
+        let clear_color = match self.debug_views.view {
+            DebugView::Off => self.clear_color(),
+            _ => wgpu::Color::BLACK,
+        };
 
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
-            render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
-            render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-            render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
-            render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
-            render_pass.draw_indexed(0..self.num_indices, 0, 0..self.instances.len() as u32);
+        if self.gpu_culling_active() {
+            self.gpu_culling.cull(&mut encoder);
         }
+        self.light_clusters.cluster(&mut encoder);
+
+        let deferred = self
+            .deferred
+            .as_ref()
+            .filter(|_| !self.gpu_culling_active() && self.debug_views.view == DebugView::Off);
+        match deferred {
+            Some(deferred) => self.render_deferred(deferred, &mut encoder),
+            None => self.render_forward(&mut encoder, clear_color),
+        }
+        if !self.gpu_culling_active() && self.debug_views.view == DebugView::Off {
+            self.render_transparent(&mut encoder);
+        }
+        if self.debug_views.view == DebugView::Off {
+            self.bloom.render(&self.pipelines, &mut encoder, &self.hdr);
+            let tonemapped = self.post_process.input().unwrap_or(&view);
+            self.hdr.render(&self.pipelines, &mut encoder, tonemapped);
+            self.post_process
+                .render(&self.pipelines, &mut encoder, &view);
+        } else {
+            // Copied straight to the surface, skipping the post effects
+            self.hdr.render(&self.pipelines, &mut encoder, &view);
+        }
+        self.gizmos.render(
+            &self.pipelines,
+            &mut encoder,
+            &view,
+            &self.depth_texture.view,
+            &self.camera_bind_group,
+        );
+
         self.queue.submit(std::iter::once(encoder.finish()));
         output.present();
 
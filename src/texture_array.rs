@@ -0,0 +1,154 @@
+use anyhow::*;
+use image::GenericImageView;
+
+use crate::texture::Texture;
+
+/// Collects same-sized images and uploads them as the layers of a single
+/// `D2Array` texture, so objects using different images can share one bind group
+/// and pick their image by layer index in the shader.
+pub struct TextureArrayBuilder {
+    width: u32,
+    height: u32,
+    layers: Vec<image::RgbaImage>,
+}
+
+impl TextureArrayBuilder {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            layers: Vec::new(),
+        }
+    }
+
+    /// Adds a layer and returns its index
+    pub fn push(&mut self, img: &image::DynamicImage) -> Result<u32> {
+        let dimensions = img.dimensions();
+        if dimensions.0 == 0 || dimensions.1 == 0 {
+            bail!("Texture array layers can't be empty");
+        }
+        if dimensions != (self.width, self.height) {
+            bail!(
+                "Texture array layer is {}x{}, expected {}x{}",
+                dimensions.0,
+                dimensions.1,
+                self.width,
+                self.height
+            );
+        }
+        self.layers.push(img.to_rgba8());
+        Ok(self.layers.len() as u32 - 1)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.layers.len() as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    pub fn build(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        label: Option<&str>,
+    ) -> Result<Texture> {
+        if self.is_empty() {
+            bail!("Texture array has no layers");
+        }
+        let max_layers = device.limits().max_texture_array_layers;
+        if self.len() > max_layers {
+            bail!(
+                "Texture array has {} layers, the device supports {}",
+                self.len(),
+                max_layers
+            );
+        }
+
+        let size = wgpu::Extent3d {
+            width: self.width,
+            height: self.height,
+            depth_or_array_layers: self.len(),
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label,
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
+
+        for (layer, rgba) in self.layers.iter().enumerate() {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: layer as u32,
+                    },
+                },
+                rgba,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(4 * self.width),
+                    rows_per_image: std::num::NonZeroU32::new(self.height),
+                },
+                wgpu::Extent3d {
+                    width: self.width,
+                    height: self.height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        // Without an explicit dimension a single layer array would get a D2 view
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let sampler = Texture::create_sampler(device);
+
+        Ok(Texture {
+            texture,
+            view,
+            sampler,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: u32, height: u32) -> image::DynamicImage {
+        image::DynamicImage::new_rgba8(width, height)
+    }
+
+    #[test]
+    fn numbers_layers_in_order() {
+        let mut builder = TextureArrayBuilder::new(4, 2);
+        assert_eq!(builder.push(&image(4, 2)).unwrap(), 0);
+        assert_eq!(builder.push(&image(4, 2)).unwrap(), 1);
+        assert_eq!(builder.len(), 2);
+    }
+
+    #[test]
+    fn rejects_layers_of_another_size() {
+        let mut builder = TextureArrayBuilder::new(4, 2);
+        assert!(builder.push(&image(2, 4)).is_err());
+        assert!(builder.is_empty());
+    }
+
+    #[test]
+    fn rejects_empty_layers() {
+        let mut builder = TextureArrayBuilder::new(0, 0);
+        assert!(builder.push(&image(0, 0)).is_err());
+        assert!(builder.is_empty());
+    }
+}
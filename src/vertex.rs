@@ -3,13 +3,25 @@
 pub struct Vertex {
     pub position: [f32; 3],
     pub tex_coords: [f32; 2],
+    pub normal: [f32; 3],
+    /// Direction of increasing u on the surface, see
+    /// [`crate::mesh::MeshData::generate_tangents`]
+    pub tangent: [f32; 3],
+    /// Direction of decreasing v on the surface, towards the top of the
+    /// texture, so normal maps with green pointing up work like in glTF
+    pub bitangent: [f32; 3],
 }
 
 impl Vertex {
     // Required because rust sees the result of vertex_attr_array as a temporary value
     // so it can't be returned from a function
-    const ATTRIBS: [wgpu::VertexAttribute; 2] =
-        wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x2];
+    const ATTRIBS: [wgpu::VertexAttribute; 5] = wgpu::vertex_attr_array![
+        0 => Float32x3,
+        1 => Float32x2,
+        2 => Float32x3,
+        3 => Float32x3,
+        4 => Float32x3,
+    ];
 
     pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         wgpu::VertexBufferLayout {
@@ -0,0 +1,117 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Detects file changes by polling modification times.
+///
+/// Polling is only done every `interval`, so calling [`FileWatcher::poll`] every
+/// frame is cheap.
+pub struct FileWatcher {
+    files: HashMap<PathBuf, Option<SystemTime>>,
+    interval: Duration,
+    last_poll: Instant,
+}
+
+impl FileWatcher {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            files: HashMap::new(),
+            interval,
+            last_poll: Instant::now(),
+        }
+    }
+
+    pub fn watch(&mut self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        let modified = modified_time(&path);
+        self.files.insert(path, modified);
+    }
+
+    /// Returns the files modified since the last poll. A file that can't be read
+    /// (e.g. an editor is in the middle of replacing it) is reported once it
+    /// reappears.
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        if self.last_poll.elapsed() < self.interval {
+            return Vec::new();
+        }
+        self.last_poll = Instant::now();
+
+        let mut changed = Vec::new();
+        for (path, last_modified) in self.files.iter_mut() {
+            let modified = modified_time(path);
+            if modified.is_some() && modified != *last_modified {
+                changed.push(path.clone());
+            }
+            *last_modified = modified;
+        }
+        changed
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    /// A file in the temp directory, unique to the test and process
+    fn temp_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("wgpu_book_{}_{}", std::process::id(), name));
+        std::fs::write(&path, "Before").unwrap();
+        path
+    }
+
+    fn touch(path: &Path, seconds: u64) {
+        let file = File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(seconds))
+            .unwrap();
+    }
+
+    #[test]
+    fn reports_modified_files_once() {
+        let path = temp_file("modified.txt");
+        let mut watcher = FileWatcher::new(Duration::ZERO);
+        watcher.watch(path.clone());
+        assert!(watcher.poll().is_empty());
+
+        touch(&path, 10);
+        assert_eq!(watcher.poll(), std::slice::from_ref(&path));
+        assert!(watcher.poll().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reports_replaced_files_once_they_reappear() {
+        let path = temp_file("replaced.txt");
+        let mut watcher = FileWatcher::new(Duration::ZERO);
+        watcher.watch(path.clone());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(watcher.poll().is_empty());
+        std::fs::write(&path, "After").unwrap();
+        touch(&path, 10);
+        assert_eq!(watcher.poll(), std::slice::from_ref(&path));
+        assert!(watcher.poll().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn waits_for_the_interval_between_polls() {
+        let path = temp_file("interval.txt");
+        let mut watcher = FileWatcher::new(Duration::from_secs(3600));
+        watcher.watch(path.clone());
+
+        touch(&path, 10);
+        assert!(watcher.poll().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}